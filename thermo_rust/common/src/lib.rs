@@ -62,19 +62,23 @@ impl From<chrono::DateTime<chrono::Utc>> for crate::MicroSecTs {
    fn from(ts: chrono::DateTime<chrono::Utc>) -> Self { MicroSecTs(ts) }
 }
 
-fn proto_timestamp_to_chrono(proto: prost_types::Timestamp) -> Result<chrono::DateTime<chrono::Utc>> {
+pub fn proto_timestamp_to_chrono(proto: prost_types::Timestamp) -> Result<chrono::DateTime<chrono::Utc>> {
    let chrono_ts = chrono::DateTime::from_timestamp(proto.seconds, proto.nanos as u32)
       .map_or_else(|| Err(anyhow!("Failed to convert proto: {proto} to chrono")), Ok)?;
    Ok(chrono_ts)
 }
 
-fn chrono_timestamp_to_proto(ts: chrono::DateTime<chrono::Utc>) -> prost_types::Timestamp {
+pub fn chrono_timestamp_to_proto(ts: chrono::DateTime<chrono::Utc>) -> prost_types::Timestamp {
    prost_types::Timestamp {
       seconds: ts.timestamp(),
       nanos: ts.timestamp_subsec_nanos() as i32,
    }
 }
 
+pub fn proto_duration_to_chrono(proto: prost_types::Duration) -> chrono::Duration {
+   chrono::Duration::seconds(proto.seconds) + chrono::Duration::nanoseconds(proto.nanos as i64)
+}
+
 
 //
 // ===========================================================================================================
@@ -149,7 +153,7 @@ impl<'r> sqlx::Decode<'r, sqlx::Sqlite> for SensorId {
 // MeasurementId
 
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, sqlx::FromRow)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, sqlx::FromRow)]
 pub struct MeasurementId {
    pub sensor_id: SensorId,
    pub index: i64,
@@ -191,6 +195,211 @@ impl std::fmt::Display for MeasurementId {
    }
 }
 
+// ===========================================================================================================
+// Quantity
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub enum QuantityKind {
+   Temperature,
+   Humidity,
+   Pressure,
+   Voltage,
+}
+
+impl QuantityKind {
+   pub const ALL: [QuantityKind; 4] = [
+      QuantityKind::Temperature,
+      QuantityKind::Humidity,
+      QuantityKind::Pressure,
+      QuantityKind::Voltage,
+   ];
+
+   pub fn name(&self) -> &'static str {
+      match self {
+         QuantityKind::Temperature => "temperature",
+         QuantityKind::Humidity => "humidity",
+         QuantityKind::Pressure => "pressure",
+         QuantityKind::Voltage => "voltage",
+      }
+   }
+
+   /// Unit, in which values of the kind are reported
+   pub fn unit(&self) -> &'static str {
+      match self {
+         QuantityKind::Temperature => "°C",
+         QuantityKind::Humidity => "%",
+         QuantityKind::Pressure => "hPa",
+         QuantityKind::Voltage => "V",
+      }
+   }
+}
+
+impl std::fmt::Display for QuantityKind {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(self.name()) }
+}
+
+impl std::str::FromStr for QuantityKind {
+   type Err = anyhow::Error;
+
+   fn from_str(value: &str) -> Result<Self, Self::Err> {
+      QuantityKind::ALL.into_iter().find(|kind| kind.name() == value).ok_or_else(|| {
+         let names: Vec<_> = QuantityKind::ALL.iter().map(|kind| kind.name()).collect();
+         anyhow!("Unknown quantity: {value}, expected one of: {}", names.join(", "))
+      })
+   }
+}
+
+impl From<QuantityKind> for crate::pb::QuantityKind {
+   fn from(kind: QuantityKind) -> Self {
+      match kind {
+         QuantityKind::Temperature => Self::Temperature,
+         QuantityKind::Humidity => Self::Humidity,
+         QuantityKind::Pressure => Self::Pressure,
+         QuantityKind::Voltage => Self::Voltage,
+      }
+   }
+}
+
+impl TryFrom<crate::pb::QuantityKind> for QuantityKind {
+   type Error = anyhow::Error;
+
+   fn try_from(kind: crate::pb::QuantityKind) -> Result<Self, Self::Error> {
+      match kind {
+         crate::pb::QuantityKind::Unspecified => Err(anyhow!("Quantity kind is not specified")),
+         crate::pb::QuantityKind::Temperature => Ok(Self::Temperature),
+         crate::pb::QuantityKind::Humidity => Ok(Self::Humidity),
+         crate::pb::QuantityKind::Pressure => Ok(Self::Pressure),
+         crate::pb::QuantityKind::Voltage => Ok(Self::Voltage),
+      }
+   }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+   pub kind: QuantityKind,
+   pub unit: String,
+   pub value: f64,
+}
+
+impl Quantity {
+   /// Quantity in the unit of its kind
+   pub fn new(kind: QuantityKind, value: f64) -> Self {
+      Self {
+         kind,
+         unit: kind.unit().to_string(),
+         value,
+      }
+   }
+}
+
+impl From<Quantity> for crate::pb::Quantity {
+   fn from(quantity: Quantity) -> Self {
+      Self {
+         kind: crate::pb::QuantityKind::from(quantity.kind).into(),
+         unit: quantity.unit,
+         value: quantity.value,
+      }
+   }
+}
+
+impl TryFrom<crate::pb::Quantity> for Quantity {
+   type Error = anyhow::Error;
+
+   fn try_from(proto: crate::pb::Quantity) -> Result<Self, Self::Error> {
+      let kind = crate::pb::QuantityKind::try_from(proto.kind)
+         .with_context(|| anyhow!("Unknown quantity kind: {}", proto.kind))?;
+      let kind: QuantityKind = kind.try_into()?;
+      // Values of a kind are stored, averaged and plotted together, so they all must be in the same unit:
+      if !proto.unit.is_empty() && proto.unit != kind.unit() {
+         return Err(anyhow!("{kind} must be in {}, got: {}", kind.unit(), proto.unit));
+      }
+      Ok(Self::new(kind, proto.value))
+   }
+}
+
+
+// ===========================================================================================================
+// Health
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+   pub since: MicroSecTs,
+   pub reads: u64,
+   pub failures: u64,
+   /// Failed reads since the last successful one
+   pub consecutive_failures: u64,
+   /// Of all reads, including failed ones
+   pub mean_latency: std::time::Duration,
+   /// Empty if there were no failures during the period
+   pub last_error: String,
+}
+
+impl Health {
+   /// In percents, None if there were no reads
+   pub fn success_rate(&self) -> Option<f64> {
+      (self.reads > 0).then(|| 100.0 * (self.reads - self.failures.min(self.reads)) as f64 / self.reads as f64)
+   }
+}
+
+impl std::fmt::Display for Health {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      let success_rate = match self.success_rate() {
+         Some(rate) => format!("{rate:.1}%"),
+         None => "-".to_string(),
+      };
+      write!(
+         f,
+         "reads: {}, ok: {success_rate}, mean read: {}ms",
+         self.reads,
+         self.mean_latency.as_millis()
+      )?;
+      if self.consecutive_failures > 0 {
+         write!(f, ", failing for the last {} reads", self.consecutive_failures)?;
+      }
+      if !self.last_error.is_empty() {
+         write!(f, ", last error: {}", self.last_error)?;
+      }
+      Ok(())
+   }
+}
+
+impl From<Health> for crate::pb::Health {
+   fn from(health: Health) -> Self {
+      Self {
+         since: Some(chrono_timestamp_to_proto(*health.since)),
+         reads: health.reads,
+         failures: health.failures,
+         consecutive_failures: health.consecutive_failures,
+         mean_latency: health.mean_latency.try_into().ok(),
+         last_error: health.last_error,
+      }
+   }
+}
+
+impl TryFrom<crate::pb::Health> for Health {
+   type Error = anyhow::Error;
+
+   fn try_from(proto: crate::pb::Health) -> Result<Self, Self::Error> {
+      let since = proto.since.ok_or_else(|| anyhow!("since is None"))?;
+      let mean_latency = proto.mean_latency.unwrap_or_default();
+      Ok(Self {
+         since: proto_timestamp_to_chrono(since)?.into(),
+         reads: proto.reads,
+         failures: proto.failures,
+         consecutive_failures: proto.consecutive_failures,
+         mean_latency: mean_latency
+            .try_into()
+            .with_context(|| anyhow!("Invalid mean_latency: {mean_latency:?}"))?,
+         last_error: proto.last_error,
+      })
+   }
+}
+
+
 // ===========================================================================================================
 // Measurement
 
@@ -201,6 +410,12 @@ pub struct Measurement {
    pub read_ts: MicroSecTs,
    pub temperature: Option<f64>,
    pub error: String,
+   /// Quantities other than temperature, at most one per kind
+   #[sqlx(skip)]
+   pub quantities: Vec<Quantity>,
+   /// Set only in periodic health summaries of a sensor, which carry no readings
+   #[sqlx(skip)]
+   pub health: Option<Health>,
 }
 
 impl Measurement {
@@ -210,7 +425,40 @@ impl Measurement {
          temperature: Some(temperature),
          error: Default::default(),
          read_ts,
+         quantities: Vec::new(),
+         health: None,
+      }
+   }
+
+   pub fn from_health(id: &MeasurementId, health: Health, read_ts: MicroSecTs) -> Self {
+      Self {
+         id: id.clone(),
+         temperature: None,
+         error: Default::default(),
+         read_ts,
+         quantities: Vec::new(),
+         health: Some(health),
+      }
+   }
+
+   /// Temperature (if any) goes to `temperature`, the rest to `quantities`. Fails if a kind occurs more than
+   /// once, as there is nowhere to keep the other values.
+   pub fn from_quantities(id: &MeasurementId, quantities: Vec<Quantity>, read_ts: MicroSecTs) -> Result<Self> {
+      for (i, quantity) in quantities.iter().enumerate() {
+         if quantities[..i].iter().any(|q| q.kind == quantity.kind) {
+            return Err(anyhow!("Duplicate {} in quantities of {id}: {quantities:?}", quantity.kind));
+         }
       }
+      let (temperatures, quantities): (Vec<_>, Vec<_>) =
+         quantities.into_iter().partition(|q| q.kind == QuantityKind::Temperature);
+      Ok(Self {
+         id: id.clone(),
+         temperature: temperatures.first().map(|q| q.value),
+         error: Default::default(),
+         read_ts,
+         quantities,
+         health: None,
+      })
    }
    pub fn from_err(id: &MeasurementId, error: impl Into<String>, read_ts: MicroSecTs) -> Self {
       Self {
@@ -218,6 +466,15 @@ impl Measurement {
          temperature: None,
          error: error.into(),
          read_ts,
+         quantities: Vec::new(),
+         health: None,
+      }
+   }
+
+   pub fn value(&self, kind: QuantityKind) -> Option<f64> {
+      match kind {
+         QuantityKind::Temperature => self.temperature,
+         _ => self.quantities.iter().find(|q| q.kind == kind).map(|q| q.value),
       }
    }
 }
@@ -232,6 +489,8 @@ impl From<Measurement> for crate::pb::Measurement {
          temperature: value.temperature,
          error: value.error,
          read_ts: Some(chrono_timestamp_to_proto(*value.read_ts)),
+         quantities: value.quantities.into_iter().map(Into::into).collect(),
+         health: value.health.map(Into::into),
       }
    }
 }
@@ -242,11 +501,24 @@ impl TryFrom<crate::pb::Measurement> for Measurement {
    fn try_from(proto: crate::pb::Measurement) -> Result<Self, Self::Error> {
       let read_ts = proto.read_ts.ok_or_else(|| anyhow!("read_ts is None"))?;
       let id = proto.id.clone().ok_or_else(|| anyhow!("id is None"))?;
+      let mut quantities: Vec<Quantity> = Vec::new();
+      for quantity in proto.quantities {
+         let quantity: Quantity = quantity.try_into()?;
+         if quantity.kind == QuantityKind::Temperature {
+            return Err(anyhow!("temperature must be sent in the temperature field, not in quantities"));
+         }
+         if quantities.iter().any(|q| q.kind == quantity.kind) {
+            return Err(anyhow!("Duplicate {} in quantities", quantity.kind));
+         }
+         quantities.push(quantity);
+      }
       let res = Self {
          id: id.try_into().with_context(|| anyhow!("Failed to convert proto id to id"))?,
          temperature: proto.temperature,
          error: proto.error,
          read_ts: proto_timestamp_to_chrono(read_ts)?.into(),
+         quantities,
+         health: proto.health.map(Health::try_from).transpose()?,
       };
       Ok(res)
    }
@@ -259,6 +531,12 @@ impl std::fmt::Display for Measurement {
          None => "-".to_string(),
       };
       write!(f, "Measurement {{{}, read_ts: {}, temperature: {}", self.id, self.read_ts, temperature)?;
+      for quantity in &self.quantities {
+         write!(f, ", {}: {} {}", quantity.kind, quantity.value, quantity.unit)?;
+      }
+      if let Some(health) = &self.health {
+         write!(f, ", health: {{{health}}}")?;
+      }
       if self.error.is_empty() == false {
          write!(f, ", error: {}", self.error)?;
       }
@@ -298,6 +576,8 @@ mod tests {
          read_ts: MicroSecTs(ts),
          temperature: Some(26.8),
          error: "error1".to_string(),
+         quantities: vec![Quantity::new(QuantityKind::Humidity, 45.5)],
+         health: None,
       };
       let proto: crate::pb::Measurement = expected.clone().into();
       assert_eq!(
@@ -307,6 +587,12 @@ mod tests {
             read_ts: Some(chrono_timestamp_to_proto(ts)),
             temperature: Some(26.8),
             error: "error1".to_string(),
+            quantities: vec![crate::pb::Quantity {
+               kind: crate::pb::QuantityKind::Humidity.into(),
+               unit: "%".to_string(),
+               value: 45.5,
+            }],
+            health: None,
          }
       );
 
@@ -315,4 +601,94 @@ mod tests {
 
       Ok(())
    }
+
+   #[test]
+   fn test_measurement_from_quantities_keeps_temperature_separately() {
+      let id = MeasurementId::new(&SensorId::new());
+      let ts = MicroSecTs(chrono::Utc::now());
+      let quantities = vec![
+         Quantity::new(QuantityKind::Humidity, 45.5),
+         Quantity::new(QuantityKind::Temperature, 21.0),
+         Quantity::new(QuantityKind::Pressure, 1013.0),
+      ];
+      let res = Measurement::from_quantities(&id, quantities, ts).unwrap();
+      assert_eq!(res.temperature, Some(21.0));
+      assert_eq!(res.quantities, vec![
+         Quantity::new(QuantityKind::Humidity, 45.5),
+         Quantity::new(QuantityKind::Pressure, 1013.0)
+      ]);
+      assert_eq!(res.value(QuantityKind::Temperature), Some(21.0));
+      assert_eq!(res.value(QuantityKind::Pressure), Some(1013.0));
+      assert_eq!(res.value(QuantityKind::Voltage), None);
+   }
+
+   #[test]
+   fn test_measurement_from_quantities_with_duplicate_kinds() {
+      let id = MeasurementId::new(&SensorId::new());
+      let ts = MicroSecTs(chrono::Utc::now());
+      for kind in [QuantityKind::Temperature, QuantityKind::Humidity] {
+         let quantities = vec![
+            Quantity::new(kind, 21.0),
+            Quantity::new(QuantityKind::Voltage, 5.0),
+            Quantity::new(kind, 22.0),
+         ];
+         let why = Measurement::from_quantities(&id, quantities, ts).unwrap_err();
+         assert!(why.to_string().starts_with(&format!("Duplicate {kind} in quantities of {id}")), "{why}");
+      }
+   }
+
+   #[test]
+   fn test_measurement_proto_with_invalid_quantities() {
+      let quantity = |kind: crate::pb::QuantityKind| crate::pb::Quantity {
+         kind: kind.into(),
+         unit: String::new(),
+         value: 1.0,
+      };
+      let proto = |quantities| crate::pb::Measurement {
+         id: Some(MeasurementId::new(&SensorId::new()).into()),
+         read_ts: Some(chrono_timestamp_to_proto(chrono::Utc::now())),
+         quantities,
+         ..Default::default()
+      };
+      let invalid = [
+         vec![quantity(crate::pb::QuantityKind::Unspecified)],
+         vec![quantity(crate::pb::QuantityKind::Temperature)],
+         vec![quantity(crate::pb::QuantityKind::Voltage), quantity(crate::pb::QuantityKind::Voltage)],
+         vec![crate::pb::Quantity {
+            unit: "Pa".to_string(),
+            ..quantity(crate::pb::QuantityKind::Pressure)
+         }],
+      ];
+      for quantities in invalid {
+         let res: Result<Measurement> = proto(quantities.clone()).try_into();
+         assert!(res.is_err(), "{quantities:?}");
+      }
+
+      // Without a unit, the one of the kind is assumed:
+      let res: Measurement = proto(vec![quantity(crate::pb::QuantityKind::Pressure)]).try_into().unwrap();
+      assert_eq!(res.quantities, vec![Quantity::new(QuantityKind::Pressure, 1.0)]);
+   }
+
+   #[test]
+   fn test_health_proto_conversion_and_display() -> Result<()> {
+      let ts = MicroSecTs(chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+      let health = Health {
+         since: ts,
+         reads: 180,
+         failures: 9,
+         consecutive_failures: 2,
+         mean_latency: std::time::Duration::from_millis(812),
+         last_error: "CRC check failed".to_string(),
+      };
+      let expected = Measurement::from_health(&MeasurementId::new(&SensorId::new()), health.clone(), ts);
+      let proto: crate::pb::Measurement = expected.clone().into();
+      let actual: Measurement = proto.try_into()?;
+      assert_eq!(actual, expected);
+
+      assert_eq!(
+         health.to_string(),
+         "reads: 180, ok: 95.0%, mean read: 812ms, failing for the last 2 reads, last error: CRC check failed"
+      );
+      Ok(())
+   }
 }
@@ -183,6 +183,22 @@ pub fn generate_subject(prefix: &str, len: usize, subject: &Option<String>) -> S
    subject.clone()
 }
 
+/// Returns subject CN of a DER-encoded certificate, e.g. of the peer of a grpc request
+pub fn subject_cn(cert_der: &[u8]) -> Result<String> {
+   let params = rcgen::CertificateParams::from_ca_cert_der(&cert_der.into())
+      .with_context(|| anyhow!("Failed to parse certificate"))?;
+   let cn = params
+      .distinguished_name
+      .get(&rcgen::DnType::CommonName)
+      .ok_or_else(|| anyhow!("There is no CN in subject: {:?}", params.distinguished_name))?;
+   match cn {
+      rcgen::DnValue::Utf8String(cn) => Ok(cn.clone()),
+      rcgen::DnValue::PrintableString(cn) => Ok(cn.as_str().to_string()),
+      rcgen::DnValue::Ia5String(cn) => Ok(cn.as_str().to_string()),
+      cn => Err(anyhow!("Unsupported encoding of CN: {cn:?}")),
+   }
+}
+
 
 //
 // ===========================================================================================================
@@ -433,26 +449,40 @@ impl ClientConfigProvider {
    }
 }
 
-#[derive(clap::Parser, Debug, Clone)]
+// Paths to TLS files of the client. Paths, that are not specified on the command line, can be provided by
+// other means (e.g. a config file), see `ClientArgs::or`.
+#[derive(clap::Parser, Debug, Clone, PartialEq, Default)]
 pub struct ClientArgs {
    /// Path to PEM-encoded CA certificate
    #[clap(long)]
-   tls_ca_cert: std::path::PathBuf,
+   pub tls_ca_cert: Option<std::path::PathBuf>,
 
    /// Path to PEM-encoded client certificate
    #[clap(long)]
-   tls_client_cert: std::path::PathBuf,
+   pub tls_client_cert: Option<std::path::PathBuf>,
 
    /// Path to PEM-encoded client key
    #[clap(long)]
-   tls_client_key: std::path::PathBuf,
+   pub tls_client_key: Option<std::path::PathBuf>,
 }
 
 impl ClientArgs {
+   /// Returns self with paths, that are not specified, taken from `fallback`
+   pub fn or(self, fallback: ClientArgs) -> ClientArgs {
+      ClientArgs {
+         tls_ca_cert: self.tls_ca_cert.or(fallback.tls_ca_cert),
+         tls_client_cert: self.tls_client_cert.or(fallback.tls_client_cert),
+         tls_client_key: self.tls_client_key.or(fallback.tls_client_key),
+      }
+   }
+
    pub fn client_config_provider(&self) -> Result<ClientConfigProvider> {
-      let ca = read_file(&self.tls_ca_cert)?;
-      let cert = read_file(&self.tls_client_cert)?;
-      let key = read_file(&self.tls_client_key)?;
+      let path = |path: &Option<std::path::PathBuf>, name: &str| {
+         path.clone().ok_or_else(|| anyhow!("Path to {name} is not specified (--{name})"))
+      };
+      let ca = read_file(&path(&self.tls_ca_cert, "tls-ca-cert")?)?;
+      let cert = read_file(&path(&self.tls_client_cert, "tls-client-cert")?)?;
+      let key = read_file(&path(&self.tls_client_key, "tls-client-key")?)?;
 
       let identity = tonic::transport::Identity::from_pem(cert, key);
       let ca = tonic::transport::Certificate::from_pem(ca);
@@ -460,3 +490,22 @@ impl ClientArgs {
       Ok(ClientConfigProvider::new(identity, ca))
    }
 }
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   #[test]
+   fn test_subject_cn_of_client_cert() -> Result<()> {
+      let ca = Ca::new(1)?;
+      let (ca_cert, ca_key) = (&ca.cert, &ca.key_pair);
+      let (cert, _) = generate_client("CLI-asdf", ca.validity.clone(), ca_cert, ca_key)?;
+      assert_eq!(subject_cn(cert.der())?, "CLI-asdf");
+      Ok(())
+   }
+}
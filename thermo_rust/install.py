@@ -389,6 +389,33 @@ def generate_tls(out_dir: pl.Path, src_root: pl.Path, dry_run: bool):
 # ============================================================================================================
 # installation & update
 
+def install_sensor_config(user: str, dry_run: bool) -> t.Tuple[pl.Path, bool]:
+   """Writes the config of the sensor binary, returns its path and whether it has been changed"""
+   path: pl.Path = pl.Path("/home") / user / "sensor.yaml"
+   content: str = "\n".join([
+       f"server_host_port: {secret.SERVER_IP}:{secret.GRPC_PORT}",
+       f"tls:"                                                  ,
+       f"  ca_cert: {tls_dir(user) / 'ca.cert'}"                ,
+       f"  client_cert: {tls_dir(user) / 'client.cert'}"        ,
+       f"  client_key: {tls_dir(user) / 'client.key'}"          ,
+       f"sensors:"                                              ,
+       f"  - id: {secret.BOTTOM_ID}"                            ,
+       f"    path: {secret.BOTTOM_PATH}"                        ,
+       f"  - id: {secret.AMBIENT_ID}"                           ,
+       f"    path: {secret.AMBIENT_PATH}"                       ,
+       f""                                                      ,
+   ])
+   if same_content(path, content):
+      logger.info(f"No changes in sensor config {path} => skipping it")
+      return path, False
+
+   logger.info(f"Installing sensor config in {path}")
+   res: ExecRes = exec(dry_run=dry_run, command=f"tee {path}", input=content)
+   if res.is_err():
+      logger.critical(f"Failed to write sensor config {path}: {res}")
+   return path, True
+
+
 def install_client(dry_run: bool):
    install_rust_if_needed(dry_run)
    user: str = secret.USER_ON_RPI
@@ -402,17 +429,16 @@ def install_client(dry_run: bool):
 
    sensor: pl.Path = get_bin_path(src_root_rel_to_script(), "sensor")
 
+   config, config_changed = install_sensor_config(user, dry_run)
    install_system_systemd_unit(systemd_main_service(" ".join([
        f"{sensor}"                                                ,
-       f"--server-host-port {secret.SERVER_IP}:{secret.GRPC_PORT}",
-       f"--bottom-id {secret.BOTTOM_ID}"                          ,
-       f"--bottom-path {secret.BOTTOM_PATH}"                      ,
-       f"--ambient-id {secret.AMBIENT_ID}"                        ,
-       f"--ambient-path {secret.AMBIENT_PATH}"                    ,
-       f"--tls-ca-cert {tls_dir(user) / 'ca.cert'}"               ,
-       f"--tls-client-cert {tls_dir(user) / 'client.cert'}"       ,
-       f"--tls-client-key {tls_dir(user) / 'client.key'}"         ,
+       f"--config {config}"                                       ,
+       f"--outbox-path {pl.Path('/home') / user / 'outbox.sqlite'}",
     ]), user), restart=True, dry_run=dry_run)
+   if config_changed:
+      res: ExecRes = exec(dry_run=dry_run, command=f"systemctl restart {THERMO_SERVICE_NAME}", root_is_required=True)
+      if res.is_err():
+         logger.warning(f"Failed to restart thermo.service: {res}")
    install_system_systemd_unit(systemd_update_service("sensor", user), restart=False, dry_run=dry_run)
    install_system_systemd_unit(systemd_update_timer(), restart=True, dry_run=dry_run)
 
@@ -0,0 +1,470 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// File format, see config.example.yaml
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DriverKind {
+   /// 1-Wire DS18B20, path is its w1_slave file
+   #[default]
+   Ds18b20,
+   /// A file containing just a number, e.g. hwmon's temp1_input or thermal_zone's temp
+   File,
+   /// Returns values one after another in a loop, path is not needed
+   Fake,
+}
+
+/// hwmon and thermal_zone files contain millidegrees Celsius (and hwmon's in*_input millivolts)
+const DEFAULT_FILE_SCALE: f64 = 0.001;
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilterEntry {
+   median_of: Option<usize>,
+   /// Only with median_of, DEFAULT_MAX_DEVIATION if not specified
+   max_deviation: Option<f64>,
+   max_rate_per_min: Option<f64>,
+}
+
+/// Single-sample glitches are tens of degrees off, while normal readings rarely jump by more than this
+const DEFAULT_MAX_DEVIATION: f64 = 5.0;
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SensorEntry {
+   id: String,
+   path: Option<std::path::PathBuf>,
+   #[serde(default)]
+   driver: DriverKind,
+   /// Only for the file driver, DEFAULT_FILE_SCALE if not specified
+   scale: Option<f64>,
+   /// Only for the file driver, temperature if not specified
+   quantity: Option<String>,
+   /// Only for the fake driver
+   values: Option<Vec<f64>>,
+   /// Only for the ds18b20 driver, DEFAULT_DS18B20_RETRIES if not specified
+   retries: Option<u32>,
+   /// If not specified, poll_interval_secs of the file is used
+   poll_interval_secs: Option<u64>,
+   filter: Option<FilterEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsEntry {
+   ca_cert: Option<std::path::PathBuf>,
+   client_cert: Option<std::path::PathBuf>,
+   client_key: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiscoveryEntry {
+   /// If not specified, crate::discovery::DEFAULT_W1_ROOT is used
+   w1_root: Option<std::path::PathBuf>,
+   /// Where the mapping of hardware serials to sensor ids is persisted
+   mapping_path: std::path::PathBuf,
+   /// If not specified, poll_interval_secs of the file is used
+   poll_interval_secs: Option<u64>,
+   /// Applied to all discovered sensors
+   filter: Option<FilterEntry>,
+}
+
+fn default_poll_interval_secs() -> u64 { 20 }
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+   server_host_port: Option<String>,
+   tls: Option<TlsEntry>,
+   #[serde(default = "default_poll_interval_secs")]
+   poll_interval_secs: u64,
+   #[serde(default)]
+   sensors: Vec<SensorEntry>,
+   discovery: Option<DiscoveryEntry>,
+}
+
+
+//
+// ===========================================================================================================
+// Validated config
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+   pub server_host_port: Option<String>,
+   pub tls: common::tls::ClientArgs,
+   pub sensors: Vec<crate::sensor::Meta>,
+   /// Sensors found on 1-Wire bus are polled in addition to the listed ones
+   pub discovery: Option<crate::discovery::Settings>,
+}
+
+impl Config {
+   pub fn load(path: &std::path::Path) -> Result<Config> {
+      let content =
+         std::fs::read_to_string(path).with_context(|| anyhow!("Failed to read config from {path:?}"))?;
+      Self::parse(&content).with_context(|| anyhow!("Invalid config in {path:?}"))
+   }
+
+   pub fn parse(content: &str) -> Result<Config> {
+      let file: File = serde_yaml::from_str(content).with_context(|| anyhow!("Failed to parse yaml"))?;
+      if file.poll_interval_secs == 0 {
+         return Err(anyhow!("poll_interval_secs must be positive"));
+      }
+      if file.sensors.is_empty() && file.discovery.is_none() {
+         return Err(anyhow!("There are no sensors and discovery is not enabled"));
+      }
+
+      let mut sensors: Vec<crate::sensor::Meta> = Vec::new();
+      for (i, entry) in file.sensors.into_iter().enumerate() {
+         let meta = Self::validate(entry.clone(), file.poll_interval_secs)
+            .with_context(|| anyhow!("Invalid sensors[{i}] (id: {})", entry.id))?;
+         if let Some(j) = sensors.iter().position(|m| m.id == meta.id) {
+            return Err(anyhow!("Invalid sensors[{i}] (id: {}): the same id as of sensors[{j}]", entry.id));
+         }
+         sensors.push(meta);
+      }
+
+      let discovery = match file.discovery {
+         Some(entry) => Some(
+            Self::validate_discovery(entry, file.poll_interval_secs)
+               .with_context(|| anyhow!("Invalid discovery"))?,
+         ),
+         None => None,
+      };
+
+      let tls = file.tls.map(|tls| common::tls::ClientArgs {
+         tls_ca_cert: tls.ca_cert,
+         tls_client_cert: tls.client_cert,
+         tls_client_key: tls.client_key,
+      });
+      Ok(Config {
+         server_host_port: file.server_host_port,
+         tls: tls.unwrap_or_default(),
+         sensors,
+         discovery,
+      })
+   }
+
+   fn validate_discovery(
+      entry: DiscoveryEntry,
+      default_poll_interval_secs: u64,
+   ) -> Result<crate::discovery::Settings> {
+      let poll_interval_secs = entry.poll_interval_secs.unwrap_or(default_poll_interval_secs);
+      if poll_interval_secs == 0 {
+         return Err(anyhow!("poll_interval_secs must be positive"));
+      }
+      Ok(crate::discovery::Settings {
+         w1_root: entry.w1_root.unwrap_or_else(|| crate::discovery::DEFAULT_W1_ROOT.into()),
+         mapping_path: entry.mapping_path,
+         interval: std::time::Duration::from_secs(poll_interval_secs),
+         filter: Self::validate_filter(entry.filter)?,
+      })
+   }
+
+   fn validate(entry: SensorEntry, default_poll_interval_secs: u64) -> Result<crate::sensor::Meta> {
+      let poll_interval_secs = entry.poll_interval_secs.unwrap_or(default_poll_interval_secs);
+      if poll_interval_secs == 0 {
+         return Err(anyhow!("poll_interval_secs must be positive"));
+      }
+      let (driver, path) = Self::validate_driver(&entry)?;
+      Ok(crate::sensor::Meta {
+         id: entry.id.try_into()?,
+         path,
+         driver,
+         interval: std::time::Duration::from_secs(poll_interval_secs),
+         filter: Self::validate_filter(entry.filter)?,
+      })
+   }
+
+   fn validate_filter(entry: Option<FilterEntry>) -> Result<crate::filter::Settings> {
+      let Some(entry) = entry else {
+         return Ok(Default::default());
+      };
+      if entry.max_deviation.is_some() && entry.median_of.is_none() {
+         return Err(anyhow!("filter.max_deviation is supported with filter.median_of only"));
+      }
+      let settings = crate::filter::Settings {
+         median_of: entry.median_of,
+         max_deviation: entry.max_deviation.unwrap_or(DEFAULT_MAX_DEVIATION),
+         max_rate_per_min: entry.max_rate_per_min,
+      };
+      settings.validate().with_context(|| anyhow!("Invalid filter"))?;
+      Ok(settings)
+   }
+
+   fn validate_driver(entry: &SensorEntry) -> Result<(crate::sensor::DriverSpec, std::path::PathBuf)> {
+      use crate::sensor::DriverSpec;
+      if entry.scale.is_some() && entry.driver != DriverKind::File {
+         return Err(anyhow!("scale is supported by the file driver only"));
+      }
+      if entry.quantity.is_some() && entry.driver != DriverKind::File {
+         return Err(anyhow!("quantity is supported by the file driver only"));
+      }
+      if entry.values.is_some() && entry.driver != DriverKind::Fake {
+         return Err(anyhow!("values are supported by the fake driver only"));
+      }
+      if entry.retries.is_some() && entry.driver != DriverKind::Ds18b20 {
+         return Err(anyhow!("retries are supported by the ds18b20 driver only"));
+      }
+      let path = || entry.path.clone().ok_or_else(|| anyhow!("path is required by {:?} driver", entry.driver));
+      let res = match entry.driver {
+         DriverKind::Ds18b20 => {
+            let retries = entry.retries.unwrap_or(crate::sensor::DEFAULT_DS18B20_RETRIES);
+            (DriverSpec::Ds18b20 { retries }, path()?)
+         }
+         DriverKind::File => {
+            let scale = entry.scale.unwrap_or(DEFAULT_FILE_SCALE);
+            let kind = match &entry.quantity {
+               Some(quantity) => quantity.parse()?,
+               None => common::QuantityKind::Temperature,
+            };
+            (DriverSpec::NumberFile { kind, scale }, path()?)
+         }
+         DriverKind::Fake => {
+            let values = entry.values.clone().unwrap_or_default();
+            if values.is_empty() {
+               return Err(anyhow!("values are required by the fake driver"));
+            }
+            (DriverSpec::Fake { values }, entry.path.clone().unwrap_or_default())
+         }
+      };
+      Ok(res)
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn meta(id: &str, path: &str, interval_secs: u64) -> crate::sensor::Meta {
+      crate::sensor::Meta {
+         id: id.to_string().try_into().unwrap(),
+         path: path.into(),
+         driver: crate::sensor::DriverSpec::Ds18b20 {
+            retries: crate::sensor::DEFAULT_DS18B20_RETRIES,
+         },
+         interval: std::time::Duration::from_secs(interval_secs),
+         filter: Default::default(),
+      }
+   }
+
+   #[test]
+   fn test_parse_full() -> Result<()> {
+      let content = r#"
+server_host_port: 127.0.0.1:1234
+tls:
+  ca_cert: /tls/ca.cert
+  client_cert: /tls/client.cert
+  client_key: /tls/client.key
+poll_interval_secs: 30
+sensors:
+  - id: sen_asdf_1
+    path: /sys/bus/w1/devices/28-1/w1_slave
+    driver: ds18b20
+  - id: sen_asdf_2
+    path: /sys/bus/w1/devices/28-2/w1_slave
+    poll_interval_secs: 5
+    retries: 0
+    filter:
+      median_of: 5
+      max_rate_per_min: 0.5
+  - id: sen_asdf_3
+    path: /sys/class/thermal/thermal_zone0/temp
+    driver: file
+  - id: sen_asdf_4
+    path: /sys/class/hwmon/hwmon0/temp1_input
+    driver: file
+    scale: 0.01
+  - id: sen_asdf_6
+    path: /sys/class/hwmon/hwmon1/in0_input
+    driver: file
+    quantity: voltage
+  - id: sen_asdf_5
+    driver: fake
+    values: [20, 20.5]
+"#;
+      let expected = Config {
+         server_host_port: Some("127.0.0.1:1234".to_string()),
+         tls: common::tls::ClientArgs {
+            tls_ca_cert: Some("/tls/ca.cert".into()),
+            tls_client_cert: Some("/tls/client.cert".into()),
+            tls_client_key: Some("/tls/client.key".into()),
+         },
+         sensors: vec![
+            meta("sen_asdf_1", "/sys/bus/w1/devices/28-1/w1_slave", 30),
+            crate::sensor::Meta {
+               driver: crate::sensor::DriverSpec::Ds18b20 { retries: 0 },
+               filter: crate::filter::Settings {
+                  median_of: Some(5),
+                  max_deviation: DEFAULT_MAX_DEVIATION,
+                  max_rate_per_min: Some(0.5),
+               },
+               ..meta("sen_asdf_2", "/sys/bus/w1/devices/28-2/w1_slave", 5)
+            },
+            crate::sensor::Meta {
+               driver: crate::sensor::DriverSpec::NumberFile {
+                  kind: common::QuantityKind::Temperature,
+                  scale: 0.001,
+               },
+               ..meta("sen_asdf_3", "/sys/class/thermal/thermal_zone0/temp", 30)
+            },
+            crate::sensor::Meta {
+               driver: crate::sensor::DriverSpec::NumberFile {
+                  kind: common::QuantityKind::Temperature,
+                  scale: 0.01,
+               },
+               ..meta("sen_asdf_4", "/sys/class/hwmon/hwmon0/temp1_input", 30)
+            },
+            crate::sensor::Meta {
+               driver: crate::sensor::DriverSpec::NumberFile {
+                  kind: common::QuantityKind::Voltage,
+                  scale: 0.001,
+               },
+               ..meta("sen_asdf_6", "/sys/class/hwmon/hwmon1/in0_input", 30)
+            },
+            crate::sensor::Meta {
+               driver: crate::sensor::DriverSpec::Fake { values: vec![20.0, 20.5] },
+               ..meta("sen_asdf_5", "", 30)
+            },
+         ],
+         discovery: None,
+      };
+      assert_eq!(Config::parse(content)?, expected);
+      Ok(())
+   }
+
+   #[test]
+   fn test_parse_minimal() -> Result<()> {
+      let content = r#"
+sensors:
+  - id: sen_asdf_1
+    path: /w1_slave
+"#;
+      let expected = Config {
+         server_host_port: None,
+         tls: Default::default(),
+         sensors: vec![meta("sen_asdf_1", "/w1_slave", 20)],
+         discovery: None,
+      };
+      assert_eq!(Config::parse(content)?, expected);
+      Ok(())
+   }
+
+   #[test]
+   fn test_parse_discovery_without_sensors() -> Result<()> {
+      let content = r#"
+poll_interval_secs: 30
+discovery:
+  mapping_path: /var/lib/sensor/w1_mapping.yaml
+"#;
+      let expected = Config {
+         server_host_port: None,
+         tls: Default::default(),
+         sensors: vec![],
+         discovery: Some(crate::discovery::Settings {
+            w1_root: crate::discovery::DEFAULT_W1_ROOT.into(),
+            mapping_path: "/var/lib/sensor/w1_mapping.yaml".into(),
+            interval: std::time::Duration::from_secs(30),
+            filter: Default::default(),
+         }),
+      };
+      assert_eq!(Config::parse(content)?, expected);
+      Ok(())
+   }
+
+   fn parse_err(content: &str) -> String { format!("{:#}", Config::parse(content).unwrap_err()) }
+
+   #[test]
+   fn test_parse_errors_point_to_offending_entry() {
+      let invalid_id = r#"
+sensors:
+  - id: sen_asdf_1
+    path: /1
+  - id: asdf_2
+    path: /2
+"#;
+      let err = parse_err(invalid_id);
+      assert!(err.starts_with("Invalid sensors[1] (id: asdf_2): "), "{err}");
+
+      let duplicate = r#"
+sensors:
+  - id: sen_asdf_1
+    path: /1
+  - id: sen_asdf_1
+    path: /2
+"#;
+      assert_eq!(parse_err(duplicate), "Invalid sensors[1] (id: sen_asdf_1): the same id as of sensors[0]");
+
+      let zero_interval = r#"
+sensors:
+  - id: sen_asdf_1
+    path: /1
+    poll_interval_secs: 0
+"#;
+      assert_eq!(
+         parse_err(zero_interval),
+         "Invalid sensors[0] (id: sen_asdf_1): poll_interval_secs must be positive"
+      );
+
+      let unknown_driver = r#"
+sensors:
+  - id: sen_asdf_1
+    path: /1
+    driver: asdf
+"#;
+      assert!(parse_err(unknown_driver).contains("sensors[0]"), "{}", parse_err(unknown_driver));
+
+      let no_path = r#"
+sensors:
+  - id: sen_asdf_1
+"#;
+      assert_eq!(
+         parse_err(no_path),
+         "Invalid sensors[0] (id: sen_asdf_1): path is required by Ds18b20 driver"
+      );
+
+      let misplaced_scale = r#"
+sensors:
+  - id: sen_asdf_1
+    path: /1
+    scale: 0.001
+"#;
+      assert_eq!(
+         parse_err(misplaced_scale),
+         "Invalid sensors[0] (id: sen_asdf_1): scale is supported by the file driver only"
+      );
+
+      let fake_without_values = r#"
+sensors:
+  - id: sen_asdf_1
+    driver: fake
+"#;
+      let invalid_filter = r#"
+sensors:
+  - id: sen_asdf_1
+    path: /1
+    filter:
+      median_of: 1
+"#;
+      assert_eq!(
+         parse_err(invalid_filter),
+         "Invalid sensors[0] (id: sen_asdf_1): Invalid filter: median_of must be at least 3, got 1"
+      );
+
+      assert_eq!(
+         parse_err(fake_without_values),
+         "Invalid sensors[0] (id: sen_asdf_1): values are required by the fake driver"
+      );
+
+      assert_eq!(parse_err("sensors: []"), "There are no sensors and discovery is not enabled");
+   }
+}
@@ -0,0 +1,398 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Scanning of 1-Wire bus
+
+pub const DEFAULT_W1_ROOT: &str = "/sys/bus/w1/devices";
+
+/// Names of DS18B20 devices on 1-Wire bus start with their family code, e.g. 28-0316a2794bff
+const DS18B20_FAMILY: &str = "28-";
+
+/// How often to rescan the bus to find probes, that disappeared from it
+const RESCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+   /// Hardware serial, e.g. 28-0316a2794bff
+   pub serial: String,
+   /// Path to w1_slave file of the device
+   pub path: std::path::PathBuf,
+}
+
+fn device_path(w1_root: &std::path::Path, serial: &str) -> std::path::PathBuf {
+   w1_root.join(serial).join("w1_slave")
+}
+
+/// Returns DS18B20 devices currently present on the bus, sorted by serial
+pub fn scan(w1_root: &std::path::Path) -> Result<Vec<Device>> {
+   let entries = std::fs::read_dir(w1_root).with_context(|| anyhow!("Failed to read dir {w1_root:?}"))?;
+   let mut devices = Vec::new();
+   for entry in entries {
+      let entry = entry.with_context(|| anyhow!("Failed to read entry of {w1_root:?}"))?;
+      let serial = entry.file_name().to_string_lossy().to_string();
+      if !serial.starts_with(DS18B20_FAMILY) {
+         continue;
+      }
+      let path = device_path(w1_root, &serial);
+      devices.push(Device { serial, path });
+   }
+   devices.sort_by(|l, r| l.serial.cmp(&r.serial));
+   Ok(devices)
+}
+
+
+//
+// ===========================================================================================================
+// Mapping of hardware serials to sensor ids
+
+/// Persisted in a yaml file (serial: sensor id), so that a probe keeps its sensor id across restarts
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Mapping {
+   pub by_serial: std::collections::BTreeMap<String, common::SensorId>,
+}
+
+impl Mapping {
+   /// Returns an empty mapping if the file does not exist
+   pub fn load(path: &std::path::Path) -> Result<Mapping> {
+      if !path.exists() {
+         return Ok(Mapping::default());
+      }
+      let content = std::fs::read_to_string(path).with_context(|| anyhow!("Failed to read {path:?}"))?;
+      let raw: std::collections::BTreeMap<String, String> =
+         serde_yaml::from_str(&content).with_context(|| anyhow!("Failed to parse {path:?}"))?;
+      let mut by_serial = std::collections::BTreeMap::new();
+      for (serial, id) in raw {
+         let id = id.try_into().with_context(|| anyhow!("Invalid id of {serial} in {path:?}"))?;
+         by_serial.insert(serial, id);
+      }
+      Ok(Mapping { by_serial })
+   }
+
+   pub fn save(&self, path: &std::path::Path) -> Result<()> {
+      let raw: std::collections::BTreeMap<&String, String> =
+         self.by_serial.iter().map(|(serial, id)| (serial, id.to_string())).collect();
+      let content = serde_yaml::to_string(&raw).with_context(|| anyhow!("Failed to serialise {raw:?}"))?;
+      std::fs::write(path, content).with_context(|| anyhow!("Failed to write to {path:?}"))
+   }
+
+   /// Returns the sensor id of the serial, assigning a new one if the serial is unknown
+   pub fn get_or_assign(&mut self, serial: &str) -> common::SensorId {
+      self.by_serial.entry(serial.to_string()).or_insert_with(common::SensorId::new).clone()
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Discovery
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+   pub w1_root: std::path::PathBuf,
+   pub mapping_path: std::path::PathBuf,
+   /// How often to poll discovered sensors
+   pub interval: std::time::Duration,
+   /// Applied to all discovered sensors
+   pub filter: crate::filter::Settings,
+}
+
+/// Scans the bus, assigns sensor ids to new probes and persists them in the mapping file. Returns metas of
+/// all known probes, including the ones, that are missing on the bus now: they are polled (and reported as
+/// failing) rather than silently forgotten.
+pub fn discover(settings: &Settings) -> Result<Vec<crate::sensor::Meta>> {
+   let devices = scan(&settings.w1_root)?;
+   let mut mapping = Mapping::load(&settings.mapping_path)?;
+   let before = mapping.clone();
+   for device in &devices {
+      mapping.get_or_assign(&device.serial);
+   }
+   if mapping != before {
+      mapping.save(&settings.mapping_path)?;
+   }
+
+   let metas = mapping
+      .by_serial
+      .iter()
+      .map(|(serial, id)| {
+         if !devices.iter().any(|d| d.serial == *serial) {
+            log::warn!("Known probe {serial} ({id}) is not present on the bus");
+         }
+         crate::sensor::Meta {
+            id: id.clone(),
+            path: device_path(&settings.w1_root, serial),
+            driver: crate::sensor::DriverSpec::Ds18b20 {
+               retries: crate::sensor::DEFAULT_DS18B20_RETRIES,
+            },
+            interval: settings.interval,
+            filter: settings.filter.clone(),
+         }
+      })
+      .collect();
+   Ok(metas)
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+   Disappeared { serial: String, id: common::SensorId },
+   Reappeared { serial: String, id: common::SensorId },
+   /// A probe, that is not in the mapping, has been plugged in after start
+   New { serial: String },
+}
+
+/// Tracks which of the known probes are present on the bus
+pub struct Watcher {
+   mapping: Mapping,
+   present: std::collections::BTreeSet<String>,
+}
+
+impl Watcher {
+   pub fn new(mapping: Mapping, devices: &[Device]) -> Self {
+      let present = devices.iter().map(|d| d.serial.clone()).collect();
+      Self { mapping, present }
+   }
+
+   pub fn check(&mut self, devices: &[Device]) -> Vec<Event> {
+      let now_present: std::collections::BTreeSet<String> = devices.iter().map(|d| d.serial.clone()).collect();
+      let mut events = Vec::new();
+      for (serial, id) in &self.mapping.by_serial {
+         match (self.present.contains(serial), now_present.contains(serial)) {
+            (true, false) => events.push(Event::Disappeared {
+               serial: serial.clone(),
+               id: id.clone(),
+            }),
+            (false, true) => events.push(Event::Reappeared {
+               serial: serial.clone(),
+               id: id.clone(),
+            }),
+            _ => {}
+         }
+      }
+      for serial in now_present.difference(&self.present) {
+         if !self.mapping.by_serial.contains_key(serial) {
+            events.push(Event::New { serial: serial.clone() });
+         }
+      }
+      self.present = now_present;
+      events
+   }
+}
+
+async fn watch_forever(
+   tx: common::Tx,
+   settings: Settings,
+   mut watcher: Watcher,
+   ct: tokio_util::sync::CancellationToken,
+) {
+   log::info!("Starting 1-Wire watcher: {settings:?}");
+   while crate::sensor::sleep_until_next_tick(RESCAN_INTERVAL, &ct).await {
+      let devices = match scan(&settings.w1_root) {
+         Ok(devices) => devices,
+         Err(why) => {
+            log::warn!("Failed to scan 1-Wire bus: {why:?}");
+            continue;
+         }
+      };
+      for event in watcher.check(&devices) {
+         match event {
+            Event::Disappeared { serial, id } => {
+               let error = format!("Probe {serial} disappeared from the 1-Wire bus");
+               log::warn!("{id}: {error}");
+               let id = common::MeasurementId::new(&id);
+               let measurement = common::Measurement::from_err(&id, error, chrono::Utc::now().into());
+               if let Err(why) = tx.try_send(measurement) {
+                  log::warn!("Failed to send measurement in channel: {why:?}");
+               }
+            }
+            Event::Reappeared { serial, id } => log::info!("{id}: probe {serial} is back on the 1-Wire bus"),
+            Event::New { serial } => {
+               log::warn!("New probe {serial} has been found on the 1-Wire bus, restart to start polling it")
+            }
+         }
+      }
+   }
+   log::info!("Stopped 1-Wire watcher");
+}
+
+pub fn spawn_watcher(
+   tx: &common::Tx,
+   settings: &Settings,
+   ct: &tokio_util::sync::CancellationToken,
+) -> Result<()> {
+   let mapping = Mapping::load(&settings.mapping_path)?;
+   let watcher = Watcher::new(mapping, &scan(&settings.w1_root)?);
+   let (tx, settings, ct) = (tx.clone(), settings.clone(), ct.clone());
+   tokio::spawn(watch_forever(tx, settings, watcher, ct));
+   Ok(())
+}
+
+
+//
+// ===========================================================================================================
+// CLI
+
+/// Returns a `sensors` section of the config file for the devices
+pub fn suggest_config(devices: &[Device], mapping: &mut Mapping) -> String {
+   let mut lines = vec!["sensors:".to_string()];
+   for device in devices {
+      lines.push(format!("  - id: {}", mapping.get_or_assign(&device.serial)));
+      lines.push(format!("    path: {}", device.path.display()));
+   }
+   lines.join("\n")
+}
+
+/// Print DS18B20 devices found on 1-Wire bus and a suggested config for them
+#[derive(clap::Parser, Debug)]
+pub struct DiscoverOpts {
+   #[arg(long, default_value = DEFAULT_W1_ROOT)]
+   w1_root: std::path::PathBuf,
+
+   /// Ids of known devices are taken from the mapping file, ids of new ones are saved in it.
+   /// If not specified, new ids are generated for all devices.
+   #[arg(long)]
+   mapping_path: Option<std::path::PathBuf>,
+}
+
+impl DiscoverOpts {
+   pub fn run(&self) -> Result<()> {
+      let devices = scan(&self.w1_root)?;
+      let mut mapping = match &self.mapping_path {
+         Some(path) => Mapping::load(path)?,
+         None => Mapping::default(),
+      };
+      println!("Found {} device(s) in {:?}:", devices.len(), self.w1_root);
+      for device in &devices {
+         println!("   {} {}", device.serial, device.path.display());
+      }
+      println!();
+      println!("Suggested config:");
+      println!("{}", suggest_config(&devices, &mut mapping));
+
+      if let Some(path) = &self.mapping_path {
+         mapping.save(path)?;
+      }
+      Ok(())
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   struct TempDir(std::path::PathBuf);
+   impl TempDir {
+      fn new() -> Self {
+         let path = std::env::temp_dir().join(common::generate_random_string("w1_test_", 10));
+         std::fs::create_dir_all(&path).unwrap();
+         Self(path)
+      }
+      fn add_device(&self, name: &str) {
+         std::fs::create_dir_all(self.0.join(name)).unwrap();
+         std::fs::write(self.0.join(name).join("w1_slave"), "26: crc=64 YES\n 26 t=18375").unwrap();
+      }
+      fn remove_device(&self, name: &str) { std::fs::remove_dir_all(self.0.join(name)).unwrap(); }
+   }
+   impl Drop for TempDir {
+      fn drop(&mut self) { let _ = std::fs::remove_dir_all(&self.0); }
+   }
+
+   fn settings(dir: &TempDir) -> Settings {
+      Settings {
+         w1_root: dir.0.join("devices"),
+         mapping_path: dir.0.join("mapping.yaml"),
+         interval: std::time::Duration::from_secs(20),
+         filter: Default::default(),
+      }
+   }
+
+   fn device(root: &std::path::Path, serial: &str) -> Device {
+      Device {
+         serial: serial.to_string(),
+         path: root.join(serial).join("w1_slave"),
+      }
+   }
+
+   #[test]
+   fn test_scan_returns_only_ds18b20_sorted() -> Result<()> {
+      let dir = TempDir::new();
+      dir.add_device("28-0000000000bb");
+      dir.add_device("w1_bus_master1");
+      dir.add_device("10-0000000000cc");
+      dir.add_device("28-0000000000aa");
+
+      let expected = vec![device(&dir.0, "28-0000000000aa"), device(&dir.0, "28-0000000000bb")];
+      assert_eq!(scan(&dir.0)?, expected);
+      Ok(())
+   }
+
+   #[test]
+   fn test_discover_persists_ids_and_keeps_missing_probes() -> Result<()> {
+      let dir = TempDir::new();
+      let devices = TempDir(dir.0.join("devices"));
+      devices.add_device("28-0000000000aa");
+      devices.add_device("28-0000000000bb");
+      let settings = settings(&dir);
+
+      let first = discover(&settings)?;
+      assert_eq!(first.len(), 2);
+
+      devices.remove_device("28-0000000000aa");
+      devices.add_device("28-0000000000cc");
+      let second = discover(&settings)?;
+      let ids = |metas: &[crate::sensor::Meta]| metas.iter().map(|m| m.id.clone()).collect::<Vec<_>>();
+      assert_eq!(second.len(), 3);
+      assert_eq!(ids(&second)[..2], ids(&first)[..]);
+      assert_eq!(second[0].path, device(&devices.0, "28-0000000000aa").path);
+
+      let mapping = Mapping::load(&settings.mapping_path)?;
+      assert_eq!(mapping.by_serial.values().cloned().collect::<Vec<_>>(), ids(&second));
+      Ok(())
+   }
+
+   #[test]
+   fn test_watcher_reports_disappeared_reappeared_and_new() {
+      let root = std::path::PathBuf::from("/w1");
+      let (id_a, id_b) = (common::SensorId::new(), common::SensorId::new());
+      let mapping = Mapping {
+         by_serial: [("28-a".to_string(), id_a.clone()), ("28-b".to_string(), id_b.clone())].into(),
+      };
+      let (a, b, c) = (device(&root, "28-a"), device(&root, "28-b"), device(&root, "28-c"));
+      let mut watcher = Watcher::new(mapping, &[a.clone(), b.clone()]);
+
+      assert_eq!(watcher.check(&[a.clone(), b.clone()]), Vec::new());
+      let only_b = std::slice::from_ref(&b);
+      assert_eq!(watcher.check(only_b), vec![Event::Disappeared {
+         serial: "28-a".to_string(),
+         id: id_a.clone()
+      }]);
+      assert_eq!(watcher.check(only_b), Vec::new());
+      assert_eq!(watcher.check(&[a.clone(), b.clone(), c.clone()]), vec![
+         Event::Reappeared {
+            serial: "28-a".to_string(),
+            id: id_a.clone()
+         },
+         Event::New {
+            serial: "28-c".to_string()
+         }
+      ]);
+   }
+
+   #[test]
+   fn test_suggest_config_uses_known_ids() {
+      let root = std::path::PathBuf::from("/w1");
+      let id = common::SensorId::new();
+      let mut mapping = Mapping {
+         by_serial: [("28-a".to_string(), id.clone())].into(),
+      };
+      let res = suggest_config(&[device(&root, "28-a")], &mut mapping);
+      assert_eq!(res, format!("sensors:\n  - id: {id}\n    path: /w1/28-a/w1_slave"));
+   }
+}
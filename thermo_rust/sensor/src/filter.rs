@@ -0,0 +1,288 @@
+use anyhow::{Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Settings
+
+/// Optional filtering of temperature readings of a sensor. Filtered readings carry the raw value and the
+/// reason in `error`, so that it is visible in reports when filtering kicked in. Readings without temperature
+/// and other quantities are passed through as is.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Settings {
+   /// A reading, that deviates from the median of the last `median_of` readings (including itself) by more
+   /// than `max_deviation`, is replaced with the median
+   pub median_of: Option<usize>,
+   pub max_deviation: f64,
+   /// A reading, that changes faster than this per minute compared to the previous emitted one, is clamped
+   pub max_rate_per_min: Option<f64>,
+}
+
+impl Settings {
+   pub fn is_enabled(&self) -> bool { self.median_of.is_some() || self.max_rate_per_min.is_some() }
+
+   pub fn validate(&self) -> Result<()> {
+      if let Some(median_of) = self.median_of
+         && median_of < 3
+      {
+         return Err(anyhow!("median_of must be at least 3, got {median_of}"));
+      }
+      if self.max_deviation.is_nan() || self.max_deviation < 0.0 {
+         return Err(anyhow!("max_deviation must not be negative, got {}", self.max_deviation));
+      }
+      if let Some(rate) = self.max_rate_per_min
+         && (rate.is_nan() || rate <= 0.0)
+      {
+         return Err(anyhow!("max_rate_per_min must be positive, got {rate}"));
+      }
+      Ok(())
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Filter of a single sensor
+
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+   settings: Settings,
+   /// The last raw temperatures, at most median_of of them
+   window: std::collections::VecDeque<f64>,
+   /// The last emitted temperature and its read_ts
+   last: Option<(f64, chrono::DateTime<chrono::Utc>)>,
+}
+
+impl Filter {
+   fn new(settings: Settings) -> Self {
+      Self {
+         settings,
+         window: Default::default(),
+         last: None,
+      }
+   }
+
+   fn apply(&mut self, mut measurement: common::Measurement) -> common::Measurement {
+      let Some(raw) = measurement.temperature.filter(|t| t.is_finite()) else {
+         return measurement;
+      };
+      let mut value = raw;
+      let mut reasons: Vec<String> = Vec::new();
+
+      if let Some(median_of) = self.settings.median_of {
+         if self.window.len() == median_of {
+            self.window.pop_front();
+         }
+         self.window.push_back(raw);
+         let median = median(&self.window);
+         if (value - median).abs() > self.settings.max_deviation {
+            reasons.push(format!("deviates from median {median} of the last {} readings", self.window.len()));
+            value = median;
+         }
+      }
+
+      let read_ts = measurement.read_ts.0;
+      if let Some(max_rate_per_min) = self.settings.max_rate_per_min
+         && let Some((last_value, last_ts)) = self.last
+      {
+         let minutes = (read_ts - last_ts).num_milliseconds().max(0) as f64 / 60_000.0;
+         let max_change = max_rate_per_min * minutes;
+         if (value - last_value).abs() > max_change {
+            reasons.push(format!("changes faster than {max_rate_per_min} per minute since {last_value}"));
+            value = value.clamp(last_value - max_change, last_value + max_change);
+         }
+      }
+      self.last = Some((value, read_ts));
+
+      if !reasons.is_empty() {
+         measurement.temperature = Some(value);
+         let filtered = format!("Filtered raw temperature {raw}: {}", reasons.join(", "));
+         measurement.error = match measurement.error.is_empty() {
+            true => filtered,
+            false => format!("{filtered}; {}", measurement.error),
+         };
+      }
+      measurement
+   }
+}
+
+fn median(values: &std::collections::VecDeque<f64>) -> f64 {
+   let mut sorted: Vec<f64> = values.iter().copied().collect();
+   sorted.sort_by(f64::total_cmp);
+   let mid = sorted.len() / 2;
+   match sorted.len() % 2 {
+      0 => (sorted[mid - 1] + sorted[mid]) / 2.0,
+      _ => sorted[mid],
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Filtering stage between pollers and the publisher
+
+/// Forwards measurements from `rx` to the returned receiver, filtering readings of the sensors with enabled
+/// settings. Sensors missing in `settings` (e.g. discovered after start) are filtered with `default` settings
+/// (if any). Stops when `rx` is closed, i.e. all pollers have stopped.
+pub fn spawn(
+   mut rx: common::Rx,
+   settings: std::collections::HashMap<common::SensorId, Settings>,
+   default: Option<Settings>,
+) -> common::Rx {
+   let (tx, filtered_rx) = tokio::sync::mpsc::channel(crate::sensor::CHANNEL_CAPACITY);
+   let create = |settings: Settings| settings.is_enabled().then(|| Filter::new(settings));
+   let mut filters: std::collections::HashMap<common::SensorId, Option<Filter>> =
+      settings.into_iter().map(|(sensor_id, settings)| (sensor_id, create(settings))).collect();
+   tokio::spawn(async move {
+      while let Some(measurement) = rx.recv().await {
+         let filter = filters
+            .entry(measurement.id.sensor_id.clone())
+            .or_insert_with(|| default.clone().and_then(create));
+         let measurement = match filter {
+            Some(filter) => filter.apply(measurement),
+            None => measurement,
+         };
+         if let Err(why) = tx.send(measurement).await {
+            log::warn!("Failed to forward filtered measurement: {why:?}");
+            break;
+         }
+      }
+      log::info!("Stopped filtering measurements");
+   });
+   filtered_rx
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn measurement(minute: i64, temperature: f64) -> common::Measurement {
+      use chrono::TimeZone;
+      let ts = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::minutes(minute);
+      let id = common::MeasurementId {
+         sensor_id: "sen_asdf_1".try_into().unwrap(),
+         index: minute,
+      };
+      common::Measurement::from_ok(&id, temperature, common::MicroSecTs(ts))
+   }
+
+   fn apply_all(settings: Settings, temperatures: &[f64]) -> Vec<(Option<f64>, bool)> {
+      let mut filter = Filter::new(settings);
+      temperatures
+         .iter()
+         .enumerate()
+         .map(|(i, t)| filter.apply(measurement(i as i64, *t)))
+         .map(|m| (m.temperature, !m.error.is_empty()))
+         .collect()
+   }
+
+   #[test]
+   fn test_median_replaces_spikes_only() {
+      let settings = Settings {
+         median_of: Some(3),
+         max_deviation: 5.0,
+         ..Default::default()
+      };
+      let res = apply_all(settings, &[20.0, 20.5, 85.0, 21.0, 21.5]);
+      let expected = vec![
+         (Some(20.0), false),
+         (Some(20.5), false),
+         (Some(20.5), true),
+         (Some(21.0), false),
+         (Some(21.5), false),
+      ];
+      assert_eq!(res, expected);
+   }
+
+   #[test]
+   fn test_rate_limit_clamps_jumps() {
+      let settings = Settings {
+         max_rate_per_min: Some(1.0),
+         ..Default::default()
+      };
+      let res = apply_all(settings, &[20.0, 20.5, 30.0, 22.0, 22.5]);
+      let expected = vec![
+         (Some(20.0), false),
+         (Some(20.5), false),
+         (Some(21.5), true),
+         (Some(22.0), false),
+         (Some(22.5), false),
+      ];
+      assert_eq!(res, expected);
+   }
+
+   #[test]
+   fn test_filtered_measurement_keeps_raw_value_and_reason() {
+      let settings = Settings {
+         max_rate_per_min: Some(1.0),
+         ..Default::default()
+      };
+      let mut filter = Filter::new(settings);
+      filter.apply(measurement(0, 20.0));
+      let res = filter.apply(measurement(1, 30.0));
+      assert_eq!(res.temperature, Some(21.0));
+      assert_eq!(res.error, "Filtered raw temperature 30: changes faster than 1 per minute since 20");
+   }
+
+   #[test]
+   fn test_errors_are_passed_through() {
+      let settings = Settings {
+         median_of: Some(3),
+         max_rate_per_min: Some(1.0),
+         ..Default::default()
+      };
+      let mut filter = Filter::new(settings);
+      let ok = measurement(0, 0.0);
+      let error = common::Measurement::from_err(&ok.id, "error1", ok.read_ts);
+      assert_eq!(filter.apply(error.clone()), error);
+      assert_eq!(filter, Filter::new(filter.settings.clone()));
+   }
+
+   #[tokio::test]
+   async fn test_filters_sensors_appearing_after_start() {
+      let (tx, rx) = tokio::sync::mpsc::channel(16);
+      let default = Settings {
+         max_rate_per_min: Some(1.0),
+         ..Default::default()
+      };
+      let mut rx = spawn(rx, Default::default(), Some(default));
+      for (minute, temperature) in [(0, 20.0), (1, 30.0)] {
+         tx.send(measurement(minute, temperature)).await.unwrap();
+      }
+      drop(tx);
+      let mut temperatures = Vec::new();
+      while let Some(measurement) = rx.recv().await {
+         temperatures.push(measurement.temperature);
+      }
+      assert_eq!(temperatures, vec![Some(20.0), Some(21.0)]);
+   }
+
+   #[test]
+   fn test_validate() {
+      let invalid = [
+         Settings {
+            median_of: Some(2),
+            ..Default::default()
+         },
+         Settings {
+            median_of: Some(3),
+            max_deviation: -1.0,
+            ..Default::default()
+         },
+         Settings {
+            max_rate_per_min: Some(0.0),
+            ..Default::default()
+         },
+      ];
+      for settings in invalid {
+         assert!(settings.validate().is_err(), "{settings:?}");
+      }
+      assert!(Settings::default().validate().is_ok());
+   }
+}
@@ -1,2 +1,6 @@
+pub mod config;
+pub mod discovery;
+pub mod filter;
+pub mod outbox;
 pub mod publisher;
 pub mod sensor;
@@ -3,31 +3,37 @@ use anyhow::{anyhow, Context, Result};
 
 #[derive(clap::Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Cli {
-   /// For example http://127.0.0.1:12345
-   #[arg(long)]
-   server_host_port: String,
+   #[command(subcommand)]
+   command: Option<Command>,
 
-   /// Bottom sensor id
-   #[arg(long)]
-   bottom_id: String,
+   /// Path to YAML config file with the list of sensors, see config.example.yaml
+   #[arg(long, required = true)]
+   config: Option<std::path::PathBuf>,
 
-   /// Path to bottom sensor file
+   /// For example 127.0.0.1:12345. Overrides server_host_port from the config file.
    #[arg(long)]
-   bottom_path: std::path::PathBuf,
+   server_host_port: Option<String>,
 
-   /// Ambient sensor id
+   /// Path to sqlite file, where unconfirmed measurements are kept, so that they survive restarts.
+   /// If not specified, they are kept in memory only.
    #[arg(long)]
-   ambient_id: String,
+   outbox_path: Option<std::path::PathBuf>,
 
-   /// Path to ambient sensor file
-   #[arg(long)]
-   ambient_path: std::path::PathBuf,
+   /// Max number of unconfirmed measurements to keep, see --outbox-overflow-policy
+   #[arg(long, default_value_t = 100_000)]
+   outbox_max_len: usize,
 
-   /// How often to poll sensors, in seconds
-   #[arg(long, default_value_t = 20)]
-   sensor_poll_periodicity: i32,
+   /// Which unconfirmed measurements to evict, when there are more than --outbox-max-len of them
+   #[arg(long, value_enum, default_value_t = sensor::publisher::OverflowPolicy::DropOldest)]
+   outbox_overflow_policy: sensor::publisher::OverflowPolicy,
 
+   /// Unconfirmed measurements older than this number of hours are evicted
+   #[arg(long, default_value_t = 24 * 7)]
+   outbox_max_age_hours: i64,
+
+   // Override the corresponding paths from the config file
    #[command(flatten)]
    tls: common::tls::ClientArgs,
 
@@ -38,11 +44,20 @@ struct Cli {
    log_level: String,
 }
 impl Cli {
-   fn sensor_poll_periodicity(&self) -> std::time::Duration {
-      std::time::Duration::from_secs(self.sensor_poll_periodicity as u64)
+   fn outbox_limits(&self) -> sensor::publisher::Limits {
+      sensor::publisher::Limits {
+         max_len: self.outbox_max_len,
+         max_age: chrono::Duration::hours(self.outbox_max_age_hours),
+         policy: self.outbox_overflow_policy,
+      }
    }
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+   Discover(sensor::discovery::DiscoverOpts),
+}
+
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -50,6 +65,10 @@ async fn main() -> Result<()> {
    let cli = Cli::parse();
    common::init_logger(&cli.log_level);
 
+   if let Some(Command::Discover(opts)) = &cli.command {
+      return opts.run();
+   }
+
    let ct = tokio_util::sync::CancellationToken::new();
 
    {
@@ -57,26 +76,61 @@ async fn main() -> Result<()> {
       ctrlc::set_handler(move || ct.cancel()).unwrap();
    }
 
-   let sensor_metas = &[
-      sensor::sensor::Meta {
-         id: cli.bottom_id.clone().try_into()?,
-         path: cli.bottom_path.clone(),
-      },
-      sensor::sensor::Meta {
-         id: cli.ambient_id.clone().try_into()?,
-         path: cli.ambient_path.clone(),
-      },
-   ];
+   let config_path = cli.config.as_ref().ok_or_else(|| anyhow!("--config is required"))?;
+   let config = sensor::config::Config::load(config_path)?;
+   let server_host_port = cli
+      .server_host_port
+      .clone()
+      .or(config.server_host_port.clone())
+      .ok_or_else(|| anyhow!("Server is specified neither in --server-host-port nor in the config"))?;
+   let tls = cli.tls.clone().or(config.tls.clone());
+
+   let outbox = match &cli.outbox_path {
+      Some(path) => Some(
+         sensor::outbox::Sqlite::new(path)
+            .await
+            .with_context(|| anyhow!("Failed to open outbox at {path:?}"))?,
+      ),
+      None => None,
+   };
+
+   let mut metas = config.sensors.clone();
+   if let Some(discovery) = &config.discovery {
+      let discovered = sensor::discovery::discover(discovery)
+         .with_context(|| anyhow!("Failed to discover sensors in {:?}", discovery.w1_root))?;
+      for meta in discovered {
+         if metas.iter().any(|m| m.id == meta.id || m.path == meta.path) {
+            log::info!("Discovered sensor {} at {:?} is already in the config", meta.id, meta.path);
+            continue;
+         }
+         log::info!("Discovered sensor {} at {:?}", meta.id, meta.path);
+         metas.push(meta);
+      }
+   }
+   if metas.is_empty() {
+      return Err(anyhow!("There are no sensors neither in the config nor on 1-Wire bus"));
+   }
+
+   let (tx, rx) = tokio::sync::mpsc::channel(sensor::sensor::CHANNEL_CAPACITY);
+   sensor::sensor::spawn_pollers(&tx, &metas, &ct);
+   if let Some(discovery) = &config.discovery {
+      sensor::discovery::spawn_watcher(&tx, discovery, &ct)?;
+   }
+   drop(tx);
 
-   let rx = sensor::sensor::spawn_pollers(sensor_metas, cli.sensor_poll_periodicity(), &ct);
+   let filters: std::collections::HashMap<_, _> =
+      metas.iter().map(|meta| (meta.id.clone(), meta.filter.clone())).collect();
+   let default_filter = config.discovery.as_ref().map(|discovery| discovery.filter.clone());
+   let rx = sensor::filter::spawn(rx, filters, default_filter);
 
    sensor::publisher::poll_and_publish_forever(
       &ct,
       rx,
-      &cli.server_host_port,
-      cli.tls
-         .client_config_provider()
+      &server_host_port,
+      tls.client_config_provider()
          .with_context(|| anyhow!("Failed to create client config provider"))?,
+      outbox,
+      cli.outbox_limits(),
    )
    .await?;
    Ok(())
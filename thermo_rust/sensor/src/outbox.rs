@@ -0,0 +1,307 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Outbox: unconfirmed measurements persisted on disk, so that they survive restarts of the sensor
+
+#[derive(sqlx::FromRow)]
+struct QuantityRow {
+   sensor_id: common::SensorId,
+   index_n: i64,
+   kind: common::QuantityKind,
+   unit: String,
+   value: f64,
+}
+
+#[derive(sqlx::FromRow)]
+struct HealthRow {
+   sensor_id: common::SensorId,
+   index_n: i64,
+   since: common::MicroSecTs,
+   reads: i64,
+   failures: i64,
+   consecutive_failures: i64,
+   mean_latency_us: i64,
+   last_error: String,
+}
+
+#[derive(Clone)]
+pub struct Sqlite {
+   pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+impl Sqlite {
+   pub async fn new(path: &std::path::Path) -> Result<Sqlite> {
+      let opts = sqlx::sqlite::SqliteConnectOptions::new().filename(path).create_if_missing(true);
+      let pool = sqlx::sqlite::SqlitePool::connect_with(opts.clone())
+         .await
+         .with_context(|| anyhow!("Failed to create sqlite pool with optons: {opts:?}"))?;
+
+      for sql in Self::ddl() {
+         sqlx::query(sql)
+            .execute(&pool)
+            .await
+            .with_context(|| anyhow!("Failed to execute ddl: {sql}"))?;
+      }
+      Ok(Sqlite { pool })
+   }
+
+   fn ddl() -> &'static [&'static str] {
+      &[
+         r#"CREATE TABLE IF NOT EXISTS outbox (
+            sensor_id   TEXT    NOT NULL,
+            index_n     INTEGER NOT NULL,
+            read_ts     INTEGER NOT NULL,
+            temperature REAL,
+            error       TEXT    NOT NULL,
+            PRIMARY KEY (sensor_id, index_n)
+         ) STRICT;"#,
+         r#"CREATE TABLE IF NOT EXISTS outbox_quantities (
+            sensor_id TEXT    NOT NULL,
+            index_n   INTEGER NOT NULL,
+            kind      TEXT    NOT NULL,
+            unit      TEXT    NOT NULL,
+            value     REAL    NOT NULL,
+            PRIMARY KEY (sensor_id, index_n, kind)
+         ) STRICT;"#,
+         r#"CREATE TABLE IF NOT EXISTS outbox_health (
+            sensor_id            TEXT    NOT NULL,
+            index_n              INTEGER NOT NULL,
+            since                INTEGER NOT NULL,
+            reads                INTEGER NOT NULL,
+            failures             INTEGER NOT NULL,
+            consecutive_failures INTEGER NOT NULL,
+            mean_latency_us      INTEGER NOT NULL,
+            last_error           TEXT    NOT NULL,
+            PRIMARY KEY (sensor_id, index_n)
+         ) STRICT;"#,
+      ]
+   }
+
+   pub async fn add(&self, row: &common::Measurement) -> Result<()> {
+      let mut tx = self.pool.begin().await?;
+      sqlx::query(
+         r#"INSERT OR REPLACE INTO outbox (read_ts, sensor_id, index_n, temperature, error)
+            VALUES ($1, $2, $3, $4, $5)
+         "#,
+      )
+      .bind(row.read_ts)
+      .bind(&row.id.sensor_id)
+      .bind(row.id.index)
+      .bind(row.temperature)
+      .bind(&row.error)
+      .execute(&mut *tx)
+      .await?;
+      for quantity in &row.quantities {
+         sqlx::query(
+            r#"INSERT OR REPLACE INTO outbox_quantities (sensor_id, index_n, kind, unit, value)
+               VALUES ($1, $2, $3, $4, $5)
+            "#,
+         )
+         .bind(&row.id.sensor_id)
+         .bind(row.id.index)
+         .bind(quantity.kind)
+         .bind(&quantity.unit)
+         .bind(quantity.value)
+         .execute(&mut *tx)
+         .await?;
+      }
+      if let Some(health) = &row.health {
+         sqlx::query(
+            r#"INSERT OR REPLACE INTO outbox_health
+                  (sensor_id, index_n, since, reads, failures, consecutive_failures, mean_latency_us,
+                   last_error)
+               VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+         )
+         .bind(&row.id.sensor_id)
+         .bind(row.id.index)
+         .bind(health.since)
+         .bind(health.reads as i64)
+         .bind(health.failures as i64)
+         .bind(health.consecutive_failures as i64)
+         .bind(health.mean_latency.as_micros() as i64)
+         .bind(&health.last_error)
+         .execute(&mut *tx)
+         .await?;
+      }
+      tx.commit().await?;
+      Ok(())
+   }
+
+   pub async fn remove(&self, id: &common::MeasurementId) -> Result<()> {
+      let mut tx = self.pool.begin().await?;
+      for sql in [
+         "DELETE FROM outbox WHERE sensor_id = $1 AND index_n = $2",
+         "DELETE FROM outbox_quantities WHERE sensor_id = $1 AND index_n = $2",
+         "DELETE FROM outbox_health WHERE sensor_id = $1 AND index_n = $2",
+      ] {
+         sqlx::query(sql).bind(&id.sensor_id).bind(id.index).execute(&mut *tx).await?;
+      }
+      tx.commit().await?;
+      Ok(())
+   }
+
+   pub async fn read_all(&self) -> Result<Vec<common::Measurement>> {
+      let mut measurements: Vec<common::Measurement> = sqlx::query_as(
+         r#"
+         SELECT read_ts, sensor_id, index_n as "index", temperature, error
+         FROM outbox
+         ORDER BY read_ts
+         "#,
+      )
+      .fetch_all(&self.pool)
+      .await?;
+
+      let quantities: Vec<QuantityRow> = sqlx::query_as(
+         r#"
+         SELECT sensor_id, index_n, kind, unit, value
+         FROM outbox_quantities
+         ORDER BY kind
+         "#,
+      )
+      .fetch_all(&self.pool)
+      .await?;
+      let mut by_id: std::collections::HashMap<common::MeasurementId, Vec<common::Quantity>> =
+         Default::default();
+      for row in quantities {
+         let id = common::MeasurementId {
+            sensor_id: row.sensor_id,
+            index: row.index_n,
+         };
+         by_id.entry(id).or_default().push(common::Quantity {
+            kind: row.kind,
+            unit: row.unit,
+            value: row.value,
+         });
+      }
+
+      let health: Vec<HealthRow> = sqlx::query_as(
+         r#"
+         SELECT sensor_id, index_n, since, reads, failures, consecutive_failures, mean_latency_us, last_error
+         FROM outbox_health
+         "#,
+      )
+      .fetch_all(&self.pool)
+      .await?;
+      let mut health_by_id: std::collections::HashMap<common::MeasurementId, common::Health> = health
+         .into_iter()
+         .map(|row| {
+            let id = common::MeasurementId {
+               sensor_id: row.sensor_id,
+               index: row.index_n,
+            };
+            let health = common::Health {
+               since: row.since,
+               reads: row.reads.max(0) as u64,
+               failures: row.failures.max(0) as u64,
+               consecutive_failures: row.consecutive_failures.max(0) as u64,
+               mean_latency: std::time::Duration::from_micros(row.mean_latency_us.max(0) as u64),
+               last_error: row.last_error,
+            };
+            (id, health)
+         })
+         .collect();
+
+      for measurement in &mut measurements {
+         if let Some(quantities) = by_id.remove(&measurement.id) {
+            measurement.quantities = quantities;
+         }
+         measurement.health = health_by_id.remove(&measurement.id);
+      }
+      Ok(measurements)
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn temp_path() -> std::path::PathBuf {
+      std::env::temp_dir().join(common::generate_random_string("outbox_test_", 10))
+   }
+
+   fn ts_ymd(year: i32, month: u32, day: u32) -> common::MicroSecTs {
+      use chrono::TimeZone;
+      let ts = chrono::Utc.with_ymd_and_hms(year, month, day, 0, 0, 0).earliest().unwrap();
+      common::MicroSecTs(ts)
+   }
+
+   fn measurement(index: i64, ts: common::MicroSecTs) -> common::Measurement {
+      let id = common::MeasurementId {
+         sensor_id: "sen_asdf_1".try_into().unwrap(),
+         index,
+      };
+      let quantities = vec![
+         common::Quantity::new(common::QuantityKind::Temperature, 26.8),
+         common::Quantity::new(common::QuantityKind::Voltage, 5.0 + index as f64),
+      ];
+      common::Measurement::from_quantities(&id, quantities, ts).unwrap()
+   }
+
+   #[tokio::test]
+   async fn test_measurements_survive_reopening() -> Result<()> {
+      let path = temp_path();
+      {
+         let outbox = Sqlite::new(&path).await?;
+         outbox.add(&measurement(2, ts_ymd(2024, 1, 2))).await?;
+         outbox.add(&measurement(1, ts_ymd(2024, 1, 1))).await?;
+      }
+      let outbox = Sqlite::new(&path).await?;
+      let res = outbox.read_all().await?;
+      std::fs::remove_file(&path)?;
+
+      let expected = vec![measurement(1, ts_ymd(2024, 1, 1)), measurement(2, ts_ymd(2024, 1, 2))];
+      assert_eq!(res, expected);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_add_is_idempotent_and_remove_removes() -> Result<()> {
+      let path = temp_path();
+      let outbox = Sqlite::new(&path).await?;
+      outbox.add(&measurement(1, ts_ymd(2024, 1, 1))).await?;
+      outbox.add(&measurement(1, ts_ymd(2024, 1, 1))).await?;
+      outbox.add(&measurement(2, ts_ymd(2024, 1, 2))).await?;
+      outbox.remove(&measurement(1, ts_ymd(2024, 1, 1)).id).await?;
+      let res = outbox.read_all().await?;
+      std::fs::remove_file(&path)?;
+
+      assert_eq!(res, vec![measurement(2, ts_ymd(2024, 1, 2))]);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_health_summaries_survive_reopening() -> Result<()> {
+      let path = temp_path();
+      let health = common::Health {
+         since: ts_ymd(2024, 1, 1),
+         reads: 60,
+         failures: 2,
+         consecutive_failures: 1,
+         mean_latency: std::time::Duration::from_micros(812_345),
+         last_error: "error1".to_string(),
+      };
+      let id = measurement(1, ts_ymd(2024, 1, 1)).id;
+      let summary = common::Measurement::from_health(&id, health, ts_ymd(2024, 1, 2));
+      {
+         let outbox = Sqlite::new(&path).await?;
+         outbox.add(&summary).await?;
+      }
+      let outbox = Sqlite::new(&path).await?;
+      assert_eq!(outbox.read_all().await?, vec![summary.clone()]);
+      outbox.remove(&summary.id).await?;
+      let res = outbox.read_all().await?;
+      std::fs::remove_file(&path)?;
+
+      assert_eq!(res, Vec::new());
+      Ok(())
+   }
+}
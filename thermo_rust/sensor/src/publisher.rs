@@ -4,19 +4,66 @@ use anyhow::{Context, Result, anyhow};
 // ===========================================================================================================
 // Measurements
 
+/// What to do with unconfirmed measurements, when there are more of them than `Limits::max_len`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+   /// Evict the oldest (by read_ts) measurements
+   #[default]
+   DropOldest,
+   /// Evict the newest (by read_ts) measurements, i.e. stop accepting new ones
+   DropNewest,
+   /// Evict every other measurement of each sensor in the older half, i.e. lower resolution of old data
+   Downsample,
+}
+
+/// Limits on unconfirmed measurements we keep for resending. Measurements older than `max_age` are always
+/// evicted, when there are more than `max_len` of them, `policy` decides which ones are evicted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+   pub max_len: usize,
+   pub max_age: chrono::Duration,
+   pub policy: OverflowPolicy,
+}
+
+impl Default for Limits {
+   fn default() -> Self {
+      Self {
+         max_len: 100_000,
+         max_age: chrono::Duration::days(7),
+         policy: Default::default(),
+      }
+   }
+}
+
+
 #[derive(Debug, Clone, PartialEq)]
 struct Measurements {
    age: chrono::Duration,
+   limits: Limits,
    by_id: std::collections::HashMap<common::MeasurementId, common::Measurement>,
    by_send_ts: std::collections::BTreeMap<chrono::DateTime<chrono::Utc>, Vec<common::MeasurementId>>,
+   /// Key of each measurement in by_send_ts, so that it is removed from there without a scan
+   send_ts_by_id: std::collections::HashMap<common::MeasurementId, chrono::DateTime<chrono::Utc>>,
+   by_read_ts: std::collections::BTreeSet<(chrono::DateTime<chrono::Utc>, common::MeasurementId)>,
+   /// Number of evicted measurements per sensor since start
+   dropped: std::collections::BTreeMap<common::SensorId, u64>,
+   /// Number of evicted measurements per sensor, that have not been reported to the server yet
+   unreported: std::collections::BTreeMap<common::SensorId, u64>,
+   last_report: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl Default for Measurements {
    fn default() -> Self {
       Self {
          age: chrono::Duration::minutes(1),
+         limits: Default::default(),
          by_id: Default::default(),
          by_send_ts: Default::default(),
+         send_ts_by_id: Default::default(),
+         by_read_ts: Default::default(),
+         dropped: Default::default(),
+         unreported: Default::default(),
+         last_report: Default::default(),
       }
    }
 }
@@ -24,22 +71,29 @@ impl Default for Measurements {
 impl Measurements {
    fn add(&mut self, m: common::Measurement, now: chrono::DateTime<chrono::Utc>) {
       let id = m.id.clone();
+      self.by_read_ts.insert((m.read_ts.0, id.clone()));
       self.by_id.insert(id.clone(), m);
+      self.unschedule(&id);
+      self.send_ts_by_id.insert(id.clone(), now);
       self.by_send_ts.entry(now).or_default().push(id);
    }
    fn remove(&mut self, id: &common::MeasurementId) {
-      self.by_id.remove(id);
-
-      let mut to_remove = Vec::new();
-      for (ts, vec) in &mut self.by_send_ts {
+      if let Some(m) = self.by_id.remove(id) {
+         self.by_read_ts.remove(&(m.read_ts.0, id.clone()));
+      }
+      self.unschedule(id);
+   }
+   /// Removes the id from by_send_ts, only the ids sent at the same time are scanned
+   fn unschedule(&mut self, id: &common::MeasurementId) {
+      let Some(ts) = self.send_ts_by_id.remove(id) else {
+         return;
+      };
+      if let Some(vec) = self.by_send_ts.get_mut(&ts) {
          vec.retain(|x| x != id);
          if vec.is_empty() {
-            to_remove.push(*ts);
+            self.by_send_ts.remove(&ts);
          }
       }
-      for ts in to_remove {
-         self.by_send_ts.remove(&ts);
-      }
    }
 
    fn get_next_to_retry(&mut self, now: chrono::DateTime<chrono::Utc>) -> Option<common::Measurement> {
@@ -60,8 +114,97 @@ impl Measurements {
       self.add(measurement.clone(), now);
       Some(measurement.clone())
    }
+
+   fn get_batch_to_retry(
+      &mut self,
+      now: chrono::DateTime<chrono::Utc>,
+      max_len: usize,
+   ) -> Vec<common::Measurement> {
+      std::iter::from_fn(|| self.get_next_to_retry(now)).take(max_len).collect()
+   }
+
+   /// Evicts too old measurements and then more according to the policy until limits are satisfied, returns
+   /// ids of evicted ones.
+   fn evict(&mut self, now: chrono::DateTime<chrono::Utc>) -> Vec<common::MeasurementId> {
+      let mut evicted = Vec::new();
+      while let Some((read_ts, id)) = self.by_read_ts.first().cloned() {
+         if read_ts >= now - self.limits.max_age {
+            break;
+         }
+         self.remove(&id);
+         evicted.push(id);
+      }
+
+      while self.by_id.len() > self.limits.max_len {
+         let victims = match self.limits.policy {
+            OverflowPolicy::DropOldest => self.by_read_ts.first().iter().map(|(_, id)| id.clone()).collect(),
+            OverflowPolicy::DropNewest => self.by_read_ts.last().iter().map(|(_, id)| id.clone()).collect(),
+            OverflowPolicy::Downsample => self.every_other_in_older_half(),
+         };
+         if victims.is_empty() {
+            break;
+         }
+         for id in victims {
+            self.remove(&id);
+            evicted.push(id);
+         }
+      }
+
+      for id in &evicted {
+         *self.dropped.entry(id.sensor_id.clone()).or_default() += 1;
+         *self.unreported.entry(id.sensor_id.clone()).or_default() += 1;
+      }
+      evicted
+   }
+
+   fn every_other_in_older_half(&self) -> Vec<common::MeasurementId> {
+      let half = self.by_read_ts.len() / 2;
+      let mut seen = std::collections::HashMap::<&common::SensorId, usize>::new();
+      let mut res = Vec::new();
+      for (_, id) in self.by_read_ts.iter().take(half) {
+         let n = seen.entry(&id.sensor_id).or_default();
+         if *n % 2 == 1 {
+            res.push(id.clone());
+         }
+         *n += 1;
+      }
+      if res.is_empty() {
+         // Too few measurements to thin out => fall back to evicting the oldest one:
+         res.extend(self.by_read_ts.first().map(|(_, id)| id.clone()));
+      }
+      res
+   }
+
+   /// Returns synthetic error measurements (one per sensor) about evicted measurements, that have not been
+   /// reported yet. To avoid flooding the server, reports are created at most once per `DROPS_REPORT_PERIOD`.
+   fn take_drops_reports(&mut self, now: chrono::DateTime<chrono::Utc>) -> Vec<common::Measurement> {
+      if self.unreported.is_empty() {
+         return Vec::new();
+      }
+      if let Some(last_report) = self.last_report
+         && now - last_report < DROPS_REPORT_PERIOD
+      {
+         return Vec::new();
+      }
+      self.last_report = Some(now);
+      std::mem::take(&mut self.unreported)
+         .into_iter()
+         .map(|(sensor_id, n)| {
+            let error = format!(
+               "Dropped {n} unconfirmed readings due to overflow of the publisher buffer (policy: {:?}, \
+                limits: {} measurements / {} hours)",
+               self.limits.policy,
+               self.limits.max_len,
+               self.limits.max_age.num_hours(),
+            );
+            common::Measurement::from_err(&common::MeasurementId::new(&sensor_id), error, now.into())
+         })
+         .collect()
+   }
 }
 
+const DROPS_REPORT_PERIOD: chrono::Duration = chrono::Duration::minutes(1);
+
 
 
 
@@ -72,33 +215,130 @@ impl Measurements {
 struct State {
    thread_rx: common::Rx,
    measurements: Measurements,
+   outbox: Option<crate::outbox::Sqlite>,
 }
 impl State {
-   fn new(thread_rx: common::Rx) -> Self {
-      Self {
-         thread_rx,
-         measurements: Default::default(),
+   async fn new(thread_rx: common::Rx, outbox: Option<crate::outbox::Sqlite>, limits: Limits) -> Result<Self> {
+      let mut measurements = Measurements {
+         limits,
+         ..Default::default()
+      };
+      if let Some(outbox) = &outbox {
+         let unconfirmed = outbox.read_all().await.with_context(|| anyhow!("Failed to read outbox"))?;
+         log::info!("Replaying {} unconfirmed measurements from outbox", unconfirmed.len());
+         for measurement in unconfirmed {
+            // Pretend they were sent long ago, so that they are resent right away:
+            measurements.add(measurement, chrono::DateTime::UNIX_EPOCH);
+         }
       }
+      let mut state = Self {
+         thread_rx,
+         measurements,
+         outbox,
+      };
+      state.evict(chrono::Utc::now()).await;
+      Ok(state)
    }
 
 
-   fn on_new_measurement(&mut self, measurement: common::Measurement) -> common::Measurement {
-      self.measurements.add(measurement.clone(), chrono::Utc::now());
-      if self.measurements.by_id.len() > 1000 {
-         log::warn!("Measurements size: {}", self.measurements.by_id.len());
+   async fn on_new_measurement(&mut self, measurement: common::Measurement) -> common::Measurement {
+      let now = chrono::Utc::now();
+      self.measurements.add(measurement.clone(), now);
+      if let Some(outbox) = &self.outbox
+         && let Err(why) = outbox.add(&measurement).await
+      {
+         log::warn!("Failed to add {measurement} to outbox: {why:?}");
       }
+      self.evict(now).await;
       measurement
    }
-   fn pull_from_rx(&mut self) {
+   async fn evict(&mut self, now: chrono::DateTime<chrono::Utc>) {
+      let evicted = self.measurements.evict(now);
+      if !evicted.is_empty() {
+         log::warn!(
+            "Evicted {} unconfirmed measurements, limits: {:?}, dropped per sensor since start: {:?}",
+            evicted.len(),
+            self.measurements.limits,
+            self.measurements.dropped
+         );
+         for id in &evicted {
+            self.remove_from_outbox(id).await;
+         }
+      }
+      for report in self.measurements.take_drops_reports(now) {
+         log::warn!("Reporting to server: {report}");
+         // Pretend it was sent long ago, so that it is sent right away:
+         self.measurements.add(report.clone(), chrono::DateTime::UNIX_EPOCH);
+         if let Some(outbox) = &self.outbox
+            && let Err(why) = outbox.add(&report).await
+         {
+            log::warn!("Failed to add {report} to outbox: {why:?}");
+         }
+      }
+   }
+   async fn pull_from_rx(&mut self) {
       while let Ok(measurement) = self.thread_rx.try_recv() {
-         self.on_new_measurement(measurement);
+         self.on_new_measurement(measurement).await;
+      }
+   }
+   async fn remove_confirmed(&mut self, id: common::MeasurementId) {
+      self.measurements.remove(&id);
+      self.remove_from_outbox(&id).await;
+   }
+   /// Forgets confirmed measurements and permanently rejected ones. Measurements rejected for other reasons
+   /// (e.g. the client has not been bound to the sensor yet) are kept and resent later.
+   async fn on_response(&mut self, resp: common::pb::StoreMeasurementResp) {
+      let mut ids: Vec<common::pb::MeasurementId> =
+         resp.confirmed.into_iter().chain(resp.confirmed_batch).collect();
+      for rejected in resp.rejected {
+         if rejected.code() == common::pb::RejectReason::Invalid {
+            log::error!("Server rejected {:?}, not resending it: {}", rejected.id, rejected.reason);
+            ids.extend(rejected.id);
+         } else {
+            log::warn!("Server rejected {:?} for now, will resend it: {}", rejected.id, rejected.reason);
+         }
+      }
+      for id in ids {
+         let Ok(id) = id.try_into() else { continue };
+         self.remove_confirmed(id).await;
+      }
+   }
+   /// For servers, that do not support health summaries and would store them as empty readings
+   async fn drop_health_summaries(&mut self) {
+      let ids: Vec<common::MeasurementId> =
+         self.measurements.by_id.values().filter(|m| m.health.is_some()).map(|m| m.id.clone()).collect();
+      if !ids.is_empty() {
+         log::info!("Server does not support health summaries, dropping {} of them", ids.len());
+      }
+      for id in ids {
+         self.remove_confirmed(id).await;
+      }
+   }
+   async fn remove_from_outbox(&self, id: &common::MeasurementId) {
+      if let Some(outbox) = &self.outbox
+         && let Err(why) = outbox.remove(id).await
+      {
+         log::warn!("Failed to remove {id} from outbox: {why:?}");
       }
    }
-   fn remove_confirmed(&mut self, id: common::MeasurementId) { self.measurements.remove(&id); }
 }
 
 
 
+/// Max number of measurements resent in a single StoreMeasurementReq
+const MAX_BATCH_LEN: usize = 500;
+
+/// Older servers do not implement GetCapabilities, i.e. support nothing beyond the original protocol
+async fn get_capabilities(
+   client: &mut common::pb::agg_client::AggClient<tonic::transport::Channel>,
+) -> Result<common::pb::GetCapabilitiesResp> {
+   match client.get_capabilities(common::pb::GetCapabilitiesReq {}).await {
+      Ok(resp) => Ok(resp.into_inner()),
+      Err(status) if status.code() == tonic::Code::Unimplemented => Ok(Default::default()),
+      Err(status) => Err(anyhow!("Failed to get capabilities of the server: {status:?}")),
+   }
+}
+
 async fn one_iteration(
    ct: &tokio_util::sync::CancellationToken,
    server_host_port: &str, // localhost:1234 (without scheme)
@@ -108,7 +348,7 @@ async fn one_iteration(
    let url = format!("https://{server_host_port}");
    let url = url::Url::parse(&url).with_context(|| anyhow!("Failed to parse: {url}"))?;
    let host = url.host().ok_or_else(|| anyhow!("There is no host in {url}"))?;
-   state.pull_from_rx();
+   state.pull_from_rx().await;
    let channel = tonic::transport::Endpoint::from_shared(url.to_string())
       .with_context(|| anyhow!("Failed to create channel for {server_host_port}"))?
       .tls_config(client_config_provider.create_for(&host.to_string()))
@@ -117,7 +357,13 @@ async fn one_iteration(
       .connect()
       .await
       .with_context(|| anyhow!("Failed to connect to {server_host_port}"))?;
-   let mut client = common::pb::agg_client::AggClient::new(channel);
+   let mut client = common::pb::agg_client::AggClient::new(channel)
+      .send_compressed(tonic::codec::CompressionEncoding::Gzip)
+      .accept_compressed(tonic::codec::CompressionEncoding::Gzip);
+   let capabilities = get_capabilities(&mut client).await?;
+   if !capabilities.health {
+      state.drop_health_summaries().await;
+   }
 
    let (tx_outbound, rx_outbound) = tokio::sync::mpsc::channel(10);
    let outbound = tokio_stream::wrappers::ReceiverStream::new(rx_outbound);
@@ -131,31 +377,42 @@ async fn one_iteration(
             return Ok(());
          },
          Some(measurement) = state.thread_rx.recv() => {
-            let measurement = state.on_new_measurement(measurement);
+            if measurement.health.is_some() && !capabilities.health {
+               log::debug!("Server does not support health summaries, not sending: {measurement}");
+               continue;
+            }
+            let measurement = state.on_new_measurement(measurement).await;
             log::info!("Sending: {measurement} to {server_host_port}");
-            let req = common::pb::StoreMeasurementReq {measurement: Some(measurement.into())};
-            tx_outbound.send(req.clone()).await.with_context(|| anyhow!("Failed to send measurement {req:?}"))?;
+            let req = common::pb::StoreMeasurementReq {
+               measurements: vec![measurement.into()],
+               ..Default::default()
+            };
+            tx_outbound
+               .send(req.clone())
+               .await
+               .with_context(|| anyhow!("Failed to send measurement {req:?}"))?;
          },
          confirmed = inbound_stream.message() => {
             match confirmed {
-               Ok(Some(confirmed)) => {
-                  let Some(confirmed) = confirmed.confirmed else {continue};
-                  let Ok(confirmed) = confirmed.try_into() else {continue};
-                  state.remove_confirmed(confirmed);
-               },
+               Ok(Some(confirmed)) => state.on_response(confirmed).await,
                Ok(None) => { return Err(anyhow!("Received an empty response from the stream"));   },
                Err(e) => {   return Err(anyhow!("Got error from grpc: {e:?}"));  }
             }
          },
          _ = interval.tick() => {
-            let Some(measurement) = state.measurements.get_next_to_retry(chrono::Utc::now()) else {
-               continue
-            };
-            log::info!("Resending: {measurement} to {server_host_port}");
+            let batch = state.measurements.get_batch_to_retry(chrono::Utc::now(), MAX_BATCH_LEN);
+            if batch.is_empty() {
+               continue;
+            }
+            log::info!("Resending: {} measurements to {server_host_port}", batch.len());
             let req = common::pb::StoreMeasurementReq {
-               measurement: Some(measurement.into()),
+               measurements: batch.into_iter().map(Into::into).collect(),
+               ..Default::default()
             };
-            tx_outbound.send(req.clone()).await.with_context(|| anyhow!("Failed to send measurement {req:?}"))?;
+            tx_outbound
+               .send(req.clone())
+               .await
+               .with_context(|| anyhow!("Failed to send measurement {req:?}"))?;
          }
       }
    }
@@ -168,8 +425,10 @@ pub async fn poll_and_publish_forever(
    thread_rx: common::Rx,
    server_host_port: &str,
    client_config_provider: common::tls::ClientConfigProvider,
+   outbox: Option<crate::outbox::Sqlite>,
+   limits: Limits,
 ) -> Result<()> {
-   let mut state = State::new(thread_rx);
+   let mut state = State::new(thread_rx, outbox, limits).await?;
    loop {
       let res = one_iteration(ct, server_host_port, &mut state, &client_config_provider).await;
       if let Err(e) = res {
@@ -213,6 +472,8 @@ mod tests {
          temperature: Some(26.8),
          read_ts: ts_ymd(2024, 1, 1),
          error: "error1".to_string(),
+         quantities: Vec::new(),
+         health: None,
       }
    }
 
@@ -252,6 +513,166 @@ mod tests {
       assert_eq!(measurements, expected);
    }
 
+   fn measurement_read_at(id: &common::MeasurementId, read_ts: common::MicroSecTs) -> common::Measurement {
+      common::Measurement {
+         read_ts,
+         ..measurement(id)
+      }
+   }
+
+   #[test]
+   fn test_measurements_evict_oldest_by_read_ts_when_too_many() {
+      let now = chrono::Utc::now();
+      let sensor_id = &common::SensorId::new();
+      let (id1, id2, id3) = (create_id(sensor_id, 1), create_id(sensor_id, 2), create_id(sensor_id, 3));
+
+      let mut measurements = Measurements {
+         limits: limits(2, OverflowPolicy::DropOldest),
+         ..Default::default()
+      };
+      measurements.add(measurement_read_at(&id2, ts_ymd(2024, 1, 1)), now);
+      measurements.add(measurement_read_at(&id1, ts_ymd(2024, 1, 2)), now);
+      measurements.add(measurement_read_at(&id3, ts_ymd(2024, 1, 3)), now);
+
+      assert_eq!(measurements.evict(now), vec![id2]);
+      assert_eq!(measurements.by_id.len(), 2);
+      assert_eq!(measurements.evict(now), Vec::new());
+   }
+
+   #[test]
+   fn test_measurements_get_batch_to_retry_returns_due_ones_up_to_max_len() {
+      let now = chrono::Utc::now();
+      let sensor_id = &common::SensorId::new();
+
+      let mut measurements = Measurements::default();
+      for index in 1..=3 {
+         measurements.add(measurement(&create_id(sensor_id, index)), chrono::DateTime::UNIX_EPOCH);
+      }
+      measurements.add(measurement(&create_id(sensor_id, 4)), now);
+
+      assert_eq!(measurements.get_batch_to_retry(now, 2).len(), 2);
+      assert_eq!(measurements.get_batch_to_retry(now, 2).len(), 1);
+      assert_eq!(measurements.get_batch_to_retry(now, 2), Vec::new());
+      assert_eq!(measurements.by_id.len(), 4);
+   }
+
+   #[test]
+   fn test_measurements_remove_keeps_send_ts_index_in_sync() {
+      let now = chrono::Utc::now();
+      let sensor_id = &common::SensorId::new();
+      let (id1, id2, id3) = (create_id(sensor_id, 1), create_id(sensor_id, 2), create_id(sensor_id, 3));
+
+      let mut measurements = Measurements::default();
+      measurements.add(measurement(&id1), chrono::DateTime::UNIX_EPOCH);
+      measurements.add(measurement(&id2), chrono::DateTime::UNIX_EPOCH);
+      measurements.add(measurement(&id3), now);
+      // Retried ones are rescheduled at now:
+      assert_eq!(measurements.get_batch_to_retry(now, 1), vec![measurement(&id2)]);
+      assert_eq!(measurements.send_ts_by_id[&id2], now);
+
+      measurements.remove(&id1);
+      measurements.remove(&id2);
+      assert_eq!(measurements.by_send_ts, [(now, vec![id3.clone()])].into());
+      assert_eq!(measurements.send_ts_by_id, [(id3.clone(), now)].into());
+      measurements.remove(&id3);
+      assert!(measurements.by_send_ts.is_empty() && measurements.send_ts_by_id.is_empty());
+   }
+
+   fn limits(max_len: usize, policy: OverflowPolicy) -> Limits {
+      Limits {
+         max_len,
+         max_age: chrono::Duration::days(365 * 100),
+         policy,
+      }
+   }
+
+   #[test]
+   fn test_measurements_evict_newest_by_read_ts_when_too_many() {
+      let now = chrono::Utc::now();
+      let sensor_id = &common::SensorId::new();
+      let (id1, id2, id3) = (create_id(sensor_id, 1), create_id(sensor_id, 2), create_id(sensor_id, 3));
+
+      let mut measurements = Measurements {
+         limits: limits(2, OverflowPolicy::DropNewest),
+         ..Default::default()
+      };
+      measurements.add(measurement_read_at(&id1, ts_ymd(2024, 1, 1)), now);
+      measurements.add(measurement_read_at(&id3, ts_ymd(2024, 1, 3)), now);
+      measurements.add(measurement_read_at(&id2, ts_ymd(2024, 1, 2)), now);
+
+      assert_eq!(measurements.evict(now), vec![id3]);
+      assert_eq!(measurements.by_id.len(), 2);
+   }
+
+   #[test]
+   fn test_measurements_downsample_evicts_every_other_of_each_sensor_in_older_half() {
+      let now = chrono::Utc::now();
+      let (sen_a, sen_b) = (&common::SensorId::new(), &common::SensorId::new());
+
+      let mut measurements = Measurements {
+         limits: limits(8, OverflowPolicy::Downsample),
+         ..Default::default()
+      };
+      for day in 1..=9 {
+         let sensor_id = if day % 2 == 0 { sen_a } else { sen_b };
+         measurements.add(measurement_read_at(&create_id(sensor_id, day as i64), ts_ymd(2024, 1, day)), now);
+      }
+
+      // older half is days 1..=4: b1, a2, b3, a4 => every other of each sensor: b3, a4
+      assert_eq!(measurements.evict(now), vec![create_id(sen_b, 3), create_id(sen_a, 4)]);
+      assert_eq!(measurements.by_id.len(), 7);
+      assert_eq!(measurements.dropped, [(sen_a.clone(), 1), (sen_b.clone(), 1)].into());
+   }
+
+   #[test]
+   fn test_measurements_drops_are_reported_once_per_period() {
+      let now = *ts_ymd(2024, 1, 10);
+      let sensor_id = &common::SensorId::new();
+
+      let mut measurements = Measurements {
+         limits: limits(1, OverflowPolicy::DropOldest),
+         ..Default::default()
+      };
+      assert_eq!(measurements.take_drops_reports(now), Vec::new());
+
+      for index in 1..=3 {
+         measurements.add(measurement_read_at(&create_id(sensor_id, index), ts_ymd(2024, 1, 5)), now);
+         measurements.evict(now);
+      }
+      let reports = measurements.take_drops_reports(now);
+      assert_eq!(reports.len(), 1);
+      assert_eq!(reports[0].id.sensor_id, *sensor_id);
+      assert_eq!(reports[0].temperature, None);
+      assert!(reports[0].error.starts_with("Dropped 2 unconfirmed readings"), "{}", reports[0].error);
+
+      measurements.add(measurement_read_at(&create_id(sensor_id, 4), ts_ymd(2024, 1, 5)), now);
+      measurements.evict(now);
+      assert_eq!(measurements.take_drops_reports(now + chrono::Duration::seconds(59)), Vec::new());
+      let reports = measurements.take_drops_reports(now + chrono::Duration::minutes(1));
+      assert_eq!(reports.len(), 1);
+      assert!(reports[0].error.starts_with("Dropped 1 unconfirmed readings"), "{}", reports[0].error);
+      assert_eq!(measurements.dropped, [(sensor_id.clone(), 3)].into());
+   }
+
+   #[test]
+   fn test_measurements_evict_too_old() {
+      let now = *ts_ymd(2024, 1, 10);
+      let sensor_id = &common::SensorId::new();
+      let (id1, id2) = (create_id(sensor_id, 1), create_id(sensor_id, 2));
+
+      let mut measurements = Measurements::default();
+      measurements.add(measurement_read_at(&id1, ts_ymd(2024, 1, 1)), now);
+      measurements.add(measurement_read_at(&id2, ts_ymd(2024, 1, 5)), now);
+
+      assert_eq!(measurements.evict(now), vec![id1]);
+
+      let mut expected = Measurements::default();
+      expected.add(measurement_read_at(&id2, ts_ymd(2024, 1, 5)), now);
+      expected.dropped = [(sensor_id.clone(), 1)].into();
+      expected.unreported = [(sensor_id.clone(), 1)].into();
+      assert_eq!(measurements, expected);
+   }
+
    #[test]
    fn test_measurements_remove_if_no_elements() {
       let sensor_id = &common::SensorId::new();
@@ -264,4 +685,76 @@ mod tests {
       let expected = Measurements::default();
       assert_eq!(measurements, expected);
    }
+
+   fn rejected(id: &common::MeasurementId, code: common::pb::RejectReason) -> common::pb::Rejected {
+      common::pb::Rejected {
+         id: Some(id.clone().into()),
+         reason: format!("{code:?}"),
+         code: code.into(),
+      }
+   }
+
+   #[tokio::test]
+   async fn test_keeps_readings_rejected_as_unbound_until_bound() -> Result<()> {
+      let path = std::env::temp_dir().join(common::generate_random_string("publisher_test_", 10));
+      let outbox = crate::outbox::Sqlite::new(&path).await?;
+      let (_tx, rx) = tokio::sync::mpsc::channel(1);
+      let mut state = State::new(rx, Some(outbox.clone()), Limits::default()).await?;
+      let sensor_id = &common::SensorId::new();
+      let (id1, id2) = (create_id(sensor_id, 1), create_id(sensor_id, 2));
+      // Whole microseconds, as stored in the outbox:
+      let read_ts = common::MicroSecTs(chrono::SubsecRound::trunc_subsecs(chrono::Utc::now(), 6));
+      let measurement = |id| measurement_read_at(id, read_ts);
+      state.on_new_measurement(measurement(&id1)).await;
+      state.on_new_measurement(measurement(&id2)).await;
+
+      // The client has not been bound to the sensor yet, id2 is broken for good:
+      let resp = common::pb::StoreMeasurementResp {
+         rejected: vec![
+            rejected(&id1, common::pb::RejectReason::Unbound),
+            rejected(&id2, common::pb::RejectReason::Invalid),
+         ],
+         ..Default::default()
+      };
+      state.on_response(resp).await;
+      assert_eq!(outbox.read_all().await?, vec![measurement(&id1)]);
+
+      // Resent later and confirmed once the client has been bound:
+      let later = chrono::Utc::now() + chrono::Duration::minutes(2);
+      assert_eq!(state.measurements.get_batch_to_retry(later, MAX_BATCH_LEN), vec![measurement(&id1)]);
+      let resp = common::pb::StoreMeasurementResp {
+         confirmed_batch: vec![id1.clone().into()],
+         ..Default::default()
+      };
+      state.on_response(resp).await;
+      assert_eq!(outbox.read_all().await?, Vec::new());
+      assert_eq!(state.measurements.get_batch_to_retry(later, MAX_BATCH_LEN), Vec::new());
+      std::fs::remove_file(path)?;
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_drops_health_summaries_for_servers_not_supporting_them() -> Result<()> {
+      let (_tx, rx) = tokio::sync::mpsc::channel(1);
+      let mut state = State::new(rx, None, Limits::default()).await?;
+      let sensor_id = &common::SensorId::new();
+      let (id1, id2) = (create_id(sensor_id, 1), create_id(sensor_id, 2));
+      let read_ts = common::MicroSecTs(chrono::Utc::now());
+      state.on_new_measurement(measurement_read_at(&id1, read_ts)).await;
+      let health = common::Health {
+         since: read_ts,
+         reads: 10,
+         failures: 0,
+         consecutive_failures: 0,
+         mean_latency: std::time::Duration::from_millis(750),
+         last_error: String::new(),
+      };
+      state.on_new_measurement(common::Measurement::from_health(&id2, health, read_ts)).await;
+
+      state.drop_health_summaries().await;
+      let later = chrono::Utc::now() + chrono::Duration::minutes(2);
+      let batch = state.measurements.get_batch_to_retry(later, MAX_BATCH_LEN);
+      assert_eq!(batch, vec![measurement_read_at(&id1, read_ts)]);
+      Ok(())
+   }
 }
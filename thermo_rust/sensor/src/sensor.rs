@@ -21,10 +21,32 @@ fn read_exactly_ignoring_early_eof(reader: &mut impl std::io::Read, max_size: us
    buffer.truncate(total_read);
    Ok(buffer)
 }
+/// Raw value, that DS18B20 reports after power-on-reset, before the first conversion has completed: 85 °C
+const POWER_ON_RESET_VALUE: i32 = 85000;
+/// Raw value, that the kernel driver reports when the sensor is disconnected: -127 °C
+const DISCONNECTED_VALUE: i32 = -127000;
+
 fn parse(reader: &mut impl std::io::Read) -> Result<f64> {
    const MAX: usize = 2 * 1024;
    let data = read_exactly_ignoring_early_eof(reader, MAX)?;
 
+   {
+      // The first line ends with the result of CRC check, e.g. "26 01 4b 46 7f ff 0a 10 64 : crc=64 YES":
+      let end = data.iter().position(|x| *x == b'\n').unwrap_or(data.len());
+      let first_line = &data[..end];
+      let marker = b"crc=";
+      let start = first_line
+         .windows(marker.len())
+         .position(|window| window == marker)
+         .with_context(|| anyhow!("Failed to find crc= in: {:?}", std::str::from_utf8(first_line)))?;
+      let crc = String::from_utf8_lossy(&first_line[start..]);
+      match crc.split_whitespace().last() {
+         Some("YES") => {}
+         Some("NO") => return Err(anyhow!("CRC check failed: {crc:?}")),
+         _ => return Err(anyhow!("Failed to find YES/NO after crc= in: {crc:?}")),
+      }
+   }
+
    let second_line = {
       let data = &data[..std::cmp::min(MAX, data.len())];
       let start = data.iter().position(|x| *x == b'\n').with_context(|| {
@@ -44,6 +66,11 @@ fn parse(reader: &mut impl std::io::Read) -> Result<f64> {
       let temp =
          std::str::from_utf8(temp).with_context(|| anyhow!("Failed to convert {:?} to string", temp))?;
       let temp: i32 = temp.parse().with_context(|| anyhow!("Failed to parse {temp} as integer"))?;
+      match temp {
+         POWER_ON_RESET_VALUE => return Err(anyhow!("Power-on-reset value: {temp}, the reading is not ready")),
+         DISCONNECTED_VALUE => return Err(anyhow!("Disconnected value: {temp}")),
+         _ => {}
+      }
       temp as f64 / 1000.0
    };
    Ok(temperature)
@@ -54,85 +81,343 @@ fn parse(reader: &mut impl std::io::Read) -> Result<f64> {
 
 //
 // ===========================================================================================================
-// Polling actor / thread
+// Drivers
 
+/// Reads quantities (at most one per kind) from a sensor
+pub trait Driver: Send {
+   fn read(&mut self) -> Result<Vec<common::Quantity>>;
+}
 
-#[derive(Debug, Clone)]
+fn temperature(value: f64) -> Vec<common::Quantity> {
+   vec![common::Quantity::new(common::QuantityKind::Temperature, value)]
+}
+
+/// How many times to re-read DS18B20 after a failed read (CRC mismatch, sentinel value, ...) by default
+pub const DEFAULT_DS18B20_RETRIES: u32 = 2;
+
+/// Gives the bus a moment to settle between attempts
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Returns the first successful result of `read`, which is called at most 1 + retries times
+fn read_with_retries(
+   retries: u32,
+   delay: std::time::Duration,
+   mut read: impl FnMut() -> Result<f64>,
+) -> Result<f64> {
+   let mut attempt = 0;
+   loop {
+      attempt += 1;
+      match read() {
+         Ok(value) => return Ok(value),
+         Err(why) if attempt > retries => {
+            return Err(why.context(anyhow!("Failed to read in {attempt} attempt(s)")));
+         }
+         Err(why) => {
+            log::debug!("Attempt {attempt} of {} failed: {why:?}", retries + 1);
+            std::thread::sleep(delay);
+         }
+      }
+   }
+}
+
+/// 1-Wire DS18B20 temperature sensor, the file is w1_slave
+pub struct Ds18b20 {
+   pub path: std::path::PathBuf,
+   pub retries: u32,
+}
+
+impl Driver for Ds18b20 {
+   fn read(&mut self) -> Result<Vec<common::Quantity>> {
+      read_with_retries(self.retries, RETRY_DELAY, || poll_sensor_iteration(&self.path)).map(temperature)
+   }
+}
+
+/// A file containing just a number, e.g. /sys/class/hwmon/hwmon0/temp1_input or
+/// /sys/class/thermal/thermal_zone0/temp. The value of the quantity is the number multiplied by scale.
+pub struct NumberFile {
+   pub path: std::path::PathBuf,
+   pub kind: common::QuantityKind,
+   pub scale: f64,
+}
+
+impl Driver for NumberFile {
+   fn read(&mut self) -> Result<Vec<common::Quantity>> {
+      let path = &self.path;
+      let content = std::fs::read_to_string(path).with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+      let number: f64 = content
+         .trim()
+         .parse()
+         .with_context(|| anyhow!("Failed to parse {:?} of {path:?} as number", content.trim()))?;
+      Ok(vec![common::Quantity::new(self.kind, number * self.scale)])
+   }
+}
+
+/// Returns the temperatures one after another in a loop, for testing without hardware
+pub struct Fake {
+   pub values: Vec<f64>,
+   pub next: usize,
+}
+
+impl Driver for Fake {
+   fn read(&mut self) -> Result<Vec<common::Quantity>> {
+      let value = *self.values.get(self.next).ok_or_else(|| anyhow!("There are no values to return"))?;
+      self.next = (self.next + 1) % self.values.len();
+      Ok(temperature(value))
+   }
+}
+
+/// Which driver to poll the sensor with, and its parameters
+#[derive(Debug, Clone, PartialEq)]
+pub enum DriverSpec {
+   Ds18b20 {
+      retries: u32,
+   },
+   NumberFile {
+      kind: common::QuantityKind,
+      scale: f64,
+   },
+   Fake {
+      values: Vec<f64>,
+   },
+}
+
+impl DriverSpec {
+   pub fn create(&self, path: &std::path::Path) -> Box<dyn Driver> {
+      let path = path.to_path_buf();
+      match self {
+         DriverSpec::Ds18b20 { retries } => Box::new(Ds18b20 {
+            path,
+            retries: *retries,
+         }),
+         DriverSpec::NumberFile { kind, scale } => Box::new(NumberFile {
+            path,
+            kind: *kind,
+            scale: *scale,
+         }),
+         DriverSpec::Fake { values } => Box::new(Fake {
+            values: values.clone(),
+            next: 0,
+         }),
+      }
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Polling actor
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Meta {
    pub id: common::SensorId,
+   /// Not used by the fake driver
    pub path: std::path::PathBuf,
+   pub driver: DriverSpec,
+   /// How often to poll the sensor
+   pub interval: std::time::Duration,
+   pub filter: crate::filter::Settings,
 }
 
 
-pub struct Waiter {
-   start: std::time::Instant,
+/// A read of DS18B20 takes ~750ms, a read, that takes much longer, means the bus is stuck
+const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Returns the first multiple of interval since unix epoch after now, so that ticks of e.g. 20s interval are
+/// at :00, :20 and :40 seconds regardless of when polling started and how long reads take
+pub fn next_tick(
+   now: chrono::DateTime<chrono::Utc>,
    interval: std::time::Duration,
+) -> chrono::DateTime<chrono::Utc> {
+   let interval_us = (interval.as_micros() as i64).max(1);
+   let now_us = now.timestamp_micros();
+   let tick_us = (now_us.div_euclid(interval_us) + 1) * interval_us;
+   chrono::DateTime::from_timestamp_micros(tick_us).unwrap_or(now)
 }
 
-impl Waiter {
-   fn new(interval: std::time::Duration) -> Self {
-      Waiter {
-         start: std::time::Instant::now(),
-         interval,
+/// Sleeps until the next tick, returns false if cancelled before it
+pub async fn sleep_until_next_tick(
+   interval: std::time::Duration,
+   ct: &tokio_util::sync::CancellationToken,
+) -> bool {
+   let now = chrono::Utc::now();
+   let delay = (next_tick(now, interval) - now).to_std().unwrap_or_default();
+   tokio::select! {
+      _ = ct.cancelled() => false,
+      _ = tokio::time::sleep(delay) => true,
+   }
+}
+
+/// How often each poller sends a health summary of its sensor to the server
+const HEALTH_REPORT_PERIOD: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// A sensor, that has failed this many reads in a row, is logged as failing
+const CONSECUTIVE_FAILURES_TO_WARN: u64 = 3;
+
+/// Read statistics of a sensor since the last health summary
+#[derive(Debug, Clone, PartialEq)]
+struct HealthStats {
+   since: chrono::DateTime<chrono::Utc>,
+   reads: u64,
+   failures: u64,
+   /// Not reset by summaries: a sensor may keep failing across periods
+   consecutive_failures: u64,
+   total_latency: std::time::Duration,
+   last_error: String,
+}
+
+impl HealthStats {
+   fn new(since: chrono::DateTime<chrono::Utc>) -> Self {
+      Self {
+         since,
+         reads: 0,
+         failures: 0,
+         consecutive_failures: 0,
+         total_latency: Default::default(),
+         last_error: String::new(),
       }
    }
-   fn wait(&mut self, ct: &tokio_util::sync::CancellationToken) {
-      let end = self.start + self.interval;
-      while std::time::Instant::now() < end && !ct.is_cancelled() {
-         std::thread::sleep(std::time::Duration::from_millis(10));
+
+   fn record<T>(&mut self, sensor_id: &common::SensorId, latency: std::time::Duration, res: &Result<T>) {
+      self.reads += 1;
+      self.total_latency += latency;
+      match res {
+         Ok(_) => {
+            if self.consecutive_failures >= CONSECUTIVE_FAILURES_TO_WARN {
+               log::info!("{sensor_id} has recovered after {} failed reads", self.consecutive_failures);
+            }
+            self.consecutive_failures = 0;
+         }
+         Err(why) => {
+            self.failures += 1;
+            self.consecutive_failures += 1;
+            self.last_error = format!("{why:#}");
+            if self.consecutive_failures == CONSECUTIVE_FAILURES_TO_WARN {
+               log::warn!("{sensor_id} has failed {} reads in a row: {why:#}", self.consecutive_failures);
+            }
+         }
       }
-      self.start = std::time::Instant::now();
    }
-}
 
+   /// Returns the summary since the last one and starts a new period
+   fn take(&mut self, now: chrono::DateTime<chrono::Utc>) -> common::Health {
+      let health = common::Health {
+         since: self.since.into(),
+         reads: self.reads,
+         failures: self.failures,
+         consecutive_failures: self.consecutive_failures,
+         mean_latency: match self.reads {
+            0 => Default::default(),
+            reads => self.total_latency / reads.min(u32::MAX as u64) as u32,
+         },
+         last_error: std::mem::take(&mut self.last_error),
+      };
+      *self = Self {
+         consecutive_failures: self.consecutive_failures,
+         ..Self::new(now)
+      };
+      health
+   }
+}
 
+/// Reads the driver in a blocking thread. The driver stays locked by a read, that has timed out, until it
+/// completes, so that stuck reads do not pile up.
+async fn read_with_timeout(
+   driver: &std::sync::Arc<std::sync::Mutex<Box<dyn Driver>>>,
+   timeout: std::time::Duration,
+) -> Result<Vec<common::Quantity>> {
+   let driver = driver.clone();
+   let read = tokio::task::spawn_blocking(move || match driver.try_lock() {
+      Ok(mut guard) => guard.read(),
+      Err(std::sync::TryLockError::WouldBlock) => Err(anyhow!("The previous read has not completed yet")),
+      Err(std::sync::TryLockError::Poisoned(poisoned)) => {
+         // A previous read panicked, drivers keep no state, that could be left inconsistent by it:
+         log::warn!("The previous read panicked, reading again");
+         let mut guard = poisoned.into_inner();
+         driver.clear_poison();
+         guard.read()
+      }
+   });
+   match tokio::time::timeout(timeout, read).await {
+      Ok(res) => res.with_context(|| anyhow!("Read task failed"))?,
+      Err(_) => Err(anyhow!("Read timed out after {timeout:?}")),
+   }
+}
 
 pub fn poll_sensor_iteration(path: &std::path::Path) -> Result<f64> {
    let mut file = std::fs::File::open(path).with_context(|| anyhow!("Failed to open file: {path:?}"))?;
    parse(&mut file).with_context(|| anyhow!("Failed to parse file: {path:?}"))
 }
 
-pub fn poll_sensor_forever(
-   tx: tokio::sync::mpsc::Sender<common::Measurement>,
+pub async fn poll_sensor_forever(tx: common::Tx, meta: Meta, ct: tokio_util::sync::CancellationToken) {
+   let driver = meta.driver.create(&meta.path);
+   poll_forever(tx, meta, driver, READ_TIMEOUT, HEALTH_REPORT_PERIOD, ct).await
+}
+
+async fn poll_forever(
+   tx: common::Tx,
    meta: Meta,
+   driver: Box<dyn Driver>,
+   read_timeout: std::time::Duration,
+   health_period: std::time::Duration,
    ct: tokio_util::sync::CancellationToken,
-   interval: std::time::Duration,
 ) {
-   log::info!("Starting polling thread: {meta:?}");
-   let mut waiter = Waiter::new(interval);
+   log::info!("Starting polling: {meta:?}");
+   let driver = std::sync::Arc::new(std::sync::Mutex::new(driver));
    let mut id = common::MeasurementId::new(&meta.id);
-   while !ct.is_cancelled() {
+   let mut health = HealthStats::new(chrono::Utc::now());
+   // Number of readings, that did not fit into the channel and have not been reported yet:
+   let mut dropped: u64 = 0;
+   // The first reading is taken right away, the next ones on ticks:
+   loop {
       id.next();
       let ts = chrono::Utc::now().into();
-      let measurement = match poll_sensor_iteration(&meta.path) {
-         Ok(temperature) => common::Measurement::from_ok(&id, temperature, ts),
-         Err(why) => common::Measurement::from_err(&id, format!("{why:?}"), ts),
+      let started = std::time::Instant::now();
+      let res = tokio::select! {
+         _ = ct.cancelled() => break,
+         res = read_with_timeout(&driver, read_timeout) => res,
       };
+      let res = res.and_then(|quantities| common::Measurement::from_quantities(&id, quantities, ts));
+      health.record(&meta.id, started.elapsed(), &res);
+      let measurement = res.unwrap_or_else(|why| common::Measurement::from_err(&id, format!("{why:?}"), ts));
       let res = tx
          .try_send(measurement.clone())
          .with_context(|| anyhow!("Failed to send measurement {:?} in channel", measurement));
-      if let Err(e) = res {
-         log::warn!("Failed to send measurements in channel: {e:?}");
+      match res {
+         Ok(()) if dropped > 0 => {
+            id.next();
+            let error = format!("Dropped {dropped} readings: the publisher is lagging behind");
+            if tx.try_send(common::Measurement::from_err(&id, error, ts)).is_ok() {
+               dropped = 0;
+            }
+         }
+         Ok(()) => {}
+         Err(e) => {
+            dropped += 1;
+            log::warn!("Failed to send measurements in channel, dropped since last report: {dropped}: {e:?}");
+         }
+      }
+      let now = chrono::Utc::now();
+      if (now - health.since).to_std().unwrap_or_default() >= health_period {
+         id.next();
+         let summary = common::Measurement::from_health(&id, health.take(now), now.into());
+         if let Err(why) = tx.try_send(summary) {
+            log::warn!("Failed to send health summary of {} in channel: {why:?}", meta.id);
+         }
+      }
+      if !sleep_until_next_tick(meta.interval, &ct).await {
+         break;
       }
-      waiter.wait(&ct);
    }
-   log::info!("Stopped polling thread: {meta:?}");
+   log::info!("Stopped polling: {meta:?}");
 }
 
-pub fn spawn_pollers(
-   metas: &[Meta],
-   interval: std::time::Duration,
-   ct: &tokio_util::sync::CancellationToken,
-) -> tokio::sync::mpsc::Receiver<common::Measurement> {
-   let (tx, rx) = tokio::sync::mpsc::channel(100);
+/// Capacity of the channel from pollers to the publisher
+pub const CHANNEL_CAPACITY: usize = 100;
 
+pub fn spawn_pollers(tx: &common::Tx, metas: &[Meta], ct: &tokio_util::sync::CancellationToken) {
    for meta in metas {
-      let tx = tx.clone();
-      let ct = ct.clone();
-      let meta = meta.clone();
-      std::thread::spawn(move || poll_sensor_forever(tx, meta, ct, interval));
+      tokio::spawn(poll_sensor_forever(tx.clone(), meta.clone(), ct.clone()));
    }
-   rx
 }
 
 
@@ -201,6 +486,10 @@ mod tests {
             reader: FakeRead::from(vec!["26: crc=64 YES\n 26 t=18375\n 26".as_bytes().to_vec()]),
             expected: 18.375,
          }, // more than 2 lines, 2nd line has correct data
+         OkParseTC {
+            reader: FakeRead::from(vec!["55 05 4b 46 7f ff : crc=64 YES\n 55 t=85125".as_bytes().to_vec()]),
+            expected: 85.125,
+         }, // close to, but not power-on-reset value
             //
       ];
 
@@ -237,12 +526,75 @@ mod tests {
          ErrorParseTC {
             reader: FakeRead::from(vec!["26: crc=64 YES\n 26 t=1 t=2".as_bytes().to_vec()]),
          }, // multiple t=
+         ErrorParseTC {
+            reader: FakeRead::from(vec!["26: crc=64 NO\n 26 t=18375".as_bytes().to_vec()]),
+         }, // CRC mismatch
+         ErrorParseTC {
+            reader: FakeRead::from(vec!["26: 64 YES\n 26 t=18375".as_bytes().to_vec()]),
+         }, // no crc=
+         ErrorParseTC {
+            reader: FakeRead::from(vec!["26: crc=64\n 26 t=18375".as_bytes().to_vec()]),
+         }, // neither YES nor NO
+         ErrorParseTC {
+            reader: FakeRead::from(vec!["50 05 4b 46 7f ff : crc=1c YES\n 50 t=85000".as_bytes().to_vec()]),
+         }, // power-on-reset value
+         ErrorParseTC {
+            reader: FakeRead::from(vec!["26: crc=64 YES\n 26 t=-127000".as_bytes().to_vec()]),
+         }, // disconnected
             //
       ];
 
-      for tc in test_cases.iter_mut() {
+      for (i, tc) in test_cases.iter_mut().enumerate() {
          let res = parse(&mut tc.reader);
-         assert!(res.is_err());
+         assert!(res.is_err(), "Test-case #{i}");
+      }
+   }
+
+   #[test]
+   fn test_read_with_retries() {
+      struct RetriesTC {
+         retries: u32,
+         results: Vec<Result<f64, &'static str>>,
+         expected: Result<f64, &'static str>,
+         expected_calls: usize,
+      }
+      let test_cases = [
+         //
+         RetriesTC {
+            retries: 2,
+            results: vec![Ok(1.0)],
+            expected: Ok(1.0),
+            expected_calls: 1,
+         }, // first attempt succeeds
+         RetriesTC {
+            retries: 2,
+            results: vec![Err("crc"), Err("85"), Ok(1.0)],
+            expected: Ok(1.0),
+            expected_calls: 3,
+         }, // last retry succeeds
+         RetriesTC {
+            retries: 1,
+            results: vec![Err("crc"), Err("85"), Ok(1.0)],
+            expected: Err("85"),
+            expected_calls: 2,
+         }, // retries exhausted, the last error is returned
+         RetriesTC {
+            retries: 0,
+            results: vec![Err("crc"), Ok(1.0)],
+            expected: Err("crc"),
+            expected_calls: 1,
+         }, // no retries
+            //
+      ];
+
+      for (i, tc) in test_cases.into_iter().enumerate() {
+         let mut calls = 0;
+         let res = read_with_retries(tc.retries, std::time::Duration::ZERO, || {
+            calls += 1;
+            tc.results[calls - 1].map_err(|e| anyhow!(e))
+         });
+         assert_eq!(res.map_err(|e| e.root_cause().to_string()), tc.expected.map_err(String::from), "#{i}");
+         assert_eq!(calls, tc.expected_calls, "Test-case #{i}");
       }
    }
 
@@ -298,4 +650,197 @@ mod tests {
       assert_eq!(res, "12345".as_bytes());
       Ok(())
    }
+
+   // --------------------------------------------------------------------------------------------------------
+   // drivers
+
+   #[test]
+   fn test_number_file_scales_number() -> Result<()> {
+      let path = std::env::temp_dir().join(common::generate_random_string("temp1_input_", 10));
+      std::fs::write(&path, "45250\n")?;
+      let spec = DriverSpec::NumberFile {
+         kind: common::QuantityKind::Voltage,
+         scale: 0.001,
+      };
+      let mut driver = spec.create(&path);
+      let res = driver.read();
+      std::fs::write(&path, "asdf\n")?;
+      let err = driver.read();
+      std::fs::remove_file(&path)?;
+
+      assert_eq!(res?, vec![common::Quantity::new(common::QuantityKind::Voltage, 45.25)]);
+      assert!(format!("{:#}", err.unwrap_err()).starts_with("Failed to parse \"asdf\""));
+      Ok(())
+   }
+
+   #[test]
+   fn test_fake_returns_values_in_loop() -> Result<()> {
+      let mut driver = DriverSpec::Fake { values: vec![1.5, 2.5] }.create(std::path::Path::new(""));
+      let res = (0..5).map(|_| driver.read()).collect::<Result<Vec<_>>>()?;
+      let expected: Vec<_> = [1.5, 2.5, 1.5, 2.5, 1.5].into_iter().map(temperature).collect();
+      assert_eq!(res, expected);
+
+      let mut empty = DriverSpec::Fake { values: vec![] }.create(std::path::Path::new(""));
+      assert!(empty.read().is_err());
+      Ok(())
+   }
+
+   // --------------------------------------------------------------------------------------------------------
+   // polling
+
+   #[test]
+   fn test_next_tick_is_aligned_to_wall_clock() {
+      use chrono::TimeZone;
+      let at = |min: u32, sec: u32, ms: i64| {
+         chrono::Utc.with_ymd_and_hms(2024, 1, 1, 10, min, sec).unwrap() + chrono::Duration::milliseconds(ms)
+      };
+      let secs = std::time::Duration::from_secs;
+      let test_cases = [
+         //
+         (at(0, 0, 0), secs(20), at(0, 20, 0)),   // on a tick => the next one
+         (at(0, 0, 1), secs(20), at(0, 20, 0)),   // just after a tick
+         (at(0, 19, 999), secs(20), at(0, 20, 0)), // just before a tick
+         (at(0, 45, 0), secs(20), at(1, 0, 0)),   // crosses a minute
+         (at(7, 30, 0), secs(600), at(10, 0, 0)), // 10 minutes
+      ];
+      for (i, (now, interval, expected)) in test_cases.into_iter().enumerate() {
+         assert_eq!(next_tick(now, interval), expected, "Test-case #{i}");
+      }
+   }
+
+   /// Panics on the first read
+   struct PanickingDriver {
+      reads: usize,
+   }
+   impl Driver for PanickingDriver {
+      fn read(&mut self) -> Result<Vec<common::Quantity>> {
+         self.reads += 1;
+         if self.reads == 1 {
+            panic!("Driver bug");
+         }
+         Ok(temperature(1.0))
+      }
+   }
+
+   #[tokio::test]
+   async fn test_read_with_timeout_recovers_after_panicked_read() -> Result<()> {
+      let driver: Box<dyn Driver> = Box::new(PanickingDriver { reads: 0 });
+      let driver = std::sync::Arc::new(std::sync::Mutex::new(driver));
+      let timeout = std::time::Duration::from_secs(5);
+      let why = read_with_timeout(&driver, timeout).await.unwrap_err();
+      assert!(format!("{why:?}").contains("Read task failed"), "{why:?}");
+      assert!(driver.is_poisoned());
+
+      assert_eq!(read_with_timeout(&driver, timeout).await?, temperature(1.0));
+      assert!(!driver.is_poisoned());
+      Ok(())
+   }
+
+   struct SlowDriver;
+   impl Driver for SlowDriver {
+      fn read(&mut self) -> Result<Vec<common::Quantity>> {
+         std::thread::sleep(std::time::Duration::from_millis(300));
+         Ok(temperature(1.0))
+      }
+   }
+
+   fn meta(interval: std::time::Duration) -> Meta {
+      Meta {
+         id: common::SensorId::new(),
+         path: Default::default(),
+         driver: DriverSpec::Fake { values: vec![1.0] },
+         interval,
+         filter: Default::default(),
+      }
+   }
+
+   #[tokio::test]
+   async fn test_poll_forever_reads_right_away_and_stops_on_cancel() -> Result<()> {
+      let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+      let ct = tokio_util::sync::CancellationToken::new();
+      let meta = meta(std::time::Duration::from_secs(3600));
+      let driver = meta.driver.create(&meta.path);
+      let poller = tokio::spawn(poll_forever(tx, meta, driver, READ_TIMEOUT, HEALTH_REPORT_PERIOD, ct.clone()));
+
+      let first = tokio::time::timeout(std::time::Duration::from_secs(1), rx.recv()).await?.unwrap();
+      assert_eq!(first.temperature, Some(1.0));
+
+      ct.cancel();
+      tokio::time::timeout(std::time::Duration::from_secs(1), poller).await??;
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_poll_forever_times_out_stuck_reads() -> Result<()> {
+      let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+      let ct = tokio_util::sync::CancellationToken::new();
+      let meta = meta(std::time::Duration::from_millis(100));
+      let timeout = std::time::Duration::from_millis(50);
+      let driver = Box::new(SlowDriver);
+      let poller = tokio::spawn(poll_forever(tx, meta, driver, timeout, HEALTH_REPORT_PERIOD, ct.clone()));
+
+      let first = rx.recv().await.unwrap();
+      assert!(first.error.contains("Read timed out"), "{first}");
+      // The first read is still in progress on the next ticks (unless its thread started late under load):
+      let mut next = Vec::new();
+      while next.len() < 3 {
+         next.push(rx.recv().await.unwrap().error);
+      }
+      assert!(next.iter().any(|error| error.contains("The previous read has not completed yet")), "{next:?}");
+
+      ct.cancel();
+      poller.await?;
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_poll_forever_sends_health_summaries() -> Result<()> {
+      let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+      let ct = tokio_util::sync::CancellationToken::new();
+      let meta = meta(std::time::Duration::from_millis(100));
+      let driver = meta.driver.create(&meta.path);
+      let period = std::time::Duration::from_millis(250);
+      let poller = tokio::spawn(poll_forever(tx, meta, driver, READ_TIMEOUT, period, ct.clone()));
+
+      let summary = loop {
+         let measurement = tokio::time::timeout(std::time::Duration::from_secs(2), rx.recv()).await?.unwrap();
+         if let Some(health) = measurement.health {
+            assert_eq!(measurement.temperature, None);
+            break health;
+         }
+      };
+      assert!(summary.reads >= 2, "{summary}");
+      assert_eq!((summary.failures, summary.consecutive_failures), (0, 0));
+
+      ct.cancel();
+      poller.await?;
+      Ok(())
+   }
+
+   #[test]
+   fn test_health_stats_count_failures() {
+      use chrono::TimeZone;
+      let ts = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+      let sensor_id = common::SensorId::new();
+      let ms = std::time::Duration::from_millis;
+      let mut stats = HealthStats::new(ts);
+      stats.record(&sensor_id, ms(100), &Ok(()));
+      stats.record(&sensor_id, ms(200), &Err::<(), _>(anyhow!("error1")));
+      stats.record(&sensor_id, ms(600), &Err::<(), _>(anyhow!("error2")));
+
+      let later = ts + chrono::Duration::hours(1);
+      let expected = common::Health {
+         since: ts.into(),
+         reads: 3,
+         failures: 2,
+         consecutive_failures: 2,
+         mean_latency: ms(300),
+         last_error: "error2".to_string(),
+      };
+      assert_eq!(stats.take(later), expected);
+      // Consecutive failures carry over to the next period:
+      let mut expected = HealthStats::new(later);
+      expected.consecutive_failures = 2;
+      assert_eq!(stats, expected);
+   }
 }
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Config
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct AlertingArgs {
+   /// A sensor that dropped below its min is considered recovered only after its temperature gets back
+   /// to at least min + hysteresis (in degrees). Prevents flapping of a sensor hovering at the threshold.
+   #[arg(long, default_value_t = 0.5)]
+   alert_hysteresis: f64,
+
+   /// How often to remind that a sensor is still below its min, in minutes
+   #[arg(long, default_value_t = 60)]
+   alert_cooldown_mins: i64,
+}
+
+impl AlertingArgs {
+   pub fn config(&self) -> Config {
+      Config {
+         hysteresis: self.alert_hysteresis,
+         cooldown: chrono::Duration::minutes(self.alert_cooldown_mins),
+      }
+   }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+   pub hysteresis: f64,
+   pub cooldown: chrono::Duration,
+}
+
+
+//
+// ===========================================================================================================
+// State machine
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+   Below { temperature: f64, min: f64 },
+   StillBelow { temperature: f64, min: f64 },
+   Recovered { temperature: f64, min: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SensorState {
+   Normal,
+   Below { last_alert: chrono::DateTime<chrono::Utc> },
+}
+
+pub struct Alerting {
+   config: Config,
+   by_sensor: std::collections::HashMap<common::SensorId, SensorState>,
+}
+
+impl Alerting {
+   pub fn new(config: Config) -> Self {
+      Self {
+         config,
+         by_sensor: Default::default(),
+      }
+   }
+
+   /// Feeds a measurement of a sensor with the given min into the state machine and returns an event, that
+   /// must be reported (if any). Time is taken from read_ts of measurements, not from the wall clock.
+   pub fn on_measurement(&mut self, measurement: &common::Measurement, min: f64) -> Option<Event> {
+      let temperature = measurement.temperature.filter(|t| !t.is_nan())?;
+      let now = measurement.read_ts.0;
+      let state = self.by_sensor.entry(measurement.id.sensor_id.clone()).or_insert(SensorState::Normal);
+
+      match state {
+         SensorState::Normal => {
+            if temperature >= min {
+               return None;
+            }
+            *state = SensorState::Below { last_alert: now };
+            Some(Event::Below { temperature, min })
+         }
+         SensorState::Below { last_alert } => {
+            if temperature >= min + self.config.hysteresis {
+               *state = SensorState::Normal;
+               return Some(Event::Recovered { temperature, min });
+            }
+            if temperature >= min || now - *last_alert < self.config.cooldown {
+               return None;
+            }
+            *last_alert = now;
+            Some(Event::StillBelow { temperature, min })
+         }
+      }
+   }
+}
+
+
+fn format_event(sensor: &crate::sensor::Sensor, event: &Event) -> String {
+   let name = format!("{} ({})", sensor.name, sensor.location);
+   match event {
+      Event::Below { temperature, min } => {
+         format!("ALERT: {name}: temperature {temperature:.1} dropped below min {min:.1}")
+      }
+      Event::StillBelow { temperature, min } => {
+         format!("ALERT: {name}: temperature {temperature:.1} is still below min {min:.1}")
+      }
+      Event::Recovered { temperature, min } => {
+         format!("RECOVERED: {name}: temperature {temperature:.1} is back above min {min:.1}")
+      }
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Actor
+
+async fn on_measurement(
+   alerting: &mut Alerting,
+   measurement: &common::Measurement,
+   sensor_db: &crate::sensor::Sqlite,
+   router: &crate::subscription::Router,
+) -> Result<()> {
+   use crate::notifier::Notifier as _;
+   use crate::sensor::Db as _;
+   let sensor_id = &measurement.id.sensor_id;
+   let Some(sensor) = sensor_db
+      .get_by_id(sensor_id)
+      .await
+      .with_context(|| anyhow!("Failed to get sensor {sensor_id}"))?
+   else {
+      log::debug!("Sensor {sensor_id} is not registered => skipping alerting for it");
+      return Ok(());
+   };
+
+   let Some(event) = alerting.on_measurement(measurement, sensor.min) else {
+      return Ok(());
+   };
+   let text = format_event(&sensor, &event);
+   log::info!("Sending alert: {text}");
+   let notifiers = router.notifiers(crate::subscription::Kind::Threshold, sensor_id).await?;
+   notifiers.send_text(&text).await.with_context(|| anyhow!("Failed to send alert {event:?}"))
+}
+
+pub fn start(
+   mut rx: tokio::sync::broadcast::Receiver<common::Measurement>,
+   sensor_db: &crate::sensor::Sqlite,
+   router: crate::subscription::Router,
+   config: Config,
+) -> Result<()> {
+   tokio::task::spawn({
+      let sensor_db = sensor_db.clone();
+      async move {
+         let mut alerting = Alerting::new(config);
+         loop {
+            let measurement = match rx.recv().await {
+               Ok(measurement) => measurement,
+               Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                  log::warn!("Alerting is lagging behind, skipped {n} measurements");
+                  continue;
+               }
+               Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                  log::info!("Measurements channel is closed => stopping alerting");
+                  return;
+               }
+            };
+            let res = on_measurement(&mut alerting, &measurement, &sensor_db, &router).await;
+            if let Err(why) = res {
+               log::warn!("Alerting on_measurement() failed: {why:?}");
+            }
+         }
+      }
+   });
+   Ok(())
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn config() -> Config {
+      Config {
+         hysteresis: 0.5,
+         cooldown: chrono::Duration::minutes(60),
+      }
+   }
+
+   fn get_sen_id() -> common::SensorId { "sen_asdf_1".to_string().try_into().unwrap() }
+
+   fn measurement(minute: i64, temperature: f64) -> common::Measurement {
+      use chrono::TimeZone;
+      let ts = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::minutes(minute);
+      let id = common::MeasurementId {
+         sensor_id: get_sen_id(),
+         index: minute,
+      };
+      common::Measurement::from_ok(&id, temperature, common::MicroSecTs(ts))
+   }
+
+   #[test]
+   fn test_no_events_above_min() {
+      let mut alerting = Alerting::new(config());
+      assert_eq!(alerting.on_measurement(&measurement(0, 10.0), 5.0), None);
+      assert_eq!(alerting.on_measurement(&measurement(1, 5.0), 5.0), None);
+   }
+
+   #[test]
+   fn test_below_is_reported_once_within_cooldown() {
+      let mut alerting = Alerting::new(config());
+      assert_eq!(
+         alerting.on_measurement(&measurement(0, 4.0), 5.0),
+         Some(Event::Below {
+            temperature: 4.0,
+            min: 5.0
+         })
+      );
+      assert_eq!(alerting.on_measurement(&measurement(1, 3.0), 5.0), None);
+      assert_eq!(alerting.on_measurement(&measurement(59, 3.0), 5.0), None);
+      assert_eq!(
+         alerting.on_measurement(&measurement(60, 3.0), 5.0),
+         Some(Event::StillBelow {
+            temperature: 3.0,
+            min: 5.0
+         })
+      );
+      assert_eq!(alerting.on_measurement(&measurement(61, 3.0), 5.0), None);
+   }
+
+   #[test]
+   fn test_hysteresis_prevents_flapping() {
+      let mut alerting = Alerting::new(config());
+      assert!(alerting.on_measurement(&measurement(0, 4.9), 5.0).is_some());
+      assert_eq!(alerting.on_measurement(&measurement(1, 5.1), 5.0), None);
+      assert_eq!(alerting.on_measurement(&measurement(2, 4.9), 5.0), None);
+      assert_eq!(alerting.on_measurement(&measurement(3, 5.4), 5.0), None);
+      assert_eq!(
+         alerting.on_measurement(&measurement(4, 5.5), 5.0),
+         Some(Event::Recovered {
+            temperature: 5.5,
+            min: 5.0
+         })
+      );
+      assert_eq!(
+         alerting.on_measurement(&measurement(5, 4.9), 5.0),
+         Some(Event::Below {
+            temperature: 4.9,
+            min: 5.0
+         })
+      );
+   }
+
+   #[test]
+   fn test_no_reminder_while_inside_hysteresis_band() {
+      let mut alerting = Alerting::new(config());
+      assert!(alerting.on_measurement(&measurement(0, 4.9), 5.0).is_some());
+      assert_eq!(alerting.on_measurement(&measurement(120, 5.2), 5.0), None);
+   }
+
+   #[test]
+   fn test_errors_are_ignored() {
+      let mut alerting = Alerting::new(config());
+      let mut m = measurement(0, 4.0);
+      m.temperature = None;
+      assert_eq!(alerting.on_measurement(&m, 5.0), None);
+   }
+}
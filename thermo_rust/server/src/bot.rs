@@ -0,0 +1,588 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Config
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct BotArgs {
+   /// Answer commands (/now, /plot, /status) sent to the bot. Nothing else may poll updates of the same bot.
+   #[arg(long)]
+   tg_commands: bool,
+
+   /// Chats allowed to send commands besides --tg-chat-id, e.g.: --tg-allowed-chat-ids 123,-456
+   #[arg(long, value_delimiter = ',')]
+   tg_allowed_chat_ids: Vec<i64>,
+
+   /// Reply to commands from chats, that are not allowed, with their id (otherwise they are only logged)
+   #[arg(long)]
+   tg_reply_not_allowed: bool,
+}
+
+impl BotArgs {
+   /// None if commands are disabled
+   pub fn config(
+      &self,
+      report_chat_id: &str,
+      stale_after: chrono::Duration,
+      tz: chrono_tz::Tz,
+   ) -> Option<Config> {
+      if !self.tg_commands {
+         return None;
+      }
+      let mut allowed_chat_ids: std::collections::HashSet<i64> =
+         self.tg_allowed_chat_ids.iter().copied().collect();
+      // The report chat may also be a @channel_name, which never sends commands:
+      allowed_chat_ids.extend(report_chat_id.parse::<i64>().ok());
+      Some(Config {
+         allowed_chat_ids,
+         reply_not_allowed: self.tg_reply_not_allowed,
+         stale_after,
+         tz,
+         poll_timeout: std::time::Duration::from_secs(50),
+      })
+   }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+   pub allowed_chat_ids: std::collections::HashSet<i64>,
+   /// Whether chats, that are not allowed, get a reply with their id
+   pub reply_not_allowed: bool,
+   /// Default silence window for sensors that do not have their own (see watchdog)
+   pub stale_after: chrono::Duration,
+   pub tz: chrono_tz::Tz,
+   /// How long getUpdates waits for new updates
+   pub poll_timeout: std::time::Duration,
+}
+
+
+//
+// ===========================================================================================================
+// Commands
+
+/// The longest window /plot accepts
+const MAX_PLOT_WINDOW: chrono::Duration = chrono::Duration::days(31);
+
+const HELP: &str = "/now - the latest reading of each sensor\n\
+                    /plot [6h|7d|30m] - chart of the last 24h or of the given window\n\
+                    /status - whether sensors are alive";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+   Now,
+   Plot(chrono::Duration),
+   Status,
+   Help,
+}
+
+/// Parses e.g. "/plot 6h" or "/plot@thermo_bot 7d" (as sent in group chats)
+pub fn parse_command(text: &str) -> Result<Command> {
+   let mut words = text.split_whitespace();
+   let name = words.next().unwrap_or_default();
+   let name = name.split_once('@').map_or(name, |(name, _)| name);
+   let command = match name {
+      "/now" => Command::Now,
+      "/status" => Command::Status,
+      "/help" | "/start" => Command::Help,
+      "/plot" => match words.next() {
+         None => Command::Plot(chrono::Duration::hours(24)),
+         Some(window) => Command::Plot(parse_window(window)?),
+      },
+      _ => return Err(anyhow!("Unknown command: {name}")),
+   };
+   if let Some(extra) = words.next() {
+      return Err(anyhow!("Unexpected argument of {name}: {extra}"));
+   }
+   Ok(command)
+}
+
+fn parse_window(window: &str) -> Result<chrono::Duration> {
+   let invalid = || anyhow!("Invalid window: {window}, expected e.g. 30m, 6h or 7d");
+   let unit_at = window.len().checked_sub(1).filter(|i| window.is_char_boundary(*i)).ok_or_else(invalid)?;
+   let n: i64 = window[..unit_at].parse().map_err(|_| invalid())?;
+   let window = match &window[unit_at..] {
+      "m" => chrono::Duration::minutes(n),
+      "h" => chrono::Duration::hours(n),
+      "d" => chrono::Duration::days(n),
+      _ => return Err(invalid()),
+   };
+   if window <= chrono::Duration::zero() || window > MAX_PLOT_WINDOW {
+      return Err(anyhow!("The window must be positive and at most {} days", MAX_PLOT_WINDOW.num_days()));
+   }
+   Ok(window)
+}
+
+
+//
+// ===========================================================================================================
+// Telegram updates
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct Response<T> {
+   ok: bool,
+   result: Option<T>,
+   description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct Update {
+   update_id: i64,
+   message: Option<Message>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct Message {
+   chat: Chat,
+   text: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct Chat {
+   id: i64,
+}
+
+/// Long polls updates after offset
+async fn get_updates(
+   telegram: &crate::message::Telegram,
+   offset: i64,
+   timeout: std::time::Duration,
+) -> Result<Vec<Update>> {
+   let data = serde_json::json!({
+      "offset": offset,
+      "timeout": timeout.as_secs(),
+      "allowed_updates": ["message"],
+   });
+   let body = reqwest::Client::new()
+      .post(telegram.url("getUpdates"))
+      .json(&data)
+      .timeout(timeout + std::time::Duration::from_secs(10))
+      .send()
+      .await
+      .with_context(|| anyhow!("Failed to send getUpdates"))?
+      .text()
+      .await
+      .with_context(|| anyhow!("Failed to obtain body of getUpdates"))?;
+   let resp: Response<Vec<Update>> =
+      serde_json::from_str(&body).with_context(|| anyhow!("Failed to parse the body: {body}"))?;
+   match resp {
+      Response {
+         ok: true,
+         result: Some(updates),
+         ..
+      } => Ok(updates),
+      Response { description, .. } => Err(anyhow!("getUpdates failed: {description:?}")),
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Bot
+
+#[derive(Clone)]
+pub struct Bot {
+   telegram: crate::message::Telegram,
+   sensor_db: crate::sensor::Sqlite,
+   measurements_db: crate::db::measurement::Sqlite,
+   retention_db: crate::retention::Sqlite,
+   config: Config,
+}
+
+impl Bot {
+   pub fn new(
+      telegram: crate::message::Telegram,
+      sensor_db: &crate::sensor::Sqlite,
+      measurements_db: &crate::db::measurement::Sqlite,
+      retention_db: &crate::retention::Sqlite,
+      config: Config,
+   ) -> Self {
+      Self {
+         telegram,
+         sensor_db: sensor_db.clone(),
+         measurements_db: measurements_db.clone(),
+         retention_db: retention_db.clone(),
+         config,
+      }
+   }
+
+   /// Polls and answers commands until the process stops. Commands sent while the bot was not running are
+   /// skipped: they may be hours old and Telegram keeps them for a day.
+   pub fn start(self) {
+      tokio::task::spawn(async move {
+         log::info!("Answering commands from chats: {:?}", self.config.allowed_chat_ids);
+         let mut offset = loop {
+            match self.skip_backlog().await {
+               Ok(offset) => break offset,
+               Err(why) => {
+                  log::warn!("Failed to skip Telegram updates sent before start: {why:?}");
+                  tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+               }
+            }
+         };
+         loop {
+            if let Err(why) = self.poll_once(&mut offset).await {
+               log::warn!("Failed to poll Telegram updates: {why:?}");
+               tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+         }
+      });
+   }
+
+   /// Confirms all pending updates without answering them, returns the offset past them
+   async fn skip_backlog(&self) -> Result<i64> {
+      // A negative offset returns only the last update and confirms all the earlier ones:
+      let updates = get_updates(&self.telegram, -1, std::time::Duration::ZERO).await?;
+      Ok(updates.iter().map(|update| update.update_id + 1).max().unwrap_or(0))
+   }
+
+   /// Answers commands of the next updates and moves offset past them
+   async fn poll_once(&self, offset: &mut i64) -> Result<()> {
+      let updates = get_updates(&self.telegram, *offset, self.config.poll_timeout).await?;
+      for update in updates {
+         // Confirm even updates, that fail to be answered, so that they are not answered again and again:
+         *offset = (*offset).max(update.update_id + 1);
+         let Some(Message {
+            chat,
+            text: Some(text),
+         }) = update.message
+         else {
+            continue;
+         };
+         if let Err(why) = self.answer(chat.id, &text).await {
+            log::warn!("Failed to answer {text:?} in chat {}: {why:?}", chat.id);
+         }
+      }
+      Ok(())
+   }
+
+   async fn answer(&self, chat_id: i64, text: &str) -> Result<()> {
+      let telegram = self.telegram.with_chat_id(chat_id);
+      if !self.config.allowed_chat_ids.contains(&chat_id) {
+         log::warn!("Ignoring {text:?} from chat {chat_id}, which is not allowed");
+         if !self.config.reply_not_allowed {
+            return Ok(());
+         }
+         let text = format!("This chat is not allowed to send commands, its id: {chat_id}");
+         return telegram.send_text(text, false).await;
+      }
+      log::info!("Answering {text:?} in chat {chat_id}");
+      let now = chrono::Utc::now();
+      match parse_command(text) {
+         Err(why) => telegram.send_text(format!("{why}\n{HELP}"), false).await,
+         Ok(Command::Help) => telegram.send_text(HELP.to_string(), false).await,
+         Ok(Command::Now) => telegram.send_long_text(&self.now(now).await?).await,
+         Ok(Command::Status) => telegram.send_long_text(&self.status(now).await?).await,
+         Ok(Command::Plot(window)) => self.plot(&telegram, window, now).await,
+      }
+   }
+
+   async fn latest(&self) -> Result<Vec<(crate::sensor::Sensor, Option<common::Measurement>)>> {
+      use crate::db::measurement::Db as _;
+      use crate::sensor::Db as _;
+      let sensors = self.sensor_db.get_all().await.with_context(|| anyhow!("Failed to get sensors"))?;
+      let mut latest = Vec::new();
+      for sensor in sensors {
+         let measurement = self
+            .measurements_db
+            .read_latest(&sensor.id)
+            .await
+            .with_context(|| anyhow!("Failed to read the latest measurement of {sensor:?}"))?;
+         latest.push((sensor, measurement));
+      }
+      Ok(latest)
+   }
+
+   async fn now(&self, now: chrono::DateTime<chrono::Utc>) -> Result<String> {
+      let mut lines = Vec::new();
+      for (sensor, measurement) in self.latest().await? {
+         let Some(measurement) = measurement else {
+            lines.push(format!("{}: no readings", sensor.name));
+            continue;
+         };
+         let mut values: Vec<String> = measurement.temperature.iter().map(|t| format!("{t:.1}°C")).collect();
+         values.extend(measurement.quantities.iter().map(|q| format!("{}: {} {}", q.kind, q.value, q.unit)));
+         if !measurement.error.is_empty() {
+            values.push(format!("error: {}", crate::report::error_category(&measurement.error)));
+         }
+         let read_ts = measurement.read_ts.0;
+         lines.push(format!(
+            "{}: {} ({} ago, at {})",
+            sensor.name,
+            values.join(", "),
+            crate::report::format_duration(now - read_ts),
+            read_ts.with_timezone(&self.config.tz).format("%H:%M %d.%m")
+         ));
+      }
+      Ok(lines.join("\n"))
+   }
+
+   async fn status(&self, now: chrono::DateTime<chrono::Utc>) -> Result<String> {
+      let mut lines = Vec::new();
+      for (sensor, measurement) in self.latest().await? {
+         let Some(measurement) = measurement else {
+            lines.push(format!("{}: no readings", sensor.name));
+            continue;
+         };
+         let stale_after =
+            sensor.stale_after_mins.map(chrono::Duration::minutes).unwrap_or(self.config.stale_after);
+         let silent_for = now - measurement.read_ts.0;
+         let state = match silent_for > stale_after {
+            true => "STALE",
+            false => "ok",
+         };
+         let silent_for = crate::report::format_duration(silent_for);
+         lines.push(format!("{}: {state}, the last reading {silent_for} ago", sensor.name));
+      }
+      Ok(lines.join("\n"))
+   }
+
+   async fn plot(
+      &self,
+      telegram: &crate::message::Telegram,
+      window: chrono::Duration,
+      now: chrono::DateTime<chrono::Utc>,
+   ) -> Result<()> {
+      use crate::sensor::Db as _;
+      let (start, end) = (common::MicroSecTs(now - window), common::MicroSecTs(now));
+      let sensors = self.sensor_db.get_all().await.with_context(|| anyhow!("Failed to get sensors"))?;
+      let mut plot_sensors = Vec::new();
+      for (i, sensor) in sensors.iter().enumerate() {
+         let (measurements_db, retention_db) = (&self.measurements_db, &self.retention_db);
+         let measurements =
+            crate::retention::read_measurements(measurements_db, retention_db, start, end, &sensor.id).await?;
+         plot_sensors.extend(crate::cron::plot_curves(i, sensor, &measurements));
+      }
+      let window = crate::report::format_duration(window);
+      // Rendering takes a while, it must not hold up other tasks:
+      let tz = self.config.tz;
+      let plot = tokio::task::spawn_blocking(move || crate::plot::create_plot(&mut plot_sensors, tz))
+         .await
+         .with_context(|| anyhow!("Plotting panicked"))??;
+      if plot.is_empty() {
+         return telegram.send_text(format!("No measurements during the last {window}"), false).await;
+      }
+      telegram.send_with_pic(&format!("The last {window}"), plot).await
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   #[test]
+   fn test_parse_command() {
+      let hours = chrono::Duration::hours;
+      let ok = [
+         ("/now", Command::Now),
+         ("/status@thermo_bot", Command::Status),
+         ("/plot", Command::Plot(hours(24))),
+         (" /plot   6h ", Command::Plot(hours(6))),
+         ("/plot@thermo_bot 7d", Command::Plot(chrono::Duration::days(7))),
+         ("/plot 30m", Command::Plot(chrono::Duration::minutes(30))),
+         ("/start", Command::Help),
+      ];
+      for (text, expected) in ok {
+         assert_eq!(parse_command(text).unwrap(), expected, "{text}");
+      }
+      let errors = [
+         "", "hello", "/plot 6", "/plot h", "/plot 0h", "/plot 32d", "/plot 6y", "/plot 6°", "/now 1",
+      ];
+      for text in errors {
+         assert!(parse_command(text).is_err(), "{text}");
+      }
+   }
+
+   // --------------------------------------------------------------------------------------------------------
+   // Against a fake Telegram Bot API
+
+   /// Path and body of a request received by the fake
+   type Requests = std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+   /// Answers the first getUpdates with updates, the next ones with no updates (after a delay, like long
+   /// polling), and everything else with ok
+   async fn start_fake_api(updates: serde_json::Value) -> Result<(String, Requests)> {
+      use tokio::io::{AsyncReadExt, AsyncWriteExt};
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+      let url = format!("http://{}", listener.local_addr()?);
+      let requests: Requests = Default::default();
+      let received = requests.clone();
+      tokio::spawn(async move {
+         let mut updates = Some(updates);
+         loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Reads until the whole body (of Content-Length) has been received:
+            let (head, body) = loop {
+               let n = stream.read(&mut buf).await.unwrap();
+               request.extend_from_slice(&buf[..n]);
+               let text = String::from_utf8_lossy(&request).to_string();
+               let Some((head, body)) = text.split_once("\r\n\r\n") else { continue };
+               let content_length = head
+                  .to_lowercase()
+                  .lines()
+                  .find_map(|line| line.strip_prefix("content-length:")?.trim().parse::<usize>().ok())
+                  .unwrap_or_default();
+               if body.len() >= content_length || n == 0 {
+                  break (head.to_string(), body.to_string());
+               }
+            };
+            let path = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+            let result = match path.ends_with("/getUpdates") {
+               true => match updates.take() {
+                  Some(updates) => updates,
+                  None => {
+                     tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                     serde_json::json!([])
+                  }
+               },
+               false => serde_json::json!({}),
+            };
+            received.lock().unwrap().push((path, body));
+            let body = serde_json::json!({"ok": true, "result": result}).to_string();
+            let resp = format!(
+               "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n{body}",
+               body.len()
+            );
+            stream.write_all(resp.as_bytes()).await.unwrap();
+         }
+      });
+      Ok((url, requests))
+   }
+
+   fn update(update_id: i64, chat_id: i64, text: &str) -> serde_json::Value {
+      serde_json::json!({"update_id": update_id, "message": {"chat": {"id": chat_id}, "text": text}})
+   }
+
+   async fn create_bot(api_url: String) -> Result<Bot> {
+      use crate::db::measurement::Db as _;
+      use crate::sensor::Db as _;
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sensor_db = crate::sensor::Sqlite::new(&pool).await?;
+      let measurements_db = crate::db::measurement::Sqlite::new(&pool).await?;
+      let retention_db = crate::retention::Sqlite::new(&pool).await?;
+      let sensor = crate::sensor::Sensor {
+         id: "sen_asdf_1".to_string().try_into()?,
+         name: "Kitchen".to_string(),
+         location: "location".to_string(),
+         min: 5.0,
+         stale_after_mins: None,
+      };
+      sensor_db.add(&sensor).await?;
+      let read_ts = chrono::Utc::now() - chrono::Duration::minutes(3);
+      let id = common::MeasurementId {
+         sensor_id: sensor.id,
+         index: 1,
+      };
+      measurements_db.write(&common::Measurement::from_ok(&id, 21.56, read_ts.into())).await?;
+      let telegram = crate::message::Telegram {
+         chat_id: "1".to_string(),
+         bot_id: "123:token".to_string(),
+         api_url,
+         retry: Default::default(),
+         queue: None,
+      };
+      let config = Config {
+         allowed_chat_ids: [1].into(),
+         reply_not_allowed: false,
+         stale_after: chrono::Duration::minutes(30),
+         tz: chrono_tz::UTC,
+         poll_timeout: std::time::Duration::from_secs(1),
+      };
+      Ok(Bot::new(telegram, &sensor_db, &measurements_db, &retention_db, config))
+   }
+
+   #[tokio::test]
+   async fn test_skip_backlog_confirms_pending_updates() -> Result<()> {
+      let (url, requests) = start_fake_api(serde_json::json!([update(12, 1, "/now")])).await?;
+      let bot = create_bot(url).await?;
+      assert_eq!(bot.skip_backlog().await?, 13);
+
+      let requests = requests.lock().unwrap().clone();
+      assert_eq!(requests.len(), 1, "{requests:?}");
+      let body: serde_json::Value = serde_json::from_str(&requests[0].1)?;
+      assert_eq!(body["offset"], -1);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_answers_allowed_chats_only() -> Result<()> {
+      let updates = serde_json::json!([
+         update(10, 1, "/now"),
+         update(11, 1, "/status"),
+         update(12, 2, "/now"),
+         update(13, 1, "/plot 1h"),
+         update(14, 1, "/plot 1y"),
+      ]);
+      let (url, requests) = start_fake_api(updates).await?;
+      let mut bot = create_bot(url).await?;
+      bot.config.reply_not_allowed = true;
+
+      let mut offset = 0;
+      bot.poll_once(&mut offset).await?;
+      assert_eq!(offset, 15);
+
+      let requests = requests.lock().unwrap().clone();
+      let paths: Vec<&str> = requests.iter().map(|(path, _)| path.as_str()).collect();
+      let expected = [
+         "/bot123:token/getUpdates",
+         "/bot123:token/sendMessage",
+         "/bot123:token/sendMessage",
+         "/bot123:token/sendMessage",
+         "/bot123:token/sendPhoto",
+         "/bot123:token/sendMessage",
+      ];
+      assert_eq!(paths, expected);
+
+      let texts: Vec<(i64, String)> = requests[1..]
+         .iter()
+         .filter_map(|(_, body)| serde_json::from_str::<serde_json::Value>(body).ok())
+         .map(|json| {
+            let chat_id = json["chat_id"].as_str().unwrap().parse().unwrap();
+            (chat_id, json["text"].as_str().unwrap().to_string())
+         })
+         .collect();
+      assert_eq!(texts.len(), 4);
+      assert!(texts[0].1.starts_with("Kitchen: 21.6°C (3m ago, at "), "{texts:?}");
+      assert_eq!(texts[1], (1, "Kitchen: ok, the last reading 3m ago".to_string()));
+      assert_eq!(texts[2], (2, "This chat is not allowed to send commands, its id: 2".to_string()));
+      assert!(texts[3].1.starts_with("Invalid window: 1y"), "{texts:?}");
+      assert!(requests[4].1.contains("The last 1h"));
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_only_logs_commands_of_not_allowed_chats_by_default() -> Result<()> {
+      let (url, requests) = start_fake_api(serde_json::json!([update(12, 2, "/now")])).await?;
+      let bot = create_bot(url).await?;
+
+      let mut offset = 0;
+      bot.poll_once(&mut offset).await?;
+      assert_eq!(offset, 13);
+      let requests = requests.lock().unwrap().clone();
+      let paths: Vec<&str> = requests.iter().map(|(path, _)| path.as_str()).collect();
+      assert_eq!(paths, ["/bot123:token/getUpdates"]);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_poll_once_fails_if_api_fails() -> Result<()> {
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+      let url = format!("http://{}", listener.local_addr()?);
+      drop(listener);
+      let bot = create_bot(url).await?;
+      let mut offset = 7;
+      assert!(bot.poll_once(&mut offset).await.is_err());
+      assert_eq!(offset, 7);
+      Ok(())
+   }
+}
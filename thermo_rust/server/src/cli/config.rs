@@ -53,6 +53,11 @@ pub struct SensorAddOpts {
    /// name
    #[arg(long)]
    min: f64,
+
+   /// Consider the sensor stale if there is no data from it for this number of minutes.
+   /// If not specified, the default of the serve command is used.
+   #[arg(long)]
+   stale_after_mins: Option<i64>,
 }
 
 
@@ -66,6 +71,7 @@ impl SensorAddOpts {
          name: self.name.clone(),
          location: self.location.clone(),
          min: self.min,
+         stale_after_mins: self.stale_after_mins,
       };
       use crate::sensor::Db;
       sqlite.add(&sensor).await.with_context(|| anyhow!("Failed to add {sensor:?}"))?;
@@ -93,6 +99,14 @@ pub struct SensorUpdateOpts {
    /// name
    #[arg(long)]
    name: Option<String>,
+
+   /// Consider the sensor stale if there is no data from it for this number of minutes
+   #[arg(long)]
+   stale_after_mins: Option<i64>,
+
+   /// Go back to the --stale-after-mins default of serve
+   #[arg(long, conflicts_with = "stale_after_mins")]
+   clear_stale_after: bool,
 }
 
 impl SensorUpdateOpts {
@@ -113,6 +127,242 @@ impl SensorUpdateOpts {
             .await
             .with_context(|| anyhow!("Failed to update name of {id}"))?;
       }
+      if self.stale_after_mins.is_some() || self.clear_stale_after {
+         sqlite
+            .update_stale_after_mins(&id, self.stale_after_mins)
+            .await
+            .with_context(|| anyhow!("Failed to update stale_after_mins of {id}"))?;
+      }
+      Ok(())
+   }
+}
+
+
+// ===========================================================================================================
+
+async fn create_client_sqlite(path: &str) -> Result<crate::client::Sqlite> {
+   let path = std::path::PathBuf::from(path);
+   let pool = crate::db::Location::create_pool(&crate::db::Location::Path(path)).await?;
+   crate::client::Sqlite::new(&pool).await
+}
+
+
+/// Allow a client (identified by subject CN of its certificate) to report measurements of a sensor
+#[derive(clap::Parser, Debug)]
+pub struct ClientBindOpts {
+   #[arg(long)]
+   db_path: String,
+
+   /// Subject CN of the client certificate, e.g. the --subject passed to gen-client
+   #[arg(long)]
+   client_cn: String,
+
+   /// Sensor id, can be specified multiple times
+   #[arg(long, required = true)]
+   sensor_id: Vec<String>,
+}
+
+impl ClientBindOpts {
+   fn bindings(&self) -> Result<Vec<crate::client::Binding>> {
+      self.sensor_id
+         .iter()
+         .map(|id| {
+            Ok(crate::client::Binding {
+               client_cn: self.client_cn.clone(),
+               sensor_id: id.clone().try_into()?,
+            })
+         })
+         .collect()
+   }
+
+   pub async fn run(&self) -> Result<()> {
+      let sqlite = create_client_sqlite(&self.db_path).await?;
+      use crate::client::Db;
+      for binding in self.bindings()? {
+         sqlite.bind(&binding).await.with_context(|| anyhow!("Failed to add {binding:?}"))?;
+      }
+      Ok(())
+   }
+}
+
+
+/// Disallow a client to report measurements of a sensor
+#[derive(clap::Parser, Debug)]
+pub struct ClientUnbindOpts {
+   #[command(flatten)]
+   opts: ClientBindOpts,
+}
+
+impl ClientUnbindOpts {
+   pub async fn run(&self) -> Result<()> {
+      let sqlite = create_client_sqlite(&self.opts.db_path).await?;
+      use crate::client::Db;
+      for binding in self.opts.bindings()? {
+         sqlite.unbind(&binding).await.with_context(|| anyhow!("Failed to remove {binding:?}"))?;
+      }
+      Ok(())
+   }
+}
+
+
+/// Print which clients are allowed to report measurements of which sensors
+#[derive(clap::Parser, Debug)]
+pub struct ClientListOpts {
+   #[arg(long)]
+   db_path: String,
+}
+
+impl ClientListOpts {
+   pub async fn run(&self) -> Result<()> {
+      let sqlite = create_client_sqlite(&self.db_path).await?;
+      use crate::client::Db;
+      for binding in sqlite.get_all().await.with_context(|| anyhow!("Failed to get bindings"))? {
+         println!("{} {}", binding.client_cn, binding.sensor_id);
+      }
+      Ok(())
+   }
+}
+
+
+// ===========================================================================================================
+
+async fn create_subscription_sqlite(path: &str) -> Result<crate::subscription::Sqlite> {
+   let path = std::path::PathBuf::from(path);
+   let pool = crate::db::Location::create_pool(&crate::db::Location::Path(path)).await?;
+   crate::subscription::Sqlite::new(&pool).await
+}
+
+
+/// Subscribe a Telegram chat to events. The chat of --tg-chat-id of the serve command gets everything until it
+/// is subscribed to something.
+#[derive(clap::Parser, Debug)]
+pub struct SubscriptionAddOpts {
+   #[arg(long)]
+   db_path: String,
+
+   /// Telegram chat id, e.g. -4609542105 for a group
+   #[arg(long, allow_negative_numbers = true)]
+   chat_id: i64,
+
+   /// Event kinds: report, threshold, stale, errors, e.g.: --kind threshold,stale
+   #[arg(long, value_delimiter = ',', required = true)]
+   kind: Vec<crate::subscription::Kind>,
+
+   /// Sensor id, can be specified multiple times. All sensors (including ones added later) if not specified.
+   #[arg(long)]
+   sensor_id: Vec<String>,
+}
+
+impl SubscriptionAddOpts {
+   fn subscriptions(&self) -> Result<Vec<crate::subscription::Subscription>> {
+      let sensor_ids: Vec<Option<common::SensorId>> = match self.sensor_id.is_empty() {
+         true => vec![None],
+         false => self.sensor_id.iter().map(|id| Ok(Some(id.clone().try_into()?))).collect::<Result<_>>()?,
+      };
+      Ok(self
+         .kind
+         .iter()
+         .flat_map(|kind| {
+            sensor_ids.iter().map(|sensor_id| crate::subscription::Subscription {
+               chat_id: self.chat_id,
+               kind: *kind,
+               sensor_id: sensor_id.clone(),
+            })
+         })
+         .collect())
+   }
+
+   pub async fn run(&self) -> Result<()> {
+      let subscriptions = self.subscriptions()?;
+      let sensor_db = create_sqlite(&self.db_path).await?;
+      use crate::sensor::Db as _;
+      for sensor_id in subscriptions.iter().filter_map(|subscription| subscription.sensor_id.as_ref()) {
+         let sensor = sensor_db.get_by_id(sensor_id).await;
+         if sensor.with_context(|| anyhow!("Failed to get sensor {sensor_id}"))?.is_none() {
+            return Err(anyhow!("There is no sensor {sensor_id}, add it with sensor-add first"));
+         }
+      }
+      let sqlite = create_subscription_sqlite(&self.db_path).await?;
+      use crate::subscription::Db;
+      for subscription in subscriptions {
+         sqlite.subscribe(&subscription).await.with_context(|| anyhow!("Failed to add {subscription:?}"))?;
+      }
+      Ok(())
+   }
+}
+
+
+/// Unsubscribe a Telegram chat from events, the same arguments as of subscription-add
+#[derive(clap::Parser, Debug)]
+pub struct SubscriptionRemoveOpts {
+   #[command(flatten)]
+   opts: SubscriptionAddOpts,
+}
+
+impl SubscriptionRemoveOpts {
+   pub async fn run(&self) -> Result<()> {
+      let sqlite = create_subscription_sqlite(&self.opts.db_path).await?;
+      use crate::subscription::Db;
+      for subscription in self.opts.subscriptions()? {
+         let removed = sqlite
+            .unsubscribe(&subscription)
+            .await
+            .with_context(|| anyhow!("Failed to remove {subscription:?}"))?;
+         if !removed {
+            log::warn!("There was no subscription: {subscription}");
+         }
+      }
+      Ok(())
+   }
+}
+
+
+/// Print subscriptions: chat id, event kind, sensor id (* for all sensors)
+#[derive(clap::Parser, Debug)]
+pub struct SubscriptionListOpts {
+   #[arg(long)]
+   db_path: String,
+}
+
+impl SubscriptionListOpts {
+   pub async fn run(&self) -> Result<()> {
+      let sqlite = create_subscription_sqlite(&self.db_path).await?;
+      use crate::subscription::Db;
+      for subscription in sqlite.get_all().await.with_context(|| anyhow!("Failed to get subscriptions"))? {
+         println!("{subscription}");
+      }
+      Ok(())
+   }
+}
+
+
+// ===========================================================================================================
+
+/// Roll raw measurements older than the retention period into hourly and daily aggregates and delete them
+/// (and older health reports), i.e. what the serve command does on schedule
+#[derive(clap::Parser, Debug)]
+pub struct RetentionRunOpts {
+   #[arg(long)]
+   db_path: String,
+
+   /// Keep raw measurements for this number of days
+   #[arg(long, default_value_t = crate::retention::DEFAULT_RAW_RETENTION_DAYS)]
+   raw_retention_days: i64,
+}
+
+impl RetentionRunOpts {
+   pub async fn run(&self) -> Result<()> {
+      if self.raw_retention_days <= 0 {
+         return Err(anyhow!("--raw-retention-days must be positive, got {}", self.raw_retention_days));
+      }
+      let path = std::path::PathBuf::from(&self.db_path);
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Path(path)).await?;
+      crate::db::measurement::Sqlite::new(&pool).await?;
+      crate::health::Sqlite::new(&pool).await?;
+      let sqlite = crate::retention::Sqlite::new(&pool).await?;
+      let retention = chrono::Duration::days(self.raw_retention_days);
+      let rolled_up = crate::retention::run_once(&sqlite, retention, chrono::Utc::now()).await?;
+      println!("Rolled up {rolled_up} measurements");
       Ok(())
    }
 }
@@ -126,11 +376,19 @@ pub enum Workflow {
    SensorAdd(SensorAddOpts),
    SensorUpdate(SensorUpdateOpts),
    // TODO: list sensors
+   ClientBind(ClientBindOpts),
+   ClientUnbind(ClientUnbindOpts),
+   ClientList(ClientListOpts),
+   SubscriptionAdd(SubscriptionAddOpts),
+   SubscriptionRemove(SubscriptionRemoveOpts),
+   SubscriptionList(SubscriptionListOpts),
+   RetentionRun(RetentionRunOpts),
 }
 
 
 
-/// Config management operations, such as managing sensors (names, thresholds, etc...)
+/// Config management operations, such as managing sensors (names, thresholds, etc...), clients allowed to
+/// report their measurements and chats subscribed to events, and one-off maintenance of the db
 #[derive(clap::Parser, Debug)]
 pub struct Cli {
    #[command(subcommand)]
@@ -144,6 +402,13 @@ impl Cli {
          Workflow::SensorGenId(opts) => opts.run().await,
          Workflow::SensorAdd(opts) => opts.run().await,
          Workflow::SensorUpdate(opts) => opts.run().await,
+         Workflow::ClientBind(opts) => opts.run().await,
+         Workflow::ClientUnbind(opts) => opts.run().await,
+         Workflow::ClientList(opts) => opts.run().await,
+         Workflow::SubscriptionAdd(opts) => opts.run().await,
+         Workflow::SubscriptionRemove(opts) => opts.run().await,
+         Workflow::SubscriptionList(opts) => opts.run().await,
+         Workflow::RetentionRun(opts) => opts.run().await,
       }
    }
 }
@@ -2,6 +2,10 @@ use anyhow::{Context, Result, anyhow};
 
 
 /// The main mode where we start server that listens for incoming measurements
+///
+/// Telegram messages that could not be sent because of an outage or rate limits are queued in memory and
+/// resent later (up to 1000 requests, the oldest ones are dropped beyond it). The queue is not persisted: the
+/// messages still queued are lost when the server restarts.
 #[derive(clap::Parser, Debug)]
 pub struct Cli {
    /// Host and port to listen on server
@@ -16,6 +20,24 @@ pub struct Cli {
 
    #[command(flatten)]
    telegram: crate::message::TelegramArgs,
+
+   #[command(flatten)]
+   notifier: crate::notifier::NotifierArgs,
+
+   #[command(flatten)]
+   alerting: crate::alerting::AlertingArgs,
+
+   #[command(flatten)]
+   watchdog: crate::watchdog::WatchdogArgs,
+
+   #[command(flatten)]
+   schedule: crate::cron::ScheduleArgs,
+
+   #[command(flatten)]
+   retention: crate::retention::RetentionArgs,
+
+   #[command(flatten)]
+   bot: crate::bot::BotArgs,
 }
 
 impl Cli {
@@ -24,10 +46,43 @@ impl Cli {
       let pool = crate::db::Location::Path(self.db_path.clone()).create_pool().await?;
       let measuruments_db = crate::db::measurement::Sqlite::new(&pool).await?;
       let sensor_db = crate::sensor::Sqlite::new(&pool).await?;
+      let client_db = crate::client::Sqlite::new(&pool).await?;
+      let health_db = crate::health::Sqlite::new(&pool).await?;
+      let retention_db = crate::retention::Sqlite::new(&pool).await?;
+      let subscription_db = crate::subscription::Sqlite::new(&pool).await?;
 
-      let (routes, _tx) = crate::grpc::Agg::start(routes, measuruments_db.clone());
-      let sender = crate::message::Telegram::from_args(self.telegram.clone());
-      crate::cron::start(&measuruments_db, &sensor_db, sender)
+      let (routes, tx) = crate::grpc::Agg::start(
+         routes,
+         measuruments_db.clone(),
+         sensor_db.clone(),
+         client_db,
+         health_db.clone(),
+         retention_db.clone(),
+      );
+      let telegram = crate::message::Telegram::from_args(self.telegram.clone()).map(|telegram| {
+         use crate::message::{QUEUE_CAPACITY, QUEUE_RETRY_PERIOD};
+         telegram.with_queue(crate::message::Queue::start(QUEUE_CAPACITY, QUEUE_RETRY_PERIOD))
+      });
+      let notifiers = self.notifier.notifiers();
+      if telegram.is_none() && notifiers.is_empty() {
+         return Err(anyhow!("Nowhere to send reports to: pass --tg-bot-id with --tg-chat-id or --notify"));
+      }
+      let router = crate::subscription::Router::new(telegram.clone(), notifiers, &subscription_db);
+      crate::alerting::start(tx.subscribe(), &sensor_db, router.clone(), self.alerting.config())
+         .with_context(|| anyhow!("Failed to start alerting"))?;
+      crate::watchdog::start(tx.subscribe(), &sensor_db, router.clone(), self.watchdog.config())
+         .with_context(|| anyhow!("Failed to start watchdog"))?;
+      let schedule = self.schedule.schedule();
+      let stale_after = self.watchdog.config().stale_after;
+      let report_chat_id = telegram.as_ref().map_or("", |telegram| telegram.chat_id.as_str());
+      if let Some(config) = self.bot.config(report_chat_id, stale_after, schedule.tz) {
+         let telegram = telegram.ok_or_else(|| anyhow!("--tg-commands requires --tg-bot-id and --tg-chat-id"))?;
+         crate::bot::Bot::new(telegram, &sensor_db, &measuruments_db, &retention_db, config).start();
+      }
+      crate::retention::start(&retention_db, self.retention.config(schedule.tz))
+         .with_context(|| anyhow!("Failed to start retention"))?;
+      let max_gap_periods = self.schedule.max_gap_periods();
+      crate::cron::start(&measuruments_db, &sensor_db, &health_db, router, schedule, max_gap_periods)
          .with_context(|| anyhow!("Failed to start cron"))?;
 
       let addr: std::net::SocketAddr =
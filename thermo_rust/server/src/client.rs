@@ -0,0 +1,143 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Binding of a client (identified by subject CN of its certificate) to a sensor it may report measurements for
+
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct Binding {
+   pub client_cn: String,
+   pub sensor_id: common::SensorId,
+}
+
+
+//
+// ===========================================================================================================
+// Db
+
+#[derive(Clone)]
+pub struct Sqlite {
+   pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+
+impl Sqlite {
+   pub async fn new(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Sqlite> {
+      crate::db::migrate(pool, "client_sensors", MIGRATIONS)
+         .await
+         .with_context(|| anyhow!("Failed to migrate"))?;
+      Ok(Sqlite { pool: pool.clone() })
+   }
+}
+
+const MIGRATIONS: &[crate::db::Migration] = &[crate::db::Migration {
+   description: "Create client_sensors",
+   steps: &[crate::db::Step::Sql(
+      r#"CREATE TABLE IF NOT EXISTS client_sensors (
+         client_cn TEXT NOT NULL,
+         sensor_id TEXT NOT NULL,
+         PRIMARY KEY (client_cn, sensor_id)
+      ) STRICT;"#,
+   )],
+}];
+
+#[async_trait::async_trait]
+pub trait Db {
+   async fn bind(&self, binding: &Binding) -> Result<()>;
+   async fn unbind(&self, binding: &Binding) -> Result<()>;
+   async fn is_bound(&self, binding: &Binding) -> Result<bool>;
+   async fn get_all(&self) -> Result<Vec<Binding>>;
+}
+
+
+#[async_trait::async_trait]
+impl Db for Sqlite {
+   async fn bind(&self, row: &Binding) -> Result<()> {
+      sqlx::query(
+         r#"INSERT OR IGNORE INTO client_sensors (client_cn, sensor_id)
+            VALUES ($1, $2)
+         "#,
+      )
+      .bind(&row.client_cn)
+      .bind(&row.sensor_id)
+      .execute(&self.pool)
+      .await?;
+      Ok(())
+   }
+
+   async fn unbind(&self, row: &Binding) -> Result<()> {
+      sqlx::query(
+         r#"DELETE FROM client_sensors WHERE client_cn = $1 AND sensor_id = $2
+         "#,
+      )
+      .bind(&row.client_cn)
+      .bind(&row.sensor_id)
+      .execute(&self.pool)
+      .await?;
+      Ok(())
+   }
+
+   async fn is_bound(&self, row: &Binding) -> Result<bool> {
+      let found: Option<(i64,)> = sqlx::query_as(
+         r#"SELECT 1 FROM client_sensors WHERE client_cn = $1 AND sensor_id = $2
+         "#,
+      )
+      .bind(&row.client_cn)
+      .bind(&row.sensor_id)
+      .fetch_optional(&self.pool)
+      .await?;
+      Ok(found.is_some())
+   }
+
+   async fn get_all(&self) -> Result<Vec<Binding>> {
+      let bindings = sqlx::query_as(
+         r#"
+         SELECT client_cn, sensor_id
+         FROM client_sensors
+         ORDER BY client_cn, sensor_id
+         "#,
+      )
+      .fetch_all(&self.pool)
+      .await?;
+      Ok(bindings)
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn binding(client_cn: &str, sensor_id: &common::SensorId) -> Binding {
+      Binding {
+         client_cn: client_cn.to_string(),
+         sensor_id: sensor_id.clone(),
+      }
+   }
+
+   #[tokio::test]
+   async fn test_bind_unbind() -> Result<()> {
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      let (sen1, sen2) = (common::SensorId::new(), common::SensorId::new());
+
+      sqlite.bind(&binding("CLI-1", &sen1)).await?;
+      sqlite.bind(&binding("CLI-1", &sen1)).await?;
+      sqlite.bind(&binding("CLI-2", &sen2)).await?;
+      assert!(sqlite.is_bound(&binding("CLI-1", &sen1)).await?);
+      assert!(!sqlite.is_bound(&binding("CLI-1", &sen2)).await?);
+      assert!(!sqlite.is_bound(&binding("CLI-2", &sen1)).await?);
+
+      sqlite.unbind(&binding("CLI-1", &sen1)).await?;
+      assert!(!sqlite.is_bound(&binding("CLI-1", &sen1)).await?);
+      assert_eq!(sqlite.get_all().await?, vec![binding("CLI-2", &sen2)]);
+      Ok(())
+   }
+}
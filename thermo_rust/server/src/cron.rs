@@ -1,50 +1,106 @@
 use anyhow::{Context, Result, anyhow};
 
-fn generate_candidates(date: chrono::NaiveDate) -> Vec<chrono::DateTime<chrono::Utc>> {
-   use chrono::TimeZone;
-   let tz = chrono_tz::Europe::Moscow;
-   let times = [
-      chrono::NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
-      chrono::NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
-      chrono::NaiveTime::from_hms_opt(21, 0, 0).unwrap(),
-   ];
-
-   times
-      .iter()
-      .filter_map(|&nt| tz.from_local_datetime(&date.and_time(nt)).latest())
-      .map(|dt| dt.with_timezone(&chrono::Utc))
-      .collect()
+
+//
+// ===========================================================================================================
+// Schedule
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct ScheduleArgs {
+   /// Local times of day to send reports at, e.g.: --report-at 09:00,18:00,21:00
+   #[arg(long, value_delimiter = ',', num_args = 1.., default_values = ["09:00", "18:00", "21:00"])]
+   report_at: Vec<chrono::NaiveTime>,
+
+   /// IANA timezone of --report-at times (also used in plots), e.g.: Europe/Berlin
+   #[arg(long, default_value = "Europe/Moscow")]
+   report_tz: chrono_tz::Tz,
+
+   /// In reports a reading covers the time until the next one but at most this many poll periods of its sensor
+   /// (estimated from the intervals between its measurements), longer gaps count as missing data
+   #[arg(long, default_value_t = crate::report::DEFAULT_MAX_GAP_PERIODS,
+      value_parser = clap::value_parser!(u32).range(1..))]
+   report_max_gap_periods: u32,
+}
+
+impl ScheduleArgs {
+   pub fn schedule(&self) -> Schedule { Schedule::new(self.report_at.clone(), self.report_tz) }
+
+   pub fn max_gap_periods(&self) -> u32 { self.report_max_gap_periods }
+}
+
+
+/// A daily schedule: the same list of local times of day every day in the given timezone
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+   times: Vec<chrono::NaiveTime>,
+   pub tz: chrono_tz::Tz,
 }
 
-fn calculate_next_run_time(now: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
-   let candidates = generate_candidates(now.date_naive());
-   if let Some(next) = candidates.iter().find(|c| **c > now) {
-      return *next;
+impl Schedule {
+   pub fn new(mut times: Vec<chrono::NaiveTime>, tz: chrono_tz::Tz) -> Self {
+      times.sort();
+      times.dedup();
+      Self { times, tz }
+   }
+
+   /// Converts local time to UTC honouring DST transitions:
+   /// - if the local time happens twice (clocks go back), the first occurrence is used,
+   /// - if the local time does not exist (clocks go forward), the first existing time after the gap is used.
+   fn to_utc(&self, local: chrono::NaiveDateTime) -> Option<chrono::DateTime<chrono::Utc>> {
+      use chrono::TimeZone;
+      let resolved = match self.tz.from_local_datetime(&local) {
+         chrono::LocalResult::Single(dt) => Some(dt),
+         chrono::LocalResult::Ambiguous(earliest, _) => Some(earliest),
+         chrono::LocalResult::None => (1..=24 * 60)
+            .map(|minutes| local + chrono::Duration::minutes(minutes))
+            .find_map(|local| self.tz.from_local_datetime(&local).earliest()),
+      };
+      resolved.map(|dt| dt.with_timezone(&chrono::Utc))
+   }
+
+   fn candidates(&self, date: chrono::NaiveDate) -> Vec<chrono::DateTime<chrono::Utc>> {
+      self.times.iter().filter_map(|&time| self.to_utc(date.and_time(time))).collect()
+   }
+
+   pub fn next_run_time(&self, now: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+      let today = now.with_timezone(&self.tz).date_naive();
+      (-1..=2)
+         .flat_map(|days| self.candidates(today + chrono::Duration::days(days)))
+         .filter(|candidate| *candidate > now)
+         .min()
+         .unwrap_or(now + chrono::Duration::hours(1))
    }
-   let tomorrow = (now + chrono::Duration::days(1)).date_naive();
-   let candidates = generate_candidates(tomorrow);
-   candidates.first().copied().unwrap_or(now + chrono::Duration::hours(1))
 }
 
+
+//
+// ===========================================================================================================
+// Cron
+
 pub fn start(
    measurements_db: &crate::db::measurement::Sqlite,
    sensor_db: &crate::sensor::Sqlite,
-   sender: crate::message::Telegram,
+   health_db: &crate::health::Sqlite,
+   router: crate::subscription::Router,
+   schedule: Schedule,
+   max_gap_periods: u32,
 ) -> Result<()> {
    tokio::task::spawn({
       let measurements_db = measurements_db.clone();
       let sensor_db = sensor_db.clone();
+      let health_db = health_db.clone();
       async move {
          loop {
             let now = chrono::Utc::now();
-            let next_run = calculate_next_run_time(now);
+            let next_run = schedule.next_run_time(now);
             let to_sleep = (next_run - now).to_std().unwrap_or(std::time::Duration::ZERO);
             log::info!(
                "now: {now} => sleeping {} until {next_run}",
                human_duration::human_duration(&to_sleep)
             );
             tokio::time::sleep(to_sleep).await;
-            let res = on_cron(&sender, &sensor_db, &measurements_db).await;
+            let res =
+               on_cron(&router, &sensor_db, &measurements_db, &health_db, schedule.tz, max_gap_periods).await;
             if let Err(why) = res {
                log::warn!("on_cron() failed: {why:?}");
             }
@@ -55,52 +111,307 @@ pub fn start(
 }
 
 
+/// Curves of all quantities of the i-th sensor (its colour depends on i)
+pub fn plot_curves(
+   i: usize,
+   sensor_meta: &crate::sensor::Sensor,
+   measurements: &[common::Measurement],
+) -> Vec<crate::plot::Sensor> {
+   let colours = [(255, 0, 0), (0, 0, 255), (0, 255, 0)];
+   common::QuantityKind::ALL
+      .into_iter()
+      .map(|kind| crate::plot::Sensor {
+         name: sensor_meta.name.clone(),
+         min: sensor_meta.min,
+         curve: measurements
+            .iter()
+            .filter_map(|measurement| measurement.value(kind).map(|value| (measurement.read_ts.0, value)))
+            .collect(),
+         colour: colours[i % colours.len()],
+         kind,
+      })
+      .filter(|sensor| !sensor.curve.is_empty())
+      .collect()
+}
+
+
+/// What the report says about a sensor, each audience gets the sensors it is subscribed to
+struct SensorReport {
+   /// Index of the sensor among all sensors, so that it has the same colour in plots of all audiences
+   index: usize,
+   sensor: crate::sensor::Sensor,
+   measurements: Vec<common::Measurement>,
+   stats: crate::report::SensorStats,
+   health: Option<common::Health>,
+}
+
+/// The report for the audience: text and curves to plot, or None if there is nothing it is subscribed to.
+/// Curves are None if the audience wants only errors (no plot is sent then).
+fn compose(
+   filter: &crate::subscription::Filter,
+   reports: &[SensorReport],
+   tz: chrono_tz::Tz,
+) -> Option<(String, Option<Vec<crate::plot::Sensor>>)> {
+   use crate::subscription::Kind;
+   let wanted = |kind| reports.iter().filter(move |r| filter.wants(kind, &r.sensor.id));
+
+   let mut lines: Vec<String> = Vec::new();
+   let mut curves: Option<Vec<crate::plot::Sensor>> = None;
+   for report in wanted(Kind::Report) {
+      let mut stats = report.stats.clone();
+      if !filter.wants(Kind::Errors, &report.sensor.id) {
+         stats.errors.clear();
+      }
+      lines.push(stats.format(tz));
+      curves.get_or_insert_default().extend(plot_curves(report.index, &report.sensor, &report.measurements));
+   }
+   let mut health: Vec<String> = Vec::new();
+   for report in wanted(Kind::Errors) {
+      if !filter.wants(Kind::Report, &report.sensor.id) && !report.stats.errors.is_empty() {
+         lines.push(format!("{}:", report.sensor.name));
+         lines.extend(report.stats.format_errors());
+      }
+      if let Some(summary) = &report.health {
+         health.push(format!("{}: {summary}", report.sensor.name));
+      }
+   }
+   if !health.is_empty() {
+      lines.push(format!("Health:\n{}", health.join("\n")));
+   }
+   match (lines.is_empty(), curves) {
+      (true, None) => None,
+      (_, curves) => Some((lines.join("\n"), curves)),
+   }
+}
+
 async fn on_cron(
-   sender: &crate::message::Telegram,
+   router: &crate::subscription::Router,
    sensor_db: &crate::sensor::Sqlite,
    measurements_db: &crate::db::measurement::Sqlite,
+   health_db: &crate::health::Sqlite,
+   tz: chrono_tz::Tz,
+   max_gap_periods: u32,
 ) -> Result<()> {
    let now = chrono::Utc::now();
    let start = common::MicroSecTs(now - chrono::Duration::hours(24));
    let end = common::MicroSecTs(now);
 
    use crate::db::measurement::Db as _;
+   use crate::health::Db as _;
+   use crate::notifier::Notifier as _;
    use crate::sensor::Db as _;
 
    let sensors_meta = sensor_db.get_all().await.with_context(|| anyhow!("Failed to sensor_db.get_all()"))?;
-   let mut plot_sensors: Vec<crate::plot::Sensor> = Vec::new();
-   let colours = vec![(255, 0, 0), (0, 0, 255), ((0, 255, 0))];
-   let mut errors: Vec<String> = Vec::new();
-   for (i, sensor_meta) in sensors_meta.iter().enumerate() {
+   let mut reports: Vec<SensorReport> = Vec::new();
+   for (index, sensor_meta) in sensors_meta.into_iter().enumerate() {
       let measurements = measurements_db.read(start, end, &sensor_meta.id).await.with_context(|| {
          anyhow!("Failed to read measurements from {start:?} until {end:?} of sensor: {sensor_meta:?}")
       })?;
-      let curve: Vec<_> = measurements
-         .clone()
-         .into_iter()
-         .filter_map(|measurement| measurement.temperature.map(|temp| (measurement.read_ts.0, temp)))
-         .collect();
-
-      let curve_errors: Vec<&str> = measurements
-         .iter()
-         .filter(|error| !error.error.is_empty())
-         .take(10)
-         .map(|s| s.error.as_str())
-         .collect();
-      let curve_errors = curve_errors.join(", ");
-      let sensor_name = sensor_meta.clone().name;
-      plot_sensors.push(crate::plot::Sensor {
-         name: sensor_name.clone(),
-         min: sensor_meta.min,
-         curve,
-         colour: colours[i % colours.len()],
+      let health_reports = health_db.read(start, end, &sensor_meta.id).await.with_context(|| {
+         anyhow!("Failed to read health reports from {start:?} until {end:?} of sensor: {sensor_meta:?}")
+      })?;
+      reports.push(SensorReport {
+         index,
+         stats: crate::report::SensorStats::compute(
+            &sensor_meta,
+            &measurements,
+            start.0,
+            end.0,
+            max_gap_periods,
+         ),
+         health: crate::health::summarize(&health_reports),
+         sensor: sensor_meta,
+         measurements,
       });
-      if !curve_errors.is_empty() {
-         errors.push(format!("{sensor_name}: {curve_errors}"));
+   }
+
+   let mut errors = Vec::new();
+   for (filter, notifiers) in router.audiences().await? {
+      let Some((text, curves)) = compose(&filter, &reports, tz) else { continue };
+      let res = match curves {
+         None => notifiers.send_text(&text).await,
+         Some(mut curves) => match crate::plot::create_plot(&mut curves, tz) {
+            Ok(plot) if plot.is_empty() => {
+               // Nothing to draw (e.g. sensors were silent for the whole window) => do not send a broken photo:
+               let text = format!("No measurements during the last 24 hours\n{text}");
+               notifiers.send_text(&text).await
+            }
+            Ok(plot) => notifiers.send_with_pic(&text, plot).await,
+            Err(why) => {
+               // The report is still worth sending without the picture:
+               log::error!("Failed to create plot for {}: {why:?}", notifiers.name());
+               notifiers.send_text(&text).await
+            }
+         },
+      };
+      if let Err(why) = res {
+         errors.push(format!("{why:?}"));
       }
    }
-   let errors = errors.join("\n");
-   let plot = crate::plot::create_plot(&mut plot_sensors)?;
-   sender.send_with_pic(&errors, plot).await?;
-   Ok(())
+   match errors.is_empty() {
+      true => Ok(()),
+      false => Err(anyhow!("Failed to send the report:\n{}", errors.join("\n"))),
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn utc(year: i32, month: u32, day: u32, hour: u32, min: u32) -> chrono::DateTime<chrono::Utc> {
+      use chrono::TimeZone;
+      chrono::Utc.with_ymd_and_hms(year, month, day, hour, min, 0).unwrap()
+   }
+
+   fn hm(hour: u32, min: u32) -> chrono::NaiveTime { chrono::NaiveTime::from_hms_opt(hour, min, 0).unwrap() }
+
+   fn moscow() -> Schedule { Schedule::new(vec![hm(21, 0), hm(9, 0), hm(18, 0)], chrono_tz::Europe::Moscow) }
+
+   #[test]
+   fn test_next_run_time_later_today() {
+      assert_eq!(moscow().next_run_time(utc(2024, 1, 10, 7, 0)), utc(2024, 1, 10, 15, 0));
+   }
+
+   #[test]
+   fn test_next_run_time_exactly_at_candidate_returns_next_one() {
+      assert_eq!(moscow().next_run_time(utc(2024, 1, 10, 6, 0)), utc(2024, 1, 10, 15, 0));
+   }
+
+   #[test]
+   fn test_next_run_time_tomorrow() {
+      assert_eq!(moscow().next_run_time(utc(2024, 1, 10, 18, 30)), utc(2024, 1, 11, 6, 0));
+   }
+
+   #[test]
+   fn test_next_run_time_local_date_differs_from_utc_date() {
+      // 2024-01-10 23:30 UTC is already 2024-01-11 08:30 in Tokyo:
+      let schedule = Schedule::new(vec![hm(9, 0)], chrono_tz::Asia::Tokyo);
+      assert_eq!(schedule.next_run_time(utc(2024, 1, 10, 23, 30)), utc(2024, 1, 11, 0, 0));
+   }
+
+   #[test]
+   fn test_next_run_time_across_spring_forward() {
+      // Berlin: 2024-03-31 02:00 CET => 03:00 CEST
+      let schedule = Schedule::new(vec![hm(9, 0)], chrono_tz::Europe::Berlin);
+      assert_eq!(schedule.next_run_time(utc(2024, 3, 30, 8, 30)), utc(2024, 3, 31, 7, 0));
+      assert_eq!(schedule.next_run_time(utc(2024, 3, 31, 7, 0)), utc(2024, 4, 1, 7, 0));
+   }
+
+   #[test]
+   fn test_next_run_time_inside_spring_forward_gap() {
+      // 02:30 does not exist in Berlin on 2024-03-31 => run at 03:00 CEST
+      let schedule = Schedule::new(vec![hm(2, 30)], chrono_tz::Europe::Berlin);
+      assert_eq!(schedule.next_run_time(utc(2024, 3, 30, 12, 0)), utc(2024, 3, 31, 1, 0));
+   }
+
+   #[test]
+   fn test_next_run_time_across_fall_back() {
+      // Berlin: 2024-10-27 03:00 CEST => 02:00 CET
+      let schedule = Schedule::new(vec![hm(9, 0)], chrono_tz::Europe::Berlin);
+      assert_eq!(schedule.next_run_time(utc(2024, 10, 26, 8, 0)), utc(2024, 10, 27, 8, 0));
+   }
+
+   #[test]
+   fn test_next_run_time_inside_fall_back_overlap_runs_once() {
+      // 02:30 happens twice in Berlin on 2024-10-27: at 00:30 UTC and at 01:30 UTC
+      let schedule = Schedule::new(vec![hm(2, 30)], chrono_tz::Europe::Berlin);
+      assert_eq!(schedule.next_run_time(utc(2024, 10, 26, 12, 0)), utc(2024, 10, 27, 0, 30));
+      assert_eq!(schedule.next_run_time(utc(2024, 10, 27, 0, 30)), utc(2024, 10, 28, 1, 30));
+   }
+
+   fn sensor_report(index: usize, name: &str, temperature: f64, error: &str) -> SensorReport {
+      let sensor = crate::sensor::Sensor {
+         id: common::SensorId::new(),
+         name: name.to_string(),
+         location: "location".to_string(),
+         min: 10.0,
+         stale_after_mins: None,
+      };
+      let id = common::MeasurementId {
+         sensor_id: sensor.id.clone(),
+         index: 1,
+      };
+      let (start, end) = (utc(2024, 1, 10, 0, 0), utc(2024, 1, 10, 0, 10));
+      let measurements = vec![
+         common::Measurement::from_ok(&id, temperature, start.into()),
+         common::Measurement::from_err(&id, error, (start + chrono::Duration::minutes(1)).into()),
+      ];
+      SensorReport {
+         index,
+         stats: crate::report::SensorStats::compute(
+            &sensor,
+            &measurements,
+            start,
+            end,
+            crate::report::DEFAULT_MAX_GAP_PERIODS,
+         ),
+         health: None,
+         sensor,
+         measurements,
+      }
+   }
+
+   #[test]
+   fn test_compose_by_subscriptions() {
+      use crate::subscription::{Filter, Kind, Subscription};
+      let reports = [sensor_report(0, "Kitchen", 21.0, "Timed out"), sensor_report(1, "Attic", 5.0, "CRC")];
+      let subscription = |kind, report: Option<&SensorReport>| Subscription {
+         chat_id: 1,
+         kind,
+         sensor_id: report.map(|r| r.sensor.id.clone()),
+      };
+      let compose_text = |filter: &Filter| {
+         compose(filter, &reports, chrono_tz::UTC).map(|(text, curves)| (text, curves.map(|c| c.len())))
+      };
+
+      let (text, curves) = compose_text(&Filter::Everything).unwrap();
+      assert_eq!(curves, Some(2));
+      assert!(text.contains("Kitchen: 21.0..21.0°C") && text.contains("1× Timed out"), "{text}");
+      assert!(text.contains("Attic: 5.0..5.0°C") && text.contains("1× CRC"), "{text}");
+
+      // Report of one sensor, errors of all:
+      let filter = Filter::Subscriptions(vec![
+         subscription(Kind::Report, Some(&reports[0])),
+         subscription(Kind::Errors, None),
+      ]);
+      let (text, curves) = compose_text(&filter).unwrap();
+      assert_eq!(curves, Some(1));
+      let expected = [
+         "Kitchen: 21.0..21.0°C, avg 21.0°C, last 21.0°C at 00:00",
+         "  below 10°C: 0m, coverage: 50.0%",
+         "  1 errors:",
+         "  1× Timed out",
+         "Attic:",
+         "  1 errors:",
+         "  1× CRC",
+      ];
+      assert_eq!(text, expected.join("\n"));
+
+      // Errors only => no plot:
+      let filter = Filter::Subscriptions(vec![subscription(Kind::Errors, Some(&reports[1]))]);
+      assert_eq!(compose_text(&filter), Some(("Attic:\n  1 errors:\n  1× CRC".to_string(), None)));
+
+      // Report without errors:
+      let filter = Filter::Subscriptions(vec![subscription(Kind::Report, Some(&reports[1]))]);
+      let (text, _) = compose_text(&filter).unwrap();
+      let expected = "Attic: 5.0..5.0°C, avg 5.0°C, last 5.0°C at 00:00\n  below 10°C: 5m, coverage: 50.0%";
+      assert_eq!(text, expected);
+
+      let filter = Filter::Subscriptions(vec![subscription(Kind::Threshold, None)]);
+      assert_eq!(compose_text(&filter), None);
+   }
+
+   #[test]
+   fn test_next_run_time_new_york() {
+      // New York: 2024-03-10 02:00 EST => 03:00 EDT
+      let schedule = Schedule::new(vec![hm(18, 0)], chrono_tz::America::New_York);
+      assert_eq!(schedule.next_run_time(utc(2024, 3, 9, 12, 0)), utc(2024, 3, 9, 23, 0));
+      assert_eq!(schedule.next_run_time(utc(2024, 3, 9, 23, 0)), utc(2024, 3, 10, 22, 0));
+   }
 }
@@ -3,25 +3,6 @@ pub mod measurement;
 use anyhow::{anyhow, Context, Result};
 
 
-fn ignore_duplicate_column_name(err_sqlx: sqlx::Error) -> Result<(), sqlx::Error> {
-   // let Some(err) = err else { return false };
-   let sqlx::Error::Database(err) = &err_sqlx else {
-      return Err(err_sqlx);
-   };
-   let Some(err) = err.try_downcast_ref::<sqlx::sqlite::SqliteError>() else {
-      return Err(err_sqlx);
-   };
-   use sqlx::error::DatabaseError;
-   let Some(code) = err.code() else {
-      return Err(err_sqlx);
-   };
-   if code == "1" {
-      Ok(())
-   } else {
-      Err(err_sqlx)
-   }
-}
-
 pub enum Location {
    Memory,
    Path(std::path::PathBuf),
@@ -58,14 +39,186 @@ impl Location {
    }
 }
 
-pub async fn init_ddl(pool: &sqlx::Pool<sqlx::Sqlite>, ddls: &[&str]) -> Result<()> {
-   for sql in ddls {
-      println!("executing ddl {sql}");
-      sqlx::query(sql)
-         .execute(pool)
-         .await
-         .map(|_| ())
-         .or_else(crate::db::ignore_duplicate_column_name)?;
+//
+// ===========================================================================================================
+// Migrations
+
+/// Which migrations of each module have been applied
+const SCHEMA_VERSION_DDL: &str = r#"CREATE TABLE IF NOT EXISTS schema_version (
+   module  TEXT    PRIMARY KEY,
+   version INTEGER NOT NULL
+) STRICT;"#;
+
+#[derive(Debug)]
+pub enum Step {
+   Sql(&'static str),
+   /// Databases created before schema versioning may already have the column: their tables were brought up to
+   /// date by adding all columns on every start
+   AddColumnIfMissing {
+      table: &'static str,
+      column: &'static str,
+      definition: &'static str,
+   },
+}
+
+impl Step {
+   async fn apply(&self, tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> Result<()> {
+      match self {
+         Step::Sql(sql) => {
+            sqlx::query(sql).execute(&mut **tx).await?;
+         }
+         Step::AddColumnIfMissing {
+            table,
+            column,
+            definition,
+         } => {
+            let exists: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM pragma_table_info($1) WHERE name = $2")
+               .bind(table)
+               .bind(column)
+               .fetch_optional(&mut **tx)
+               .await?;
+            if exists.is_none() {
+               let sql = format!("ALTER TABLE {table} ADD {column} {definition};");
+               sqlx::query(&sql).execute(&mut **tx).await?;
+            }
+         }
+      }
+      Ok(())
+   }
+}
+
+/// Migrations of a module are numbered from 1 by their position in its list, so the list may only grow at the
+/// end: a released migration must never be changed or removed
+#[derive(Debug)]
+pub struct Migration {
+   pub description: &'static str,
+   pub steps: &'static [Step],
+}
+
+/// Returns the version of the module schema, 0 if it has never been migrated
+pub async fn schema_version(pool: &sqlx::Pool<sqlx::Sqlite>, module: &str) -> Result<i64> {
+   let mut conn = pool.acquire().await?;
+   sqlx::query(SCHEMA_VERSION_DDL).execute(&mut *conn).await?;
+   let version: Option<(i64,)> = sqlx::query_as("SELECT version FROM schema_version WHERE module = $1")
+      .bind(module)
+      .fetch_optional(&mut *conn)
+      .await?;
+   Ok(version.map_or(0, |(version,)| version))
+}
+
+/// Applies migrations of the module, that have not been applied yet, each one in its own transaction. Fails if
+/// the schema is newer than the latest known migration, e.g. after a downgrade of the server.
+pub async fn migrate(pool: &sqlx::Pool<sqlx::Sqlite>, module: &str, migrations: &[Migration]) -> Result<()> {
+   let latest = migrations.len() as i64;
+   loop {
+      let current = schema_version(pool, module).await?;
+      if current > latest {
+         return Err(anyhow!(
+            "Schema of {module} has version {current}, which is newer than the latest known {latest}: \
+             refusing to use the db, upgrade the server"
+         ));
+      }
+      let Some(migration) = migrations.get(current as usize) else {
+         return Ok(());
+      };
+      let version = current + 1;
+      log::info!("Migrating {module} to version {version}: {}", migration.description);
+
+      let mut tx = pool.begin().await?;
+      let res: Result<()> = async {
+         for step in migration.steps {
+            step.apply(&mut tx).await.with_context(|| anyhow!("Failed to apply {step:?}"))?;
+         }
+         // Fails if another process has migrated the module concurrently:
+         let updated = sqlx::query(
+            r#"INSERT INTO schema_version (module, version) VALUES ($1, $2)
+               ON CONFLICT (module) DO UPDATE SET version = excluded.version WHERE version = $3
+            "#,
+         )
+         .bind(module)
+         .bind(version)
+         .bind(current)
+         .execute(&mut *tx)
+         .await?;
+         if updated.rows_affected() == 0 {
+            return Err(anyhow!("Schema version has changed concurrently"));
+         }
+         Ok(())
+      }
+      .await;
+      res.with_context(|| anyhow!("Failed to migrate {module} to version {version}"))?;
+      tx.commit().await?;
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   const MIGRATIONS: &[Migration] = &[
+      Migration {
+         description: "Create table",
+         steps: &[Step::Sql("CREATE TABLE t (a INTEGER) STRICT;")],
+      },
+      Migration {
+         description: "Add column",
+         steps: &[Step::AddColumnIfMissing {
+            table: "t",
+            column: "b",
+            definition: "TEXT",
+         }],
+      },
+   ];
+
+   #[tokio::test]
+   async fn test_migrate_applies_new_migrations_only() -> Result<()> {
+      let pool = Location::create_pool(&Location::Memory).await?;
+      migrate(&pool, "test", &MIGRATIONS[..1]).await?;
+      assert_eq!(schema_version(&pool, "test").await?, 1);
+      // CREATE TABLE without IF NOT EXISTS would fail if applied again:
+      migrate(&pool, "test", MIGRATIONS).await?;
+      migrate(&pool, "test", MIGRATIONS).await?;
+      assert_eq!(schema_version(&pool, "test").await?, 2);
+      assert_eq!(schema_version(&pool, "other").await?, 0);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_migrate_rolls_back_failed_migration() -> Result<()> {
+      let pool = Location::create_pool(&Location::Memory).await?;
+      let broken = [
+         Migration {
+            description: "Create table",
+            steps: &[Step::Sql("CREATE TABLE t (a INTEGER) STRICT;")],
+         },
+         Migration {
+            description: "Broken",
+            steps: &[Step::Sql("CREATE TABLE u (a INTEGER) STRICT;"), Step::Sql("ALTER TABLE u ADDD b;")],
+         },
+      ];
+      let res = migrate(&pool, "test", &broken).await;
+      assert!(res.is_err());
+      assert_eq!(schema_version(&pool, "test").await?, 1);
+      let tables: Vec<(String,)> = sqlx::query_as("SELECT name FROM sqlite_master WHERE name = 'u'")
+         .fetch_all(&pool)
+         .await?;
+      assert_eq!(tables, Vec::new());
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_migrate_refuses_newer_schema() -> Result<()> {
+      let pool = Location::create_pool(&Location::Memory).await?;
+      migrate(&pool, "test", MIGRATIONS).await?;
+      let res = migrate(&pool, "test", &MIGRATIONS[..1]).await;
+      let error = format!("{:?}", res.unwrap_err());
+      assert!(error.contains("newer than the latest known 1"), "{error}");
+      Ok(())
    }
-   Ok(())
 }
@@ -8,20 +8,102 @@ pub struct Sqlite {
 
 impl Sqlite {
    pub async fn new(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Sqlite> {
-      crate::db::init_ddl(&pool, Self::ddl())
+      crate::db::migrate(pool, "measurements", MIGRATIONS)
          .await
-         .with_context(|| anyhow!("Failed to init ddl"))?;
+         .with_context(|| anyhow!("Failed to migrate"))?;
       Ok(Sqlite { pool: pool.clone() })
    }
+}
 
-   fn ddl() -> &'static [&'static str] {
-      &[
-         "CREATE TABLE IF NOT EXISTS measurements (read_ts INTEGER  NOT NULL) STRICT;",
-         "ALTER TABLE measurements ADD sensor_id   TEXT   ;",
-         "ALTER TABLE measurements ADD index_n     INTEGER;",
-         "ALTER TABLE measurements ADD temperature REAL   ;",
-         "ALTER TABLE measurements ADD error       TEXT   ;",
-      ]
+/// The first ones are idempotent, because databases created before schema versioning may have any of them
+/// applied already
+const MIGRATIONS: &[crate::db::Migration] = &[
+   crate::db::Migration {
+      description: "Create measurements",
+      steps: &[
+         crate::db::Step::Sql("CREATE TABLE IF NOT EXISTS measurements (read_ts INTEGER NOT NULL) STRICT;"),
+         crate::db::Step::AddColumnIfMissing {
+            table: "measurements",
+            column: "sensor_id",
+            definition: "TEXT",
+         },
+         crate::db::Step::AddColumnIfMissing {
+            table: "measurements",
+            column: "index_n",
+            definition: "INTEGER",
+         },
+         crate::db::Step::AddColumnIfMissing {
+            table: "measurements",
+            column: "temperature",
+            definition: "REAL",
+         },
+         crate::db::Step::AddColumnIfMissing {
+            table: "measurements",
+            column: "error",
+            definition: "TEXT",
+         },
+         // Rows written before the column was added can not be read as measurements otherwise:
+         crate::db::Step::Sql("UPDATE measurements SET error = '' WHERE error IS NULL;"),
+      ],
+   },
+   crate::db::Migration {
+      description: "Make (sensor_id, index_n) unique",
+      steps: &[
+         // (sensor_id, index_n) identifies a measurement, but before the unique index below was introduced,
+         // measurements resent by sensors (due to lost confirmations) were stored again => remove duplicates:
+         crate::db::Step::Sql(
+            r#"DELETE FROM measurements
+               WHERE sensor_id IS NOT NULL AND index_n IS NOT NULL AND rowid NOT IN (
+                  SELECT MIN(rowid) FROM measurements GROUP BY sensor_id, index_n
+               );"#,
+         ),
+         crate::db::Step::Sql(
+            r#"CREATE UNIQUE INDEX IF NOT EXISTS measurements_sensor_id_index_n
+               ON measurements (sensor_id, index_n);"#,
+         ),
+      ],
+   },
+   crate::db::Migration {
+      description: "Create quantities",
+      steps: &[
+         // Quantities other than temperature, which stays in measurements for backward compatibility:
+         crate::db::Step::Sql(
+            r#"CREATE TABLE IF NOT EXISTS quantities (
+               sensor_id TEXT    NOT NULL,
+               index_n   INTEGER NOT NULL,
+               kind      TEXT    NOT NULL,
+               unit      TEXT    NOT NULL,
+               value     REAL    NOT NULL,
+               PRIMARY KEY (sensor_id, index_n, kind)
+            ) STRICT;"#,
+         ),
+      ],
+   },
+];
+
+
+#[derive(sqlx::FromRow)]
+struct QuantityRow {
+   index_n: i64,
+   kind: common::QuantityKind,
+   unit: String,
+   value: f64,
+}
+
+/// Moves quantities to measurements (of the same sensor) they belong to
+fn attach_quantities(measurements: &mut [common::Measurement], rows: Vec<QuantityRow>) {
+   let mut by_index: std::collections::HashMap<i64, Vec<common::Quantity>> = Default::default();
+   for row in rows {
+      by_index.entry(row.index_n).or_default().push(common::Quantity {
+         kind: row.kind,
+         unit: row.unit,
+         value: row.value,
+      });
+   }
+   for measurement in measurements {
+      if let Some(quantities) = by_index.remove(&measurement.id.index) {
+         measurement.quantities = quantities;
+      }
    }
 }
 
@@ -34,23 +116,27 @@ impl Sqlite {
 
 #[async_trait::async_trait]
 pub trait Db {
-   async fn write(&self, row: &common::Measurement) -> Result<()>;
+   /// Returns false if a measurement with the same id has already been written (and nothing was written)
+   async fn write(&self, row: &common::Measurement) -> Result<bool>;
    async fn read(
       &self,
       start: common::MicroSecTs,
       end: common::MicroSecTs,
       sensor_id: &common::SensorId,
    ) -> Result<Vec<common::Measurement>>;
+   async fn read_latest(&self, sensor_id: &common::SensorId) -> Result<Option<common::Measurement>>;
    async fn delete(&self, up_to: common::MicroSecTs) -> Result<()>;
 }
 
 
 #[async_trait::async_trait]
 impl Db for Sqlite {
-   async fn write(&self, row: &common::Measurement) -> Result<()> {
-      sqlx::query(
+   async fn write(&self, row: &common::Measurement) -> Result<bool> {
+      let mut tx = self.pool.begin().await?;
+      let res = sqlx::query(
          r#"INSERT INTO measurements (read_ts, sensor_id, index_n, temperature, error)
             VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (sensor_id, index_n) DO NOTHING
          "#,
       )
       .bind(row.read_ts)
@@ -58,9 +144,27 @@ impl Db for Sqlite {
       .bind(&row.id.index)
       .bind(row.temperature)
       .bind(&row.error)
-      .execute(&self.pool)
+      .execute(&mut *tx)
       .await?;
-      Ok(())
+      if res.rows_affected() == 0 {
+         return Ok(false);
+      }
+      for quantity in &row.quantities {
+         sqlx::query(
+            r#"INSERT INTO quantities (sensor_id, index_n, kind, unit, value)
+               VALUES ($1, $2, $3, $4, $5)
+            "#,
+         )
+         .bind(&row.id.sensor_id)
+         .bind(row.id.index)
+         .bind(quantity.kind)
+         .bind(&quantity.unit)
+         .bind(quantity.value)
+         .execute(&mut *tx)
+         .await?;
+      }
+      tx.commit().await?;
+      Ok(true)
    }
 
    async fn read(
@@ -69,7 +173,7 @@ impl Db for Sqlite {
       end: common::MicroSecTs,
       sensor_id: &common::SensorId,
    ) -> Result<Vec<common::Measurement>> {
-      let measurements = sqlx::query_as(
+      let mut measurements: Vec<common::Measurement> = sqlx::query_as(
          r#"
          SELECT read_ts, sensor_id, index_n as "index", temperature, error
          FROM measurements
@@ -79,25 +183,94 @@ impl Db for Sqlite {
       )
       .bind(start)
       .bind(end)
-      .bind(&sensor_id)
+      .bind(sensor_id)
       .fetch_all(&self.pool)
       .await?;
 
+      let quantities = sqlx::query_as(
+         r#"
+         SELECT q.index_n, q.kind, q.unit, q.value
+         FROM quantities q
+         JOIN measurements m ON m.sensor_id = q.sensor_id AND m.index_n = q.index_n
+         WHERE m.read_ts >= $1 AND m.read_ts < $2 AND m.sensor_id = $3
+         ORDER BY q.kind
+         "#,
+      )
+      .bind(start)
+      .bind(end)
+      .bind(sensor_id)
+      .fetch_all(&self.pool)
+      .await?;
+      attach_quantities(&mut measurements, quantities);
       Ok(measurements)
    }
 
-   async fn delete(&self, up_to: common::MicroSecTs) -> Result<()> {
-      sqlx::query(
-         r#"DELETE FROM measurements WHERE read_ts < $1
+   async fn read_latest(&self, sensor_id: &common::SensorId) -> Result<Option<common::Measurement>> {
+      let measurement: Option<common::Measurement> = sqlx::query_as(
+         r#"
+         SELECT read_ts, sensor_id, index_n as "index", temperature, error
+         FROM measurements
+         WHERE sensor_id = $1
+         ORDER BY read_ts DESC
+         LIMIT 1
          "#,
       )
-      .bind(up_to)
-      .execute(&self.pool)
+      .bind(sensor_id)
+      .fetch_optional(&self.pool)
       .await?;
+      let Some(mut measurement) = measurement else {
+         return Ok(None);
+      };
+
+      let quantities = sqlx::query_as(
+         r#"
+         SELECT index_n, kind, unit, value
+         FROM quantities
+         WHERE sensor_id = $1 AND index_n = $2
+         ORDER BY kind
+         "#,
+      )
+      .bind(sensor_id)
+      .bind(measurement.id.index)
+      .fetch_all(&self.pool)
+      .await?;
+      attach_quantities(std::slice::from_mut(&mut measurement), quantities);
+      Ok(Some(measurement))
+   }
+
+   async fn delete(&self, up_to: common::MicroSecTs) -> Result<()> {
+      let mut tx = self.pool.begin().await?;
+      delete_in(&mut tx, up_to).await?;
+      tx.commit().await?;
       Ok(())
    }
 }
 
+/// Deletes measurements read before up_to (and their quantities) within the transaction, returns the number
+/// of deleted measurements
+pub(crate) async fn delete_in(
+   tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+   up_to: common::MicroSecTs,
+) -> Result<u64> {
+   sqlx::query(
+      r#"DELETE FROM quantities WHERE (sensor_id, index_n) IN (
+            SELECT sensor_id, index_n FROM measurements WHERE read_ts < $1
+         )
+      "#,
+   )
+   .bind(up_to)
+   .execute(&mut **tx)
+   .await?;
+   let res = sqlx::query(
+      r#"DELETE FROM measurements WHERE read_ts < $1
+      "#,
+   )
+   .bind(up_to)
+   .execute(&mut **tx)
+   .await?;
+   Ok(res.rows_affected())
+}
+
 
 //
 // ===========================================================================================================
@@ -110,10 +283,11 @@ mod tests {
    use pretty_assertions::assert_eq;
 
    #[tokio::test]
-   async fn test_init_ddl_is_idempotent() -> Result<()> {
+   async fn test_new_is_idempotent() -> Result<()> {
       let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
-      let sqlite = Sqlite::new(&pool).await?;
-      let _ = crate::db::init_ddl(&sqlite.pool, Sqlite::ddl()).await;
+      Sqlite::new(&pool).await?;
+      Sqlite::new(&pool).await?;
+      assert_eq!(crate::db::schema_version(&pool, "measurements").await?, MIGRATIONS.len() as i64);
       Ok(())
    }
 
@@ -128,13 +302,15 @@ mod tests {
    fn measurement(ts: common::MicroSecTs) -> common::Measurement {
       let id = common::MeasurementId {
          sensor_id: get_sen_id(),
-         index: 123,
+         index: ts.0.timestamp(),
       };
       let mes = common::Measurement {
          id,
          temperature: Some(26.8),
          error: "error1".to_string(),
          read_ts: ts,
+         quantities: Vec::new(),
+         health: None,
       };
       mes
    }
@@ -239,6 +415,110 @@ mod tests {
       Ok(())
    }
 
+   #[tokio::test]
+   async fn test_read_latest() -> Result<()> {
+      let (y, m, d) = (2024, 1, 2);
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      sqlite.write(&measurement(ts_ymd(y, m, d + 1))).await?;
+      sqlite.write(&measurement(ts_ymd(y, m, d + 2))).await?;
+      sqlite.write(&measurement(ts_ymd(y, m, d))).await?;
+      let res = sqlite.read_latest(&get_sen_id()).await?;
+      assert_eq!(res, Some(measurement(ts_ymd(y, m, d + 2))));
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_write_ignores_duplicates() -> Result<()> {
+      let (y, m, d) = (2024, 1, 1);
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      assert!(sqlite.write(&measurement(ts_ymd(y, m, d))).await?);
+      let resent = common::Measurement {
+         temperature: Some(1.0),
+         ..measurement(ts_ymd(y, m, d))
+      };
+      assert!(!sqlite.write(&resent).await?);
+      let res = sqlite.read(ts_ymd(y, m, d), ts_ymd(y + 1, m, d), &get_sen_id()).await?;
+      assert_eq!(res, vec![measurement(ts_ymd(y, m, d))]);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_new_migrates_old_layout_and_removes_existing_duplicates() -> Result<()> {
+      let (y, m, d) = (2024, 1, 1);
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      // The layout before schema versioning, with the columns added over time in a different order:
+      let old_ddl = [
+         "CREATE TABLE measurements (read_ts INTEGER  NOT NULL) STRICT;",
+         "ALTER TABLE measurements ADD temperature REAL   ;",
+         "ALTER TABLE measurements ADD sensor_id   TEXT   ;",
+         "ALTER TABLE measurements ADD index_n     INTEGER;",
+      ];
+      for sql in old_ddl {
+         sqlx::query(sql).execute(&pool).await?;
+      }
+      for (index, temperature) in [(1, 1.0), (1, 2.0), (2, 3.0)] {
+         let sql = "INSERT INTO measurements (read_ts, sensor_id, index_n, temperature) \
+                    VALUES ($1, $2, $3, $4)";
+         sqlx::query(sql)
+            .bind(ts_ymd(y, m, d))
+            .bind(get_sen_id())
+            .bind(index)
+            .bind(temperature)
+            .execute(&pool)
+            .await?;
+      }
+
+      let sqlite = Sqlite::new(&pool).await?;
+      assert_eq!(crate::db::schema_version(&pool, "measurements").await?, MIGRATIONS.len() as i64);
+      let res = sqlite.read(ts_ymd(y, m, d), ts_ymd(y + 1, m, d), &get_sen_id()).await?;
+      let temperatures: Vec<_> = res.iter().map(|m| m.temperature).collect();
+      assert_eq!(temperatures, vec![Some(1.0), Some(3.0)]);
+      assert!(sqlite.write(&measurement(ts_ymd(y, m, d))).await?);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_quantities_are_written_read_and_deleted() -> Result<()> {
+      let (y, m, d) = (2024, 1, 1);
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      let with_quantities = |ts| common::Measurement {
+         quantities: vec![
+            common::Quantity::new(common::QuantityKind::Humidity, 45.5),
+            common::Quantity::new(common::QuantityKind::Voltage, 5.1),
+         ],
+         ..measurement(ts)
+      };
+      sqlite.write(&with_quantities(ts_ymd(y, m, d))).await?;
+      sqlite.write(&measurement(ts_ymd(y, m, d + 1))).await?;
+      sqlite.write(&with_quantities(ts_ymd(y, m, d + 2))).await?;
+
+      let res = sqlite.read(ts_ymd(y, m, d), ts_ymd(y + 1, m, d), &get_sen_id()).await?;
+      let expected = vec![
+         with_quantities(ts_ymd(y, m, d)),
+         measurement(ts_ymd(y, m, d + 1)),
+         with_quantities(ts_ymd(y, m, d + 2)),
+      ];
+      assert_eq!(res, expected);
+      assert_eq!(sqlite.read_latest(&get_sen_id()).await?, Some(with_quantities(ts_ymd(y, m, d + 2))));
+
+      sqlite.delete(ts_ymd(y, m, d + 2)).await?;
+      let (left,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM quantities").fetch_one(&pool).await?;
+      assert_eq!(left, 2);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_read_latest_no_measurements() -> Result<()> {
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      let res = sqlite.read_latest(&get_sen_id()).await?;
+      assert_eq!(res, None);
+      Ok(())
+   }
+
    #[tokio::test]
    async fn test_delete_ts_less_than_up_to() -> Result<()> {
       let (y, m, d) = (2024, 1, 1);
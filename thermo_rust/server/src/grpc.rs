@@ -2,20 +2,41 @@ use anyhow::{Context, Result, anyhow};
 
 type MeasurementTx = tokio::sync::broadcast::Sender<common::Measurement>;
 
+/// How many measurements a subscriber (alerting, live subscriptions, etc...) can lag behind, before it starts
+/// missing them
+const BROADCAST_CAPACITY: usize = 1024;
+
 #[derive(Clone)]
 pub struct Agg {
    tx: MeasurementTx,
    db: crate::db::measurement::Sqlite,
+   sensor_db: crate::sensor::Sqlite,
+   client_db: crate::client::Sqlite,
+   health_db: crate::health::Sqlite,
+   retention_db: crate::retention::Sqlite,
 }
 
 impl Agg {
    pub fn start(
       routes: tonic::service::Routes,
       db: crate::db::measurement::Sqlite,
+      sensor_db: crate::sensor::Sqlite,
+      client_db: crate::client::Sqlite,
+      health_db: crate::health::Sqlite,
+      retention_db: crate::retention::Sqlite,
    ) -> (tonic::service::Routes, MeasurementTx) {
-      let (tx, _) = tokio::sync::broadcast::channel(16);
-      let agg = Agg { tx: tx.clone(), db };
-      let service = common::pb::aggproto::agg_server::AggServer::new(agg);
+      let (tx, _) = tokio::sync::broadcast::channel(BROADCAST_CAPACITY);
+      let agg = Agg {
+         tx: tx.clone(),
+         db,
+         sensor_db,
+         client_db,
+         health_db,
+         retention_db,
+      };
+      let service = common::pb::aggproto::agg_server::AggServer::new(agg)
+         .accept_compressed(tonic::codec::CompressionEncoding::Gzip)
+         .send_compressed(tonic::codec::CompressionEncoding::Gzip);
       let routes = routes.add_service(service);
       (routes, tx)
    }
@@ -27,6 +48,21 @@ impl Agg {
 
 type Stream = dyn futures::Stream<Item = Result<common::pb::StoreMeasurementResp, tonic::Status>> + Send;
 type PBStream = std::pin::Pin<Box<Stream>>;
+type QueryStream = std::pin::Pin<
+   Box<dyn futures::Stream<Item = Result<common::pb::QueryMeasurementsResp, tonic::Status>> + Send>,
+>;
+
+type SubscribeStream = std::pin::Pin<
+   Box<dyn futures::Stream<Item = Result<common::pb::SubscribeMeasurementsResp, tonic::Status>> + Send>,
+>;
+
+/// Max number of measurements in a single QueryMeasurementsResp
+const QUERY_CHUNK_SIZE: usize = 1000;
+
+fn internal(why: anyhow::Error) -> tonic::Status { tonic::Status::internal(format!("{why:?}")) }
+fn invalid_argument(why: anyhow::Error) -> tonic::Status {
+   tonic::Status::invalid_argument(format!("{why:?}"))
+}
 
 #[tonic::async_trait]
 impl common::pb::agg_server::Agg for Agg {
@@ -38,16 +74,20 @@ impl common::pb::agg_server::Agg for Agg {
       &self,
       request: tonic::Request<tonic::Streaming<common::pb::StoreMeasurementReq>>,
    ) -> Result<tonic::Response<Self::StoreMeasurementStream>, tonic::Status> {
+      let client_cn = client_cn(&request);
+      if let Err(why) = &client_cn {
+         log::warn!("Failed to get CN of the client, all its measurements will be rejected: {why:?}");
+      }
+      let client_cn = client_cn.ok();
       let mut stream = request.into_inner();
-      let tx = self.tx.clone();
-      let db = self.db.clone();
+      let agg = self.clone();
 
       use futures::StreamExt;
       let output = async_stream::try_stream! {
          loop {
             match stream.message().await {
                Ok(Some(proto)) => {
-                  let response = match persist(proto.clone(), &tx, &db).await {
+                  let response = match persist(proto.clone(), client_cn.as_deref(), &agg).await {
                      Ok(response) => response,
                      Err(why) => {
                         log::warn!("Failed to persist: {proto:?}: {why:?}");
@@ -72,30 +112,629 @@ impl common::pb::agg_server::Agg for Agg {
 
       Ok(tonic::Response::new(output as PBStream))
    }
+
+   async fn list_sensors(
+      &self,
+      _request: tonic::Request<common::pb::ListSensorsReq>,
+   ) -> Result<tonic::Response<common::pb::ListSensorsResp>, tonic::Status> {
+      use crate::sensor::Db;
+      let sensors = self.sensor_db.get_all().await.map_err(internal)?;
+      Ok(tonic::Response::new(common::pb::ListSensorsResp {
+         sensors: sensors.into_iter().map(Into::into).collect(),
+      }))
+   }
+
+   type QueryMeasurementsStream = QueryStream;
+
+   async fn query_measurements(
+      &self,
+      request: tonic::Request<common::pb::QueryMeasurementsReq>,
+   ) -> Result<tonic::Response<Self::QueryMeasurementsStream>, tonic::Status> {
+      let query = Query::try_from(request.into_inner()).map_err(invalid_argument)?;
+
+      let (start, end, sensor_id) = (query.start, query.end, &query.sensor_id);
+      let measurements =
+         crate::retention::read_measurements(&self.db, &self.retention_db, start, end, sensor_id)
+            .await
+            .map_err(internal)?;
+      let measurements = match query.downsample {
+         Some(bucket) => downsample(&measurements, bucket),
+         None => measurements,
+      };
+
+      let responses: Vec<_> = measurements
+         .chunks(QUERY_CHUNK_SIZE)
+         .map(|chunk| common::pb::QueryMeasurementsResp {
+            measurements: chunk.iter().cloned().map(Into::into).collect(),
+         })
+         .collect();
+      use futures::StreamExt;
+      Ok(tonic::Response::new(futures::stream::iter(responses).map(Ok).boxed()))
+   }
+
+   async fn get_latest(
+      &self,
+      request: tonic::Request<common::pb::GetLatestReq>,
+   ) -> Result<tonic::Response<common::pb::GetLatestResp>, tonic::Status> {
+      use crate::db::measurement::Db as _;
+      use crate::sensor::Db as _;
+
+      let sensor_ids = request.into_inner().sensor_ids;
+      let sensor_ids: Vec<common::SensorId> = if sensor_ids.is_empty() {
+         let sensors = self.sensor_db.get_all().await.map_err(internal)?;
+         sensors.into_iter().map(|s| s.id).collect()
+      } else {
+         let sensor_ids: Result<Vec<_>> = sensor_ids.into_iter().map(TryInto::try_into).collect();
+         sensor_ids.map_err(invalid_argument)?
+      };
+
+      let mut measurements = Vec::new();
+      for sensor_id in &sensor_ids {
+         let latest = self.db.read_latest(sensor_id).await.map_err(internal)?;
+         measurements.extend(latest.map(Into::into));
+      }
+      Ok(tonic::Response::new(common::pb::GetLatestResp { measurements }))
+   }
+
+   async fn get_capabilities(
+      &self,
+      _request: tonic::Request<common::pb::GetCapabilitiesReq>,
+   ) -> Result<tonic::Response<common::pb::GetCapabilitiesResp>, tonic::Status> {
+      Ok(tonic::Response::new(common::pb::GetCapabilitiesResp { health: true }))
+   }
+
+   type SubscribeMeasurementsStream = SubscribeStream;
+
+   async fn subscribe_measurements(
+      &self,
+      request: tonic::Request<common::pb::SubscribeMeasurementsReq>,
+   ) -> Result<tonic::Response<Self::SubscribeMeasurementsStream>, tonic::Status> {
+      let sensor_ids: Result<std::collections::HashSet<common::SensorId>> =
+         request.into_inner().sensor_ids.into_iter().map(TryInto::try_into).collect();
+      let sensor_ids = sensor_ids.map_err(invalid_argument)?;
+      let mut rx = self.tx.subscribe();
+
+      use futures::StreamExt;
+      let output = async_stream::stream! {
+         loop {
+            match rx.recv().await {
+               Ok(measurement) => {
+                  if !sensor_ids.is_empty() && !sensor_ids.contains(&measurement.id.sensor_id) {
+                     continue;
+                  }
+                  yield Ok(common::pb::SubscribeMeasurementsResp {
+                     measurement: Some(measurement.into()),
+                     dropped: 0,
+                  });
+               }
+               Err(tokio::sync::broadcast::error::RecvError::Lagged(dropped)) => {
+                  log::warn!("Subscriber is lagging behind, dropped {dropped} measurements");
+                  yield Ok(common::pb::SubscribeMeasurementsResp { measurement: None, dropped });
+               }
+               Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                  break;
+               }
+            }
+         }
+      }
+      .boxed();
+
+      Ok(tonic::Response::new(output))
+   }
+}
+
+
+// ===========================================================================================================
+// Query
+
+struct Query {
+   sensor_id: common::SensorId,
+   start: common::MicroSecTs,
+   end: common::MicroSecTs,
+   downsample: Option<chrono::Duration>,
+}
+
+impl TryFrom<common::pb::QueryMeasurementsReq> for Query {
+   type Error = anyhow::Error;
+
+   fn try_from(proto: common::pb::QueryMeasurementsReq) -> Result<Self, Self::Error> {
+      let start = proto.start.ok_or_else(|| anyhow!("start is None"))?;
+      let end = proto.end.ok_or_else(|| anyhow!("end is None"))?;
+      let downsample = proto.downsample.map(common::proto_duration_to_chrono);
+      if let Some(downsample) = downsample
+         && downsample <= chrono::Duration::zero()
+      {
+         return Err(anyhow!("downsample must be positive, got: {downsample}"));
+      }
+      Ok(Self {
+         sensor_id: proto.sensor_id.try_into()?,
+         start: common::proto_timestamp_to_chrono(start)?.into(),
+         end: common::proto_timestamp_to_chrono(end)?.into(),
+         downsample,
+      })
+   }
+}
+
+/// Averages temperature and other quantities of measurements (sorted by read_ts) within buckets of the given
+/// size aligned to unix epoch. Each bucket becomes a single measurement with id of its first measurement and
+/// read_ts of the start of the bucket. Errors are kept only for buckets without any values.
+fn downsample(measurements: &[common::Measurement], bucket: chrono::Duration) -> Vec<common::Measurement> {
+   let bucket_us = bucket.num_microseconds().unwrap_or(i64::MAX).max(1);
+   let key = |m: &common::Measurement| m.read_ts.timestamp_micros().div_euclid(bucket_us);
+   let mean =
+      |values: Vec<f64>| (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64);
+
+   measurements
+      .chunk_by(|a, b| key(a) == key(b))
+      .map(|chunk| {
+         let first = &chunk[0];
+         let read_ts: common::MicroSecTs = chrono::DateTime::from_timestamp_micros(key(first) * bucket_us)
+            .unwrap_or(first.read_ts.0)
+            .into();
+         let temperature = mean(chunk.iter().filter_map(|m| m.temperature).collect());
+         let mut quantities: Vec<common::Quantity> = Vec::new();
+         for kind in common::QuantityKind::ALL {
+            let of_kind: Vec<&common::Quantity> =
+               chunk.iter().flat_map(|m| &m.quantities).filter(|q| q.kind == kind).collect();
+            if let Some(value) = mean(of_kind.iter().map(|q| q.value).collect()) {
+               let unit = of_kind[0].unit.clone();
+               quantities.push(common::Quantity { kind, unit, value });
+            }
+         }
+         if temperature.is_none() && quantities.is_empty() {
+            return common::Measurement {
+               read_ts,
+               ..first.clone()
+            };
+         }
+         common::Measurement {
+            id: first.id.clone(),
+            read_ts,
+            temperature,
+            error: String::new(),
+            quantities,
+            health: None,
+         }
+      })
+      .collect()
 }
 
 
+fn client_cn<T>(request: &tonic::Request<T>) -> Result<String> {
+   let certs = request.peer_certs().ok_or_else(|| anyhow!("There is no client certificate"))?;
+   let cert = certs.first().ok_or_else(|| anyhow!("There is no client certificate"))?;
+   common::tls::subject_cn(cert)
+}
+
+/// Returns why the client is not allowed to report measurements of the sensor (if it is not)
+async fn check_access(
+   client_cn: Option<&str>,
+   sensor_id: &common::SensorId,
+   agg: &Agg,
+) -> Result<Option<(common::pb::RejectReason, String)>> {
+   use common::pb::RejectReason;
+   let Some(client_cn) = client_cn else {
+      let reason = "Unknown client: there is no valid client certificate".to_string();
+      return Ok(Some((RejectReason::UnknownClient, reason)));
+   };
+
+   use crate::sensor::Db as _;
+   let sensor = agg
+      .sensor_db
+      .get_by_id(sensor_id)
+      .await
+      .with_context(|| anyhow!("Failed to get {sensor_id}"))?;
+   if sensor.is_none() {
+      return Ok(Some((RejectReason::Unregistered, format!("Sensor {sensor_id} is not registered"))));
+   }
+
+   use crate::client::Db as _;
+   let binding = crate::client::Binding {
+      client_cn: client_cn.to_string(),
+      sensor_id: sensor_id.clone(),
+   };
+   let is_bound = agg
+      .client_db
+      .is_bound(&binding)
+      .await
+      .with_context(|| anyhow!("Failed to check {binding:?}"))?;
+   if !is_bound {
+      let reason = format!("Client {client_cn} is not allowed to report measurements of sensor {sensor_id}");
+      return Ok(Some((RejectReason::Unbound, reason)));
+   }
+   Ok(None)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+   Confirmed(common::MeasurementId),
+   Rejected(common::pb::Rejected),
+}
+
+/// Persists all measurements from the request (both the single one sent by older sensors and the batch).
+/// Measurements, that failed to be persisted, are neither confirmed nor rejected, so that sensors resend them
+/// later.
 async fn persist(
    proto: common::pb::StoreMeasurementReq,
-   tx: &MeasurementTx,
-   db: &crate::db::measurement::Sqlite,
+   client_cn: Option<&str>,
+   agg: &Agg,
 ) -> Result<common::pb::StoreMeasurementResp> {
-   let measurement: common::Measurement = proto
-      .measurement
-      .clone()
-      .ok_or_else(|| anyhow!("Measurement is missing in {proto:?}"))?
-      .try_into()
-      .with_context(|| anyhow!("Failed to convert proto measurement to measurement: {proto:?}"))?;
+   let mut outcomes = Vec::new();
+   let mut confirmed = None;
+   if let Some(proto) = proto.measurement {
+      let outcome = persist_one(proto.clone(), client_cn, agg)
+         .await
+         .with_context(|| anyhow!("Failed to persist {proto:?}"))?;
+      if let Outcome::Confirmed(id) = &outcome {
+         confirmed = Some(id.clone().into());
+      }
+      outcomes.push(outcome);
+   }
+
+   for proto in proto.measurements {
+      match persist_one(proto.clone(), client_cn, agg).await {
+         Ok(outcome) => outcomes.push(outcome),
+         Err(why) => log::warn!("Failed to persist: {proto:?}: {why:?}"),
+      }
+   }
+
+   let mut resp = common::pb::StoreMeasurementResp {
+      confirmed,
+      ..Default::default()
+   };
+   for outcome in outcomes {
+      match outcome {
+         Outcome::Confirmed(id) => resp.confirmed_batch.push(id.into()),
+         Outcome::Rejected(rejected) => resp.rejected.push(rejected),
+      }
+   }
+   Ok(resp)
+}
+
+async fn persist_one(proto: common::pb::Measurement, client_cn: Option<&str>, agg: &Agg) -> Result<Outcome> {
+   let measurement: common::Measurement = match proto.clone().try_into() {
+      Ok(measurement) => measurement,
+      // Resending will not make it valid => reject it for good if the sensor can at least tell which one it is:
+      Err(why) => match &proto.id {
+         Some(id) if common::MeasurementId::try_from(id.clone()).is_ok() => {
+            log::warn!("Rejecting invalid {proto:?}: {why:?}");
+            return Ok(Outcome::Rejected(common::pb::Rejected {
+               id: Some(id.clone()),
+               reason: format!("Invalid measurement: {why}"),
+               code: common::pb::RejectReason::Invalid.into(),
+            }));
+         }
+         _ => return Err(why).with_context(|| anyhow!("Failed to convert proto measurement: {proto:?}")),
+      },
+   };
    log::info!("Received {measurement}");
 
+   if let Some((code, reason)) = check_access(client_cn, &measurement.id.sensor_id, agg).await? {
+      log::warn!("Rejecting {measurement}: {reason}");
+      return Ok(Outcome::Rejected(common::pb::Rejected {
+         id: Some(measurement.id.into()),
+         reason,
+         code: code.into(),
+      }));
+   }
+
+   if let Some(report) = crate::health::Report::from_measurement(&measurement) {
+      // Health summaries are neither readings nor broadcast to subscribers:
+      use crate::health::Db as _;
+      let is_new = agg
+         .health_db
+         .write(&report)
+         .await
+         .with_context(|| anyhow!("Failed to health_db.write {report:?}"))?;
+      if !is_new {
+         log::info!("{measurement} has already been stored");
+      }
+      return Ok(Outcome::Confirmed(measurement.id));
+   }
+
    use crate::db::measurement::Db;
-   db.write(&measurement)
+   let is_new = agg
+      .db
+      .write(&measurement)
       .await
       .with_context(|| anyhow!("Failed to db.write {measurement:?}"))?;
    let confirmed = measurement.id.clone();
-   let _ = tx.send(measurement);
+   if is_new {
+      let _ = agg.tx.send(measurement);
+   } else {
+      // The sensor did not get our confirmation and resent it => just confirm it again:
+      log::info!("{measurement} has already been stored");
+   }
+   Ok(Outcome::Confirmed(confirmed))
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn ts_minute(minute: i64) -> common::MicroSecTs {
+      use chrono::TimeZone;
+      (chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::minutes(minute)).into()
+   }
+
+   fn get_sen_id() -> common::SensorId { "sen_asdf_1".to_string().try_into().unwrap() }
+
+   fn id(index: i64) -> common::MeasurementId {
+      common::MeasurementId {
+         sensor_id: get_sen_id(),
+         index,
+      }
+   }
+
+   async fn subscribe(agg: &Agg, sensor_ids: &[&common::SensorId]) -> SubscribeStream {
+      use common::pb::agg_server::Agg as _;
+      let req = common::pb::SubscribeMeasurementsReq {
+         sensor_ids: sensor_ids.iter().map(|id| id.to_string()).collect(),
+      };
+      agg.subscribe_measurements(tonic::Request::new(req)).await.unwrap().into_inner()
+   }
+
+   const CLIENT_CN: &str = "CLI-asdf";
+
+   /// Creates Agg with sensor get_sen_id() registered and bound to CLIENT_CN
+   async fn create_agg(capacity: usize) -> Result<Agg> {
+      use crate::client::Db as _;
+      use crate::sensor::Db as _;
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let (tx, _) = tokio::sync::broadcast::channel(capacity);
+      let agg = Agg {
+         tx,
+         db: crate::db::measurement::Sqlite::new(&pool).await?,
+         sensor_db: crate::sensor::Sqlite::new(&pool).await?,
+         client_db: crate::client::Sqlite::new(&pool).await?,
+         health_db: crate::health::Sqlite::new(&pool).await?,
+         retention_db: crate::retention::Sqlite::new(&pool).await?,
+      };
+      agg.sensor_db
+         .add(&crate::sensor::Sensor {
+            id: get_sen_id(),
+            name: "name".to_string(),
+            location: "location".to_string(),
+            min: 5.0,
+            stale_after_mins: None,
+         })
+         .await?;
+      agg.client_db
+         .bind(&crate::client::Binding {
+            client_cn: CLIENT_CN.to_string(),
+            sensor_id: get_sen_id(),
+         })
+         .await?;
+      Ok(agg)
+   }
+
+   #[tokio::test]
+   async fn test_persist_confirms_single_measurement_of_older_sensors() -> Result<()> {
+      let agg = create_agg(16).await?;
+      let req = common::pb::StoreMeasurementReq {
+         measurement: Some(common::Measurement::from_ok(&id(1), 1.0, ts_minute(1)).into()),
+         ..Default::default()
+      };
+      let resp = persist(req, Some(CLIENT_CN), &agg).await?;
 
-   Ok(common::pb::StoreMeasurementResp {
-      confirmed: Some(confirmed.into()),
-   })
+      let expected = common::pb::StoreMeasurementResp {
+         confirmed: Some(id(1).into()),
+         confirmed_batch: vec![id(1).into()],
+         rejected: Vec::new(),
+      };
+      assert_eq!(resp, expected);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_persist_confirms_batch() -> Result<()> {
+      use crate::db::measurement::Db;
+      let agg = create_agg(16).await?;
+      let invalid = common::pb::Measurement {
+         id: None,
+         ..common::Measurement::from_ok(&id(2), 2.0, ts_minute(2)).into()
+      };
+      let req = common::pb::StoreMeasurementReq {
+         measurements: vec![
+            common::Measurement::from_ok(&id(1), 1.0, ts_minute(1)).into(),
+            invalid,
+            common::Measurement::from_ok(&id(3), 3.0, ts_minute(3)).into(),
+         ],
+         ..Default::default()
+      };
+      let resp = persist(req, Some(CLIENT_CN), &agg).await?;
+
+      let expected = common::pb::StoreMeasurementResp {
+         confirmed: None,
+         confirmed_batch: vec![id(1).into(), id(3).into()],
+         rejected: Vec::new(),
+      };
+      assert_eq!(resp, expected);
+      let latest = agg.db.read_latest(&get_sen_id()).await?;
+      assert_eq!(latest, Some(common::Measurement::from_ok(&id(3), 3.0, ts_minute(3))));
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_persist_rejects_unregistered_sensors_and_unauthorised_clients() -> Result<()> {
+      use crate::db::measurement::Db;
+      let agg = create_agg(16).await?;
+      let unregistered = common::MeasurementId::new(&common::SensorId::new());
+      let req = common::pb::StoreMeasurementReq {
+         measurements: vec![
+            common::Measurement::from_ok(&unregistered, 1.0, ts_minute(1)).into(),
+            common::Measurement::from_ok(&id(2), 2.0, ts_minute(2)).into(),
+         ],
+         ..Default::default()
+      };
+
+      let reasons = |resp: common::pb::StoreMeasurementResp| -> Vec<String> {
+         resp.rejected.into_iter().map(|r| r.reason).collect()
+      };
+      let resp = persist(req.clone(), Some(CLIENT_CN), &agg).await?;
+      assert_eq!(resp.confirmed_batch, vec![id(2).into()]);
+      assert_eq!(resp.rejected.first().and_then(|r| r.id.clone()), Some(unregistered.clone().into()));
+      assert_eq!(resp.rejected[0].code(), common::pb::RejectReason::Unregistered);
+      assert_eq!(reasons(resp), vec![format!("Sensor {} is not registered", unregistered.sensor_id)]);
+
+      let resp = persist(req.clone(), Some("CLI-other"), &agg).await?;
+      assert_eq!(resp.confirmed_batch, Vec::new());
+      let sensor_id = get_sen_id();
+      let expected = format!("Client CLI-other is not allowed to report measurements of sensor {sensor_id}");
+      assert_eq!(resp.rejected[1].code(), common::pb::RejectReason::Unbound);
+      assert_eq!(reasons(resp)[1], expected);
+
+      let resp = persist(req, None, &agg).await?;
+      assert_eq!(resp.confirmed_batch, Vec::new());
+      assert_eq!(resp.rejected.len(), 2);
+      assert!(resp.rejected.iter().all(|r| r.code() == common::pb::RejectReason::UnknownClient));
+
+      let stored = agg.db.read(ts_minute(0), ts_minute(10), &get_sen_id()).await?;
+      assert_eq!(stored, vec![common::Measurement::from_ok(&id(2), 2.0, ts_minute(2))]);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_persist_confirms_duplicates_but_does_not_broadcast_them() -> Result<()> {
+      let agg = create_agg(16).await?;
+      let mut rx = agg.tx.subscribe();
+      let req = common::pb::StoreMeasurementReq {
+         measurements: vec![common::Measurement::from_ok(&id(1), 1.0, ts_minute(1)).into()],
+         ..Default::default()
+      };
+      let first = persist(req.clone(), Some(CLIENT_CN), &agg).await?;
+      let second = persist(req, Some(CLIENT_CN), &agg).await?;
+
+      assert_eq!(first.confirmed_batch, vec![id(1).into()]);
+      assert_eq!(second.confirmed_batch, vec![id(1).into()]);
+      assert_eq!(rx.try_recv()?, common::Measurement::from_ok(&id(1), 1.0, ts_minute(1)));
+      assert!(rx.try_recv().is_err());
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_persist_stores_health_separately_from_readings() -> Result<()> {
+      use crate::db::measurement::Db as _;
+      use crate::health::Db as _;
+      let agg = create_agg(16).await?;
+      let mut rx = agg.tx.subscribe();
+      let health = common::Health {
+         since: ts_minute(0),
+         reads: 60,
+         failures: 1,
+         consecutive_failures: 0,
+         mean_latency: std::time::Duration::from_millis(750),
+         last_error: "error1".to_string(),
+      };
+      let measurement = common::Measurement::from_health(&id(1), health.clone(), ts_minute(60));
+      let req = common::pb::StoreMeasurementReq {
+         measurements: vec![measurement.clone().into()],
+         ..Default::default()
+      };
+      let resp = persist(req, Some(CLIENT_CN), &agg).await?;
+
+      assert_eq!(resp.confirmed_batch, vec![id(1).into()]);
+      assert!(rx.try_recv().is_err());
+      assert_eq!(agg.db.read_latest(&get_sen_id()).await?, None);
+      let reports = agg.health_db.read(ts_minute(0), ts_minute(120), &get_sen_id()).await?;
+      assert_eq!(reports, vec![crate::health::Report::from_measurement(&measurement).unwrap()]);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_subscribe_measurements_filters_by_sensor_id() -> Result<()> {
+      use futures::StreamExt;
+      let agg = create_agg(16).await?;
+      let other_id = common::MeasurementId::new(&common::SensorId::new());
+      let mut stream = subscribe(&agg, &[&get_sen_id()]).await;
+
+      agg.tx.send(common::Measurement::from_ok(&other_id, 1.0, ts_minute(1)))?;
+      agg.tx.send(common::Measurement::from_ok(&id(2), 2.0, ts_minute(2)))?;
+
+      let resp = stream.next().await.unwrap()?;
+      let expected = common::pb::SubscribeMeasurementsResp {
+         measurement: Some(common::Measurement::from_ok(&id(2), 2.0, ts_minute(2)).into()),
+         dropped: 0,
+      };
+      assert_eq!(resp, expected);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_subscribe_measurements_reports_dropped() -> Result<()> {
+      use futures::StreamExt;
+      let agg = create_agg(2).await?;
+      let mut stream = subscribe(&agg, &[]).await;
+      for i in 0..5 {
+         agg.tx.send(common::Measurement::from_ok(&id(i), 1.0, ts_minute(i)))?;
+      }
+
+      let resp = stream.next().await.unwrap()?;
+      assert_eq!(resp, common::pb::SubscribeMeasurementsResp {
+         measurement: None,
+         dropped: 3
+      });
+      let resp = stream.next().await.unwrap()?;
+      assert_eq!(resp.measurement, Some(common::Measurement::from_ok(&id(3), 1.0, ts_minute(3)).into()));
+      Ok(())
+   }
+
+   #[test]
+   fn test_downsample_averages_within_buckets() {
+      let measurements = vec![
+         common::Measurement::from_ok(&id(1), 10.0, ts_minute(1)),
+         common::Measurement::from_ok(&id(2), 20.0, ts_minute(9)),
+         common::Measurement::from_ok(&id(3), 30.0, ts_minute(10)),
+      ];
+      let res = downsample(&measurements, chrono::Duration::minutes(10));
+      let expected = vec![
+         common::Measurement::from_ok(&id(1), 15.0, ts_minute(0)),
+         common::Measurement::from_ok(&id(3), 30.0, ts_minute(10)),
+      ];
+      assert_eq!(res, expected);
+   }
+
+   #[test]
+   fn test_downsample_ignores_errors_if_bucket_has_temperature() {
+      let measurements = vec![
+         common::Measurement::from_err(&id(1), "error1", ts_minute(1)),
+         common::Measurement::from_ok(&id(2), 20.0, ts_minute(2)),
+         common::Measurement::from_err(&id(3), "error3", ts_minute(11)),
+      ];
+      let res = downsample(&measurements, chrono::Duration::minutes(10));
+      let expected = vec![
+         common::Measurement::from_ok(&id(1), 20.0, ts_minute(0)),
+         common::Measurement::from_err(&id(3), "error3", ts_minute(10)),
+      ];
+      assert_eq!(res, expected);
+   }
+
+   #[test]
+   fn test_downsample_averages_quantities_per_kind() -> Result<()> {
+      let humidity = |v| common::Quantity::new(common::QuantityKind::Humidity, v);
+      let voltage = |v| common::Quantity::new(common::QuantityKind::Voltage, v);
+      let measurements = vec![
+         common::Measurement::from_quantities(&id(1), vec![humidity(40.0), voltage(5.0)], ts_minute(1))?,
+         common::Measurement::from_quantities(&id(2), vec![humidity(50.0)], ts_minute(2))?,
+      ];
+      let res = downsample(&measurements, chrono::Duration::minutes(10));
+      let expected = vec![common::Measurement::from_quantities(
+         &id(1),
+         vec![humidity(45.0), voltage(5.0)],
+         ts_minute(0),
+      )?];
+      assert_eq!(res, expected);
+      Ok(())
+   }
+
+   #[test]
+   fn test_downsample_empty() {
+      assert_eq!(downsample(&[], chrono::Duration::minutes(10)), Vec::new());
+   }
 }
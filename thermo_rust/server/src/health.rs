@@ -0,0 +1,261 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Health summary of a sensor, periodically reported by its client
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+   pub id: common::MeasurementId,
+   /// End of the period the summary covers
+   pub read_ts: common::MicroSecTs,
+   pub health: common::Health,
+}
+
+impl Report {
+   /// Returns None if the measurement is not a health summary
+   pub fn from_measurement(measurement: &common::Measurement) -> Option<Self> {
+      let health = measurement.health.clone()?;
+      Some(Self {
+         id: measurement.id.clone(),
+         read_ts: measurement.read_ts,
+         health,
+      })
+   }
+}
+
+/// Combines reports (sorted by read_ts) of a single sensor into one covering all of their periods
+pub fn summarize(reports: &[Report]) -> Option<common::Health> {
+   let first = reports.first()?;
+   let last = reports.last()?;
+   let reads: u64 = reports.iter().map(|r| r.health.reads).sum();
+   let total_latency_us: u128 =
+      reports.iter().map(|r| r.health.mean_latency.as_micros() * r.health.reads as u128).sum();
+   let last_error = reports.iter().rev().map(|r| &r.health.last_error).find(|e| !e.is_empty());
+   Some(common::Health {
+      since: first.health.since,
+      reads,
+      failures: reports.iter().map(|r| r.health.failures).sum(),
+      consecutive_failures: last.health.consecutive_failures,
+      mean_latency: match reads {
+         0 => Default::default(),
+         _ => std::time::Duration::from_micros((total_latency_us / reads as u128) as u64),
+      },
+      last_error: last_error.cloned().unwrap_or_default(),
+   })
+}
+
+#[derive(sqlx::FromRow)]
+struct Row {
+   sensor_id: common::SensorId,
+   index_n: i64,
+   read_ts: common::MicroSecTs,
+   since: common::MicroSecTs,
+   reads: i64,
+   failures: i64,
+   consecutive_failures: i64,
+   mean_latency_us: i64,
+   last_error: String,
+}
+
+impl From<Row> for Report {
+   fn from(row: Row) -> Self {
+      Self {
+         id: common::MeasurementId {
+            sensor_id: row.sensor_id,
+            index: row.index_n,
+         },
+         read_ts: row.read_ts,
+         health: common::Health {
+            since: row.since,
+            reads: row.reads.max(0) as u64,
+            failures: row.failures.max(0) as u64,
+            consecutive_failures: row.consecutive_failures.max(0) as u64,
+            mean_latency: std::time::Duration::from_micros(row.mean_latency_us.max(0) as u64),
+            last_error: row.last_error,
+         },
+      }
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Db
+
+#[derive(Clone)]
+pub struct Sqlite {
+   pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+
+impl Sqlite {
+   pub async fn new(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Sqlite> {
+      crate::db::migrate(pool, "sensor_health", MIGRATIONS)
+         .await
+         .with_context(|| anyhow!("Failed to migrate"))?;
+      Ok(Sqlite { pool: pool.clone() })
+   }
+}
+
+const MIGRATIONS: &[crate::db::Migration] = &[crate::db::Migration {
+   description: "Create sensor_health",
+   steps: &[crate::db::Step::Sql(
+      r#"CREATE TABLE IF NOT EXISTS sensor_health (
+         sensor_id            TEXT    NOT NULL,
+         index_n              INTEGER NOT NULL,
+         read_ts              INTEGER NOT NULL,
+         since                INTEGER NOT NULL,
+         reads                INTEGER NOT NULL,
+         failures             INTEGER NOT NULL,
+         consecutive_failures INTEGER NOT NULL,
+         mean_latency_us      INTEGER NOT NULL,
+         last_error           TEXT    NOT NULL,
+         PRIMARY KEY (sensor_id, index_n)
+      ) STRICT;"#,
+   )],
+}];
+
+/// Deletes reports read before up_to, returns their number. Used by crate::retention: reports are only
+/// summarized for recent periods, so they are not rolled up.
+pub(crate) async fn delete_in(
+   tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+   up_to: common::MicroSecTs,
+) -> Result<u64> {
+   let res = sqlx::query("DELETE FROM sensor_health WHERE read_ts < $1").bind(up_to).execute(&mut **tx).await?;
+   Ok(res.rows_affected())
+}
+
+#[async_trait::async_trait]
+pub trait Db {
+   /// Returns false if the report has already been stored
+   async fn write(&self, report: &Report) -> Result<bool>;
+   /// Reports of the sensor with read_ts in [start, end), sorted by read_ts
+   async fn read(
+      &self,
+      start: common::MicroSecTs,
+      end: common::MicroSecTs,
+      sensor_id: &common::SensorId,
+   ) -> Result<Vec<Report>>;
+}
+
+
+#[async_trait::async_trait]
+impl Db for Sqlite {
+   async fn write(&self, report: &Report) -> Result<bool> {
+      let health = &report.health;
+      let res = sqlx::query(
+         r#"INSERT OR IGNORE INTO sensor_health
+               (sensor_id, index_n, read_ts, since, reads, failures, consecutive_failures, mean_latency_us,
+                last_error)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+         "#,
+      )
+      .bind(&report.id.sensor_id)
+      .bind(report.id.index)
+      .bind(report.read_ts)
+      .bind(health.since)
+      .bind(health.reads as i64)
+      .bind(health.failures as i64)
+      .bind(health.consecutive_failures as i64)
+      .bind(health.mean_latency.as_micros() as i64)
+      .bind(&health.last_error)
+      .execute(&self.pool)
+      .await?;
+      Ok(res.rows_affected() > 0)
+   }
+
+   async fn read(
+      &self,
+      start: common::MicroSecTs,
+      end: common::MicroSecTs,
+      sensor_id: &common::SensorId,
+   ) -> Result<Vec<Report>> {
+      let rows: Vec<Row> = sqlx::query_as(
+         r#"
+         SELECT sensor_id, index_n, read_ts, since, reads, failures, consecutive_failures, mean_latency_us,
+                last_error
+         FROM sensor_health
+         WHERE sensor_id = $1 AND read_ts >= $2 AND read_ts < $3
+         ORDER BY read_ts
+         "#,
+      )
+      .bind(sensor_id)
+      .bind(start)
+      .bind(end)
+      .fetch_all(&self.pool)
+      .await?;
+      Ok(rows.into_iter().map(Into::into).collect())
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn ts_hour(hour: i64) -> common::MicroSecTs {
+      use chrono::TimeZone;
+      (chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::hours(hour)).into()
+   }
+
+   fn report(hour: i64, reads: u64, failures: u64, latency_ms: u64, last_error: &str) -> Report {
+      Report {
+         id: common::MeasurementId {
+            sensor_id: "sen_asdf_1".to_string().try_into().unwrap(),
+            index: hour,
+         },
+         read_ts: ts_hour(hour + 1),
+         health: common::Health {
+            since: ts_hour(hour),
+            reads,
+            failures,
+            consecutive_failures: failures,
+            mean_latency: std::time::Duration::from_millis(latency_ms),
+            last_error: last_error.to_string(),
+         },
+      }
+   }
+
+   #[tokio::test]
+   async fn test_write_and_read() -> Result<()> {
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let db = Sqlite::new(&pool).await?;
+      let reports = [report(0, 60, 0, 800, ""), report(1, 60, 2, 900, "error1")];
+      for r in &reports {
+         assert!(db.write(r).await?);
+      }
+      assert!(!db.write(&reports[0]).await?);
+
+      let sensor_id = &reports[0].id.sensor_id;
+      assert_eq!(db.read(ts_hour(0), ts_hour(3), sensor_id).await?, reports.to_vec());
+      assert_eq!(db.read(ts_hour(2), ts_hour(3), sensor_id).await?, vec![reports[1].clone()]);
+      Ok(())
+   }
+
+   #[test]
+   fn test_summarize() {
+      assert_eq!(summarize(&[]), None);
+      let reports = [
+         report(0, 30, 3, 1000, "error1"),
+         report(1, 10, 0, 200, ""),
+         report(2, 0, 0, 0, ""),
+      ];
+      let expected = common::Health {
+         since: ts_hour(0),
+         reads: 40,
+         failures: 3,
+         consecutive_failures: 0,
+         mean_latency: std::time::Duration::from_millis(800),
+         last_error: "error1".to_string(),
+      };
+      assert_eq!(summarize(&reports), Some(expected));
+   }
+}
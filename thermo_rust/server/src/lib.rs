@@ -1,8 +1,16 @@
 pub mod cli;
+pub mod client;
 pub mod message;
 pub mod plot;
-// pub mod alerting;
+pub mod report;
+pub mod alerting;
+pub mod bot;
 pub mod cron;
 pub mod db;
 pub mod grpc;
+pub mod health;
+pub mod notifier;
+pub mod retention;
 pub mod sensor;
+pub mod subscription;
+pub mod watchdog;
@@ -1,84 +1,231 @@
 use anyhow::{Context, Result, anyhow};
 
-fn handle_response(body: Result<String, reqwest::Error>) -> Result<()> {
-   let body = body.with_context(|| anyhow!("Failed to obtain body"))?;
-   let json: serde_json::Value =
-      serde_json::from_str(&body).with_context(|| anyhow!("Failed to parse the body: {body}"))?;
+//
+// ===========================================================================================================
+// Errors
+
+/// Why Telegram did not accept a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+   /// Too many requests (429): may be retried after the given time
+   RateLimited { retry_after: std::time::Duration, description: String },
+   /// Retrying will not help: wrong chat id, the bot was blocked or kicked, the token was revoked, etc...
+   Permanent { code: i64, description: String },
+   /// Network errors, server errors, unexpected responses (e.g. of a proxy): may be retried
+   Transient(String),
+}
+
+impl std::fmt::Display for Error {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      match self {
+         Error::RateLimited { retry_after, description } => {
+            write!(f, "Rate limited, retry after {retry_after:?}: {description}")
+         }
+         Error::Permanent { code, description } => write!(f, "Permanent error {code}: {description}"),
+         Error::Transient(description) => write!(f, "Transient error: {description}"),
+      }
+   }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+   /// Whether an error of sending (possibly with context) may go away later
+   pub fn is_transient(why: &anyhow::Error) -> bool {
+      !matches!(why.downcast_ref::<Error>(), Some(Error::Permanent { .. }))
+   }
+}
 
-   if let Some(ok) = json.get("ok").and_then(|v| v.as_bool()) {
-      if ok {
-         Ok(())
-      } else {
-         Err(anyhow!("ok exists but is false: {body}"))
+/// Parses a response of Bot API: {"ok": true, ...} or {"ok": false, "error_code": 429, "description": "...",
+/// "parameters": {"retry_after": 5}}
+pub fn parse_response(body: &str) -> Result<(), Error> {
+   let json: serde_json::Value = serde_json::from_str(body)
+      .map_err(|why| Error::Transient(format!("Failed to parse the body: {why}: {body}")))?;
+   match json.get("ok").and_then(|ok| ok.as_bool()) {
+      Some(true) => return Ok(()),
+      Some(false) => {}
+      None => return Err(Error::Transient(format!("ok is not in json body: {body}"))),
+   }
+   let description = json.get("description").and_then(|d| d.as_str()).unwrap_or(body).to_string();
+   let code = json.get("error_code").and_then(|code| code.as_i64()).unwrap_or_default();
+   match code {
+      429 => {
+         let retry_after = json.pointer("/parameters/retry_after").and_then(|secs| secs.as_u64()).unwrap_or(1);
+         Err(Error::RateLimited {
+            retry_after: std::time::Duration::from_secs(retry_after),
+            description,
+         })
       }
-   } else {
-      Err(anyhow!("ok is not in json body: {body}"))
+      400..=499 => Err(Error::Permanent { code, description }),
+      _ => Err(Error::Transient(format!("{code}: {description}"))),
    }
 }
 
+/// Exponential backoff between attempts of sending a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retry {
+   pub attempts: u32,
+   pub initial_delay: std::time::Duration,
+   pub max_delay: std::time::Duration,
+}
+
+impl Default for Retry {
+   fn default() -> Self {
+      Self {
+         attempts: 4,
+         initial_delay: std::time::Duration::from_millis(500),
+         max_delay: std::time::Duration::from_secs(10),
+      }
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Telegram
+
+/// Max number of characters in a caption of a picture
+pub const CAPTION_LIMIT: usize = 1024;
+/// Max number of characters in a text message
+pub const TEXT_LIMIT: usize = 4096;
+
+/// Splits text into chunks of at most first_limit (the first chunk) and limit (the next ones) characters.
+/// Splits between lines, lines that do not fit into a chunk on their own are cut.
+pub fn split_text(text: &str, first_limit: usize, limit: usize) -> Vec<String> {
+   let mut chunks: Vec<String> = Vec::new();
+   // The chunk being filled and whether it has any (possibly empty) lines:
+   let mut current = (String::new(), false);
+   for mut line in text.lines() {
+      loop {
+         let chunk_limit = if chunks.is_empty() { first_limit } else { limit };
+         let needed = current.0.chars().count() + usize::from(current.1) + line.chars().count();
+         if needed <= chunk_limit {
+            if current.1 {
+               current.0.push('\n');
+            }
+            current.0.push_str(line);
+            current.1 = true;
+            break;
+         }
+         if current.1 {
+            chunks.push(std::mem::take(&mut current).0);
+            continue;
+         }
+         let cut = line.char_indices().nth(chunk_limit.max(1)).map_or(line.len(), |(i, _)| i);
+         chunks.push(line[..cut].to_string());
+         line = &line[cut..];
+         if line.is_empty() {
+            break;
+         }
+      }
+   }
+   if current.1 {
+      chunks.push(current.0);
+   }
+   chunks
+}
+
+pub const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
 #[derive(clap::Parser, Debug, Clone)]
 pub struct TelegramArgs {
-   #[arg(long)]
-   tg_bot_id: String,
+   /// Telegram is optional if other notifiers are configured
+   #[arg(long, requires = "tg_chat_id")]
+   tg_bot_id: Option<String>,
 
-   #[arg(long)]
-   tg_chat_id: String,
+   #[arg(long, requires = "tg_bot_id")]
+   tg_chat_id: Option<String>,
+
+   /// Base URL of Telegram Bot API, e.g. of a local fake server
+   #[arg(long, default_value = DEFAULT_API_URL)]
+   tg_api_url: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct Telegram {
    pub chat_id: String,
    pub bot_id: String,
+   pub api_url: String,
+   pub retry: Retry,
+   /// Messages that could not be sent because of transient errors are queued here (if any) and sent later
+   pub queue: Option<Queue>,
 }
 
 
 impl Telegram {
-   pub fn from_args(args: TelegramArgs) -> Self {
+   /// None if Telegram is not configured
+   pub fn from_args(args: TelegramArgs) -> Option<Self> {
+      Some(Self {
+         bot_id: args.tg_bot_id?,
+         chat_id: args.tg_chat_id?,
+         api_url: args.tg_api_url,
+         retry: Retry::default(),
+         queue: None,
+      })
+   }
+
+   /// The same bot sending to another chat
+   pub fn with_chat_id(&self, chat_id: i64) -> Self {
+      Self {
+         chat_id: chat_id.to_string(),
+         ..self.clone()
+      }
+   }
+
+   pub fn with_queue(self, queue: Queue) -> Self {
       Self {
-         bot_id: args.tg_bot_id,
-         chat_id: args.tg_chat_id,
+         queue: Some(queue),
+         ..self
       }
    }
+
+   pub fn url(&self, method: &str) -> String {
+      format!("{}/bot{}/{method}", self.api_url.trim_end_matches('/'), self.bot_id)
+   }
    // pub fn new(bot_id: String, chat_id: String) -> Self { Self { bot_id, chat_id } }
 
 
+   async fn send_once(&self, request: reqwest::Request) -> Result<(), Error> {
+      let resp = reqwest::Client::new()
+         .execute(request)
+         .await
+         .map_err(|why| Error::Transient(format!("Failed to send request: {why}")))?;
+      let body = resp.text().await.map_err(|why| Error::Transient(format!("Failed to obtain body: {why}")))?;
+      parse_response(&body)
+   }
+
+   /// Retries transient errors with exponential backoff, waits as long as asked if rate limited
    async fn try_sending(&self, len: usize, get_req: impl Fn() -> reqwest::Request) -> Result<()> {
-      let mut descriptions = Vec::new();
-      for i in 0..3 {
+      let mut delay = self.retry.initial_delay;
+      for attempt in 1..=self.retry.attempts {
          let request = get_req();
-         if i == 0 {
+         if attempt == 1 {
             log::info!("Sending request of size: {len}, request headers: {:?}", request.headers());
          }
-         let request_headers = request.headers().clone();
-         let resp = reqwest::Client::new().execute(request).await;
-         match resp {
-            Err(why) => {
-               descriptions.push(format!(
-                  ", Attempt: {i}, failed to send request of size: {len}, request headers: {:?}: {why:?}",
-                  request_headers
-               ));
-               tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-               continue;
+         let why = match self.send_once(request).await {
+            Ok(()) => return Ok(()),
+            Err(why @ Error::Permanent { .. }) => {
+               return Err(why).with_context(|| anyhow!("Failed to send request of size {len}"));
             }
-            Ok(resp) => match handle_response(resp.text().await) {
-               Err(why) => {
-                  descriptions.push(format!(
-                     "Attempt: {i}, failed to handle response of request of size: {len}, request headers: {:?}: {why:?}",
-                     request_headers));
-                  tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                  continue;
-               }
-               Ok(_) => {
-                  return Ok(());
-               }
-            },
-         }
+            Err(why) if attempt == self.retry.attempts => {
+               let context = || anyhow!("Failed to send request of size {len} in {attempt} attempts");
+               return Err(why).with_context(context);
+            }
+            Err(why) => why,
+         };
+         let to_wait = match &why {
+            Error::RateLimited { retry_after, .. } => delay.max(*retry_after),
+            _ => delay,
+         };
+         log::warn!("Attempt {attempt} to send request of size {len} failed, retrying in {to_wait:?}: {why}");
+         tokio::time::sleep(to_wait).await;
+         delay = (delay * 2).min(self.retry.max_delay);
       }
-      Err(anyhow!(descriptions.join("\n")))
+      Err(anyhow!("No attempts to send request of size {len}"))
    }
 
    pub async fn send_with_pic(&self, text: &str, pic: Vec<u8>) -> Result<()> {
-      let url = format!("https://api.telegram.org/bot{}/sendPhoto", self.bot_id);
+      let url = self.url("sendPhoto");
 
       let len = pic.len();
 
@@ -108,8 +255,26 @@ impl Telegram {
       self.try_sending(len, get_req).await
    }
 
+   /// Sends what fits into the caption of the picture and the rest as follow-up text messages
+   pub async fn send_with_pic_and_text(&self, text: &str, pic: Vec<u8>) -> Result<()> {
+      let mut chunks = split_text(text, CAPTION_LIMIT, TEXT_LIMIT).into_iter();
+      self.send_with_pic(&chunks.next().unwrap_or_default(), pic).await?;
+      for chunk in chunks {
+         self.send_text(chunk, false).await?;
+      }
+      Ok(())
+   }
+
+   /// Sends the text in as many messages as needed
+   pub async fn send_long_text(&self, text: &str) -> Result<()> {
+      for chunk in split_text(text, TEXT_LIMIT, TEXT_LIMIT) {
+         self.send_text(chunk, false).await?;
+      }
+      Ok(())
+   }
+
    pub async fn send_text(&self, mut text: String, is_markdown: bool) -> Result<()> {
-      let url = format!("https://api.telegram.org/bot{}/sendMessage", self.bot_id);
+      let url = self.url("sendMessage");
       if is_markdown {
          text = text.replace('.', "\\.");
       }
@@ -135,6 +300,214 @@ impl Telegram {
 }
 
 
+//
+// ===========================================================================================================
+// Outbound queue
+
+/// Max number of queued messages, the oldest ones are dropped beyond it
+pub const QUEUE_CAPACITY: usize = 1000;
+/// How often to retry sending queued messages
+pub const QUEUE_RETRY_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+   Text(String),
+   WithPic { text: String, pic: Vec<u8> },
+}
+
+impl Message {
+   /// Splits into messages that are sent by a single request each: the caption of the picture and chunks of
+   /// text that fit into the limits
+   pub fn split(self) -> Vec<Message> {
+      match self {
+         Message::Text(text) => {
+            split_text(&text, TEXT_LIMIT, TEXT_LIMIT).into_iter().map(Message::Text).collect()
+         }
+         Message::WithPic { text, pic } => {
+            let mut chunks = split_text(&text, CAPTION_LIMIT, TEXT_LIMIT).into_iter();
+            let caption = chunks.next().unwrap_or_default();
+            std::iter::once(Message::WithPic { text: caption, pic }).chain(chunks.map(Message::Text)).collect()
+         }
+      }
+   }
+}
+
+impl Telegram {
+   pub async fn send(&self, message: &Message) -> Result<()> {
+      match message {
+         Message::Text(text) => self.send_long_text(text).await,
+         Message::WithPic { text, pic } => self.send_with_pic_and_text(text, pic.clone()).await,
+      }
+   }
+
+   /// Sends a message, that has been split already, by a single request
+   async fn send_part(&self, part: &Message) -> Result<()> {
+      match part {
+         Message::Text(text) => self.send_text(text.clone(), false).await,
+         Message::WithPic { text, pic } => self.send_with_pic(text, pic.clone()).await,
+      }
+   }
+
+   /// Sends the message or queues it if Telegram is not reachable now. The message is sent part by part
+   /// (see [Message::split]): if one of them fails, it is queued with the parts that follow it, so that the
+   /// parts sent already are not sent again. While there are messages queued for the chat, new ones are queued
+   /// after them, so that e.g. a recovery is not delivered before the alert it follows.
+   pub async fn deliver(&self, message: Message) -> Result<()> {
+      let Some(queue) = &self.queue else { return self.send(&message).await };
+      let mut parts = message.split().into_iter();
+      if queue.has_pending(&self.chat_id) {
+         queue.push(self, parts);
+         return Ok(());
+      }
+      while let Some(part) = parts.next() {
+         match self.send_part(&part).await {
+            Ok(()) => {}
+            Err(why) if Error::is_transient(&why) => {
+               log::warn!("Queued a message to chat {} to send later: {why:?}", self.chat_id);
+               queue.push(self, std::iter::once(part).chain(parts));
+               return Ok(());
+            }
+            Err(why) => return Err(why),
+         }
+      }
+      Ok(())
+   }
+}
+
+#[derive(Clone)]
+struct Queued {
+   seq: u64,
+   telegram: Telegram,
+   /// Sent by a single request
+   part: Message,
+}
+
+#[derive(Default)]
+struct QueueState {
+   next_seq: u64,
+   /// By chat, so that a chat Telegram refuses to deliver to for a while does not hold up the others
+   chats: std::collections::BTreeMap<String, std::collections::VecDeque<Queued>>,
+}
+
+impl QueueState {
+   fn len(&self) -> usize { self.chats.values().map(|queue| queue.len()).sum() }
+
+   fn drop_oldest(&mut self) -> Option<Queued> {
+      let (chat_id, _) = self
+         .chats
+         .iter()
+         .filter_map(|(chat_id, queue)| Some((chat_id, queue.front()?.seq)))
+         .min_by_key(|(_, seq)| *seq)?;
+      let chat_id = chat_id.clone();
+      let queue = self.chats.get_mut(&chat_id)?;
+      let dropped = queue.pop_front();
+      if queue.is_empty() {
+         self.chats.remove(&chat_id);
+      }
+      dropped
+   }
+
+   /// Removes the front of the queue of the chat, unless it has been dropped meanwhile because of overflow
+   fn remove(&mut self, queued: &Queued) {
+      let chat_id = &queued.telegram.chat_id;
+      let Some(queue) = self.chats.get_mut(chat_id) else { return };
+      if queue.front().is_some_and(|front| front.seq == queued.seq) {
+         queue.pop_front();
+      }
+      if queue.is_empty() {
+         self.chats.remove(chat_id);
+      }
+   }
+}
+
+/// Messages waiting for Telegram to become reachable (in memory: they are lost on restart)
+#[derive(Clone)]
+pub struct Queue {
+   state: std::sync::Arc<std::sync::Mutex<QueueState>>,
+   capacity: usize,
+}
+
+impl std::fmt::Debug for Queue {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      f.debug_struct("Queue").field("len", &self.len()).field("capacity", &self.capacity).finish()
+   }
+}
+
+impl Queue {
+   /// Starts delivering queued messages in the background
+   pub fn start(capacity: usize, retry_period: std::time::Duration) -> Self {
+      let queue = Self {
+         state: Default::default(),
+         capacity,
+      };
+      tokio::task::spawn({
+         let queue = queue.clone();
+         async move {
+            loop {
+               tokio::time::sleep(retry_period).await;
+               queue.deliver_pending().await;
+            }
+         }
+      });
+      queue
+   }
+
+   /// Number of queued requests (a long message may take several)
+   pub fn len(&self) -> usize { self.state.lock().unwrap().len() }
+
+   pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+   fn has_pending(&self, chat_id: &str) -> bool { self.state.lock().unwrap().chats.contains_key(chat_id) }
+
+   fn push(&self, telegram: &Telegram, parts: impl IntoIterator<Item = Message>) {
+      let mut state = self.state.lock().unwrap();
+      for part in parts {
+         if state.len() >= self.capacity
+            && let Some(dropped) = state.drop_oldest()
+         {
+            let chat_id = &dropped.telegram.chat_id;
+            log::error!("Outbound queue is full => dropped the oldest message to chat {chat_id}");
+         }
+         let seq = state.next_seq;
+         state.next_seq += 1;
+         state.chats.entry(telegram.chat_id.clone()).or_default().push_back(Queued {
+            seq,
+            // The queue must not queue its own messages again:
+            telegram: Telegram {
+               queue: None,
+               ..telegram.clone()
+            },
+            part,
+         });
+      }
+   }
+
+   /// Sends queued messages of every chat in order until its queue is empty or Telegram still does not accept
+   /// them, then goes on with the next chat
+   async fn deliver_pending(&self) {
+      let chat_ids: Vec<String> = self.state.lock().unwrap().chats.keys().cloned().collect();
+      for chat_id in chat_ids {
+         self.deliver_pending_to(&chat_id).await;
+      }
+   }
+
+   async fn deliver_pending_to(&self, chat_id: &str) {
+      loop {
+         let front = self.state.lock().unwrap().chats.get(chat_id).and_then(|queue| queue.front().cloned());
+         let Some(queued) = front else { return };
+         match queued.telegram.send_part(&queued.part).await {
+            Ok(()) => log::info!("Delivered a queued message to chat {chat_id}"),
+            Err(why) if Error::is_transient(&why) => {
+               log::warn!("Failed to deliver queued messages to chat {chat_id}, {} left: {why:?}", self.len());
+               return;
+            }
+            Err(why) => log::error!("Dropped a queued message to chat {chat_id}: {why:?}"),
+         }
+         self.state.lock().unwrap().remove(&queued);
+      }
+   }
+}
+
 //
 // ===========================================================================================================
 // Tests
@@ -147,12 +520,228 @@ mod tests {
 
    // static TELEGRAM: Telegram = once_cell::sync::Lazy::new(|| Telegram {chat_id: -4609542105, bot_id: "7575784506:AAFIFywDLlLNtIR6qBPY6m9E4z7KBdTfx3c".to_string()});
 
+   #[test]
+   fn test_split_text() {
+      use pretty_assertions::assert_eq;
+      assert_eq!(split_text("", 5, 5), Vec::<String>::new());
+      assert_eq!(split_text("ab\ncd", 5, 5), vec!["ab\ncd"]);
+      assert_eq!(split_text("ab\n\ncd\nef", 4, 5), vec!["ab\n", "cd\nef"]);
+      assert_eq!(split_text("°°°°°°\nab", 4, 3), vec!["°°°°", "°°", "ab"]);
+   }
+
+   #[test]
+   fn test_parse_response() {
+      use pretty_assertions::assert_eq;
+      assert_eq!(parse_response(r#"{"ok": true, "result": {}}"#), Ok(()));
+      let rate_limited = r#"{"ok": false, "error_code": 429, "description": "Too Many Requests: retry after 5",
+                             "parameters": {"retry_after": 5}}"#;
+      let expected = Error::RateLimited {
+         retry_after: std::time::Duration::from_secs(5),
+         description: "Too Many Requests: retry after 5".to_string(),
+      };
+      assert_eq!(parse_response(rate_limited), Err(expected));
+      let blocked = r#"{"ok": false, "error_code": 403,
+                        "description": "Forbidden: bot was blocked by the user"}"#;
+      let expected = Error::Permanent {
+         code: 403,
+         description: "Forbidden: bot was blocked by the user".to_string(),
+      };
+      assert_eq!(parse_response(blocked), Err(expected));
+      let bad_gateway = r#"{"ok": false, "error_code": 502, "description": "Bad Gateway"}"#;
+      assert_eq!(parse_response(bad_gateway), Err(Error::Transient("502: Bad Gateway".to_string())));
+      assert!(matches!(parse_response("<html>502</html>"), Err(Error::Transient(_))));
+   }
+
+   type Requests = std::sync::Arc<std::sync::Mutex<Vec<(String, std::time::Instant)>>>;
+
+   /// Answers requests with the given bodies in order, then with ok. Returns the received paths.
+   async fn start_stub(responses: Vec<serde_json::Value>) -> Result<(String, Requests)> {
+      use tokio::io::{AsyncReadExt, AsyncWriteExt};
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+      let url = format!("http://{}", listener.local_addr()?);
+      let requests: Requests = Default::default();
+      let received = requests.clone();
+      tokio::spawn(async move {
+         let mut responses = responses.into_iter();
+         loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Reads until the whole body (of Content-Length) has been received:
+            let head = loop {
+               let n = stream.read(&mut buf).await.unwrap();
+               request.extend_from_slice(&buf[..n]);
+               let text = String::from_utf8_lossy(&request).to_string();
+               let Some((head, body)) = text.split_once("\r\n\r\n") else { continue };
+               let content_length = head
+                  .to_lowercase()
+                  .lines()
+                  .find_map(|line| line.strip_prefix("content-length:")?.trim().parse::<usize>().ok())
+                  .unwrap_or_default();
+               if body.len() >= content_length || n == 0 {
+                  break head.to_string();
+               }
+            };
+            let path = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+            received.lock().unwrap().push((path, std::time::Instant::now()));
+            let body = responses.next().unwrap_or(serde_json::json!({"ok": true, "result": {}})).to_string();
+            let resp = format!(
+               "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n{body}",
+               body.len()
+            );
+            stream.write_all(resp.as_bytes()).await.unwrap();
+         }
+      });
+      Ok((url, requests))
+   }
+
+   fn stub_telegram(api_url: String) -> Telegram {
+      Telegram {
+         chat_id: "1".to_string(),
+         bot_id: "123:token".to_string(),
+         api_url,
+         retry: Retry {
+            attempts: 3,
+            initial_delay: std::time::Duration::from_millis(10),
+            max_delay: std::time::Duration::from_millis(20),
+         },
+         queue: None,
+      }
+   }
+
+   fn error(code: i64, retry_after: Option<u64>) -> serde_json::Value {
+      let mut json = serde_json::json!({"ok": false, "error_code": code, "description": "Error"});
+      if let Some(retry_after) = retry_after {
+         json["parameters"] = serde_json::json!({ "retry_after": retry_after });
+      }
+      json
+   }
+
+   #[tokio::test]
+   async fn test_honours_retry_after() -> Result<()> {
+      let (url, requests) = start_stub(vec![error(429, Some(1)), error(502, None)]).await?;
+      stub_telegram(url).send_text("hello".to_string(), false).await?;
+
+      let requests = requests.lock().unwrap().clone();
+      assert_eq!(requests.len(), 3);
+      assert!(requests[1].1 - requests[0].1 >= std::time::Duration::from_secs(1));
+      assert!(requests[2].1 - requests[1].1 < std::time::Duration::from_secs(1));
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_does_not_retry_permanent_errors() -> Result<()> {
+      let (url, requests) = start_stub(vec![error(403, None)]).await?;
+      let why = stub_telegram(url).send_text("hello".to_string(), false).await.unwrap_err();
+      assert!(matches!(why.downcast_ref::<Error>(), Some(Error::Permanent { code: 403, .. })), "{why:?}");
+      assert!(!Error::is_transient(&why));
+      assert_eq!(requests.lock().unwrap().len(), 1);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_gives_up_after_attempts() -> Result<()> {
+      let (url, requests) = start_stub(vec![error(500, None); 3]).await?;
+      let why = stub_telegram(url).send_text("hello".to_string(), false).await.unwrap_err();
+      assert!(Error::is_transient(&why));
+      assert_eq!(requests.lock().unwrap().len(), 3);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_queue_delivers_after_outage() -> Result<()> {
+      use pretty_assertions::assert_eq;
+      // The first message fails all 3 attempts, the second one to the same chat is queued after it without
+      // trying, the one to another chat is sent right away:
+      let (url, requests) = start_stub(vec![error(502, None); 3]).await?;
+      let queue = Queue {
+         state: Default::default(),
+         capacity: 10,
+      };
+      let telegram = stub_telegram(url).with_queue(queue.clone());
+      telegram.deliver(Message::Text("alert".to_string())).await?;
+      telegram.deliver(Message::Text("recovered".to_string())).await?;
+      telegram.with_chat_id(2).deliver(Message::Text("report".to_string())).await?;
+      assert_eq!(queue.len(), 2);
+      assert_eq!(requests.lock().unwrap().len(), 4);
+
+      queue.deliver_pending().await;
+      assert!(queue.is_empty());
+      assert_eq!(requests.lock().unwrap().len(), 6);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_queue_resends_only_parts_that_failed() -> Result<()> {
+      use pretty_assertions::assert_eq;
+      let ok = serde_json::json!({"ok": true, "result": {}});
+      let (url, requests) = start_stub(vec![ok, error(502, None), error(502, None), error(502, None)]).await?;
+      let queue = Queue {
+         state: Default::default(),
+         capacity: 10,
+      };
+      let telegram = stub_telegram(url).with_queue(queue.clone());
+      let line = "x".repeat(100);
+      let text = vec![line.as_str(); 50].join("\n");
+      let parts = Message::Text(text.clone()).split();
+      assert_eq!(parts.len(), 2);
+
+      telegram.deliver(Message::Text(text)).await?;
+      assert_eq!(requests.lock().unwrap().len(), 4);
+      let queued: Vec<Message> =
+         queue.state.lock().unwrap().chats["1"].iter().map(|queued| queued.part.clone()).collect();
+      assert_eq!(queued, parts[1..]);
+
+      queue.deliver_pending().await;
+      assert!(queue.is_empty());
+      assert_eq!(requests.lock().unwrap().len(), 5);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_queue_does_not_hold_up_other_chats() -> Result<()> {
+      // Telegram keeps failing to deliver to chat 1:
+      let (failing_url, _) = start_stub(vec![error(502, None); 100]).await?;
+      let (url, requests) = start_stub(Vec::new()).await?;
+      let queue = Queue {
+         state: Default::default(),
+         capacity: 10,
+      };
+      queue.push(&stub_telegram(failing_url), [Message::Text("a".to_string())]);
+      queue.push(&stub_telegram(url).with_chat_id(2), [Message::Text("b".to_string())]);
+
+      queue.deliver_pending().await;
+      assert_eq!(requests.lock().unwrap().len(), 1);
+      assert!(queue.has_pending("1"));
+      assert!(!queue.has_pending("2"));
+      Ok(())
+   }
+
+   #[test]
+   fn test_queue_drops_oldest_when_full() {
+      let queue = Queue {
+         state: Default::default(),
+         capacity: 2,
+      };
+      let telegram = stub_telegram(DEFAULT_API_URL.to_string());
+      queue.push(&telegram, [Message::Text("a".to_string())]);
+      queue.push(&telegram.with_chat_id(2), [Message::Text("b".to_string())]);
+      queue.push(&telegram, [Message::Text("c".to_string())]);
+      let state = queue.state.lock().unwrap();
+      let texts: Vec<&Message> = state.chats.values().flatten().map(|queued| &queued.part).collect();
+      assert_eq!(texts, [&Message::Text("c".to_string()), &Message::Text("b".to_string())]);
+   }
+
    #[ignore]
    #[tokio::test]
    async fn test_send_text_without_markdown() {
       let sender: Telegram = Telegram {
          chat_id: "-4609542105".to_string(),
          bot_id: "7575784506:AAFIFywDLlLNtIR6qBPY6m9E4z7KBdTfx3c".to_string(),
+         api_url: DEFAULT_API_URL.to_string(),
+         retry: Retry::default(),
+         queue: None,
       };
       let text: String = String::from("Hello Test");
       let result = sender.send_text(text, false).await;
@@ -165,6 +754,9 @@ mod tests {
       let sender: Telegram = Telegram {
          chat_id: "-4609542105".to_string(),
          bot_id: "7575784506:AAFIFywDLlLNtIR6qBPY6m9E4z7KBdTfx3c".to_string(),
+         api_url: DEFAULT_API_URL.to_string(),
+         retry: Retry::default(),
+         queue: None,
       };
       let text = String::from(
          "Authenticating has not been implemented yet, so insert your chat id into Google BigQuery manually by issuing:\n\n\
@@ -185,6 +777,9 @@ mod tests {
       let sender: Telegram = Telegram {
          chat_id: "-4609542105".to_string(),
          bot_id: "7575784506:AAFIFywDLlLNtIR6qBPY6m9E4z7KBdTfx3c".to_string(),
+         api_url: DEFAULT_API_URL.to_string(),
+         retry: Retry::default(),
+         queue: None,
       };
       let result = sender.send_with_pic(text, pic).await;
       log::info!("result: {result:?}");
@@ -7,15 +7,119 @@ type Rgb = (u8, u8, u8);
 
 pub struct Sensor {
    pub name: String,
+   /// Drawn as a dashed line for temperature curves only
    pub min: f64,
    pub curve: Vec<XY>,
    pub colour: Rgb,
+   pub kind: common::QuantityKind,
 }
 
 
-fn format_date(x: &chrono::DateTime<chrono::Utc>) -> String { x.format("%m-%d %H").to_string() }
+fn format_date(x: &chrono::DateTime<chrono::Utc>, tz: chrono_tz::Tz) -> String {
+   x.with_timezone(&tz).format("%m-%d %H").to_string()
+}
+
+type Area<'a> = plotters::drawing::DrawingArea<plotters::prelude::BitMapBackend<'a>, plotters::coord::Shift>;
+
+/// Draws curves of a single quantity kind
+fn draw_panel(
+   area: &Area,
+   sensors: &[&Sensor],
+   caption: &str,
+   x_range: (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+   tz: chrono_tz::Tz,
+) -> Result<()> {
+   let (min_x, max_x) = x_range;
+   let is_temperature = sensors[0].kind == common::QuantityKind::Temperature;
+   let (min_y, max_y) = sensors
+      .iter()
+      .flat_map(|s| s.curve.iter().map(|p| p.1))
+      .chain(sensors.iter().filter(|_| is_temperature).map(|s| s.min))
+      .filter(|y| y.is_nan() == false)
+      .fold((None, None), |(min, max), y| {
+         let min = Some(min.unwrap_or(y).min(y));
+         let max = Some(max.unwrap_or(y).max(y));
+         (min, max)
+      });
+   let (Some(min_y), Some(max_y)) = (min_y, max_y) else {
+      return Ok(());
+   };
+   // Temperature gets a fixed margin of 2 degrees as before, others a margin relative to their range:
+   let margin = if is_temperature { 2.0 } else { ((max_y - min_y) * 0.1).max(0.1) };
+
+   let mut chart_builder = plotters::prelude::ChartBuilder::on(area);
+   chart_builder
+      .margin(20)
+      .x_label_area_size(40)
+      .y_label_area_size(40)
+      .caption(caption, ("sans-serif", if is_temperature { 40 } else { 30 }, &plotters::prelude::BLACK));
+   let mut chart_context =
+      chart_builder.build_cartesian_2d(min_x..max_x, min_y - margin..max_y + margin).unwrap();
+   chart_context
+      .configure_mesh()
+      .x_label_formatter(&|x| {
+         if x == &min_x {
+            // Check if this is the starting point
+            String::new() // Hide the label
+         } else {
+            format_date(&*x, tz) // Display the date for other points
+         }
+      })
+      .light_line_style(&plotters::prelude::WHITE)
+      .x_labels(10)
+      .y_labels(5)
+      .x_label_style(("sans-serif", 20))
+      .y_label_style(("sans-serif", 30))
+      .draw()
+      .unwrap();
+
+   for s in sensors {
+      chart_context
+         .draw_series(
+            plotters::prelude::LineSeries::new(
+               s.curve.clone(),
+               plotters::prelude::ShapeStyle {
+                  color: plotters::prelude::RGBColor(s.colour.0, s.colour.1, s.colour.2).into(),
+                  filled: false,
+                  stroke_width: 2,
+               },
+            )
+            .point_size(2),
+         )
+         .unwrap()
+         .label(format!("   {}", s.name.clone()))
+         .legend(|(x, y)| {
+            plotters::prelude::PathElement::new(
+               vec![(x, y), (x + 40, y)],
+               plotters::prelude::RGBColor(s.colour.0, s.colour.1, s.colour.2),
+            )
+         });
+      if is_temperature {
+         chart_context.draw_series(std::iter::once(plotters::element::DashedPathElement::new(
+            vec![(min_x, s.min), (max_x, s.min)].into_iter(),
+            15, // Dash size
+            7,  // Gap size
+            plotters::prelude::ShapeStyle {
+               color: plotters::prelude::RGBColor(s.colour.0, s.colour.1, s.colour.2).into(),
+               filled: false,
+               stroke_width: 1,
+            },
+         )))?;
+      }
+   }
 
-pub fn create_plot(sensors: &mut Vec<Sensor>) -> Result<Vec<u8>> {
+   chart_context
+      .configure_series_labels()
+      .background_style(&plotters::style::Color::mix(&plotters::style::colors::WHITE, 0.7)) // Translucent white background
+      .border_style(&plotters::prelude::RGBColor(211, 211, 211)) // No border
+      .label_font(("sans-serif", 30)) // Larger font for labels
+      .position(plotters::prelude::SeriesLabelPosition::LowerLeft)
+      .draw()?;
+   Ok(())
+}
+
+/// Draws a panel per quantity kind (temperature on top), one above another
+pub fn create_plot(sensors: &mut Vec<Sensor>, tz: chrono_tz::Tz) -> Result<Vec<u8>> {
    use plotters::drawing::IntoDrawingArea;
    for sensor in &mut *sensors {
       sensor.curve.sort_by_key(|elem| elem.0);
@@ -24,9 +128,13 @@ pub fn create_plot(sensors: &mut Vec<Sensor>) -> Result<Vec<u8>> {
    if sensors.is_empty() {
       return Ok(Vec::new());
    }
+   let mut kinds: Vec<common::QuantityKind> = sensors.iter().map(|s| s.kind).collect();
+   kinds.sort();
+   kinds.dedup();
 
+   const PANEL_HEIGHT: u32 = 350;
    let width: u32 = 700;
-   let height: u32 = 700;
+   let height: u32 = 2 * PANEL_HEIGHT + PANEL_HEIGHT * (kinds.len() as u32 - 1);
    let mut buffer = vec![0u8; width as usize * height as usize * 3]; // RGB buffer
 
    {
@@ -38,92 +146,27 @@ pub fn create_plot(sensors: &mut Vec<Sensor>) -> Result<Vec<u8>> {
       let min_x = sensors.iter().map(|s| s.curve.first().unwrap().0).min().unwrap();
       let max_x = sensors.iter().map(|s| s.curve.last().unwrap().0).max().unwrap();
 
-      let (min_y, max_y) = sensors
-         .iter()
-         .flat_map(|s| s.curve.iter().map(|p| p.1))
-         .chain(sensors.iter().map(|s| s.min))
-         .filter(|y| y.is_nan() == false)
-         .fold((None, None), |(min, max), y| {
-            let min = Some(min.unwrap_or(y).min(y));
-            let max = Some(max.unwrap_or(y).max(y));
-            (min, max)
-         });
-      let (min_y, max_y) = (min_y.unwrap(), max_y.unwrap());
-
       let current_time = chrono::Utc::now()
-         .with_timezone(&chrono_tz::Europe::Moscow)
+         .with_timezone(&tz)
          .format("%d.%m  %H:%M")
          .to_string();
-      let title = format!("Temp in Tarasovka on {}", current_time);
-
 
-      let mut chart_builder = plotters::prelude::ChartBuilder::on(&drawing_area);
-      chart_builder
-         .margin(20)
-         .x_label_area_size(40)
-         .y_label_area_size(40)
-         .caption(title, ("sans-serif", 40, &plotters::prelude::BLACK));
-      let mut chart_context =
-         chart_builder.build_cartesian_2d(min_x..max_x, min_y - 2.0..max_y + 2.0).unwrap();
-      chart_context
-         .configure_mesh()
-         .x_label_formatter(&|x| {
-            if x == &min_x {
-               // Check if this is the starting point
-               String::new() // Hide the label
-            } else {
-               format_date(&*x) // Display the date for other points
-            }
-         })
-         .light_line_style(&plotters::prelude::WHITE)
-         .x_labels(10)
-         .y_labels(5)
-         .x_label_style(("sans-serif", 20))
-         .y_label_style(("sans-serif", 30))
-         .draw()
-         .unwrap();
-
-      for s in sensors {
-         chart_context
-            .draw_series(
-               plotters::prelude::LineSeries::new(
-                  s.curve.clone(),
-                  plotters::prelude::ShapeStyle {
-                     color: plotters::prelude::RGBColor(s.colour.0, s.colour.1, s.colour.2).into(),
-                     filled: false,
-                     stroke_width: 2,
-                  },
-               )
-               .point_size(2),
-            )
-            .unwrap()
-            .label(format!("   {}", s.name.clone()))
-            .legend(|(x, y)| {
-               plotters::prelude::PathElement::new(
-                  vec![(x, y), (x + 40, y)],
-                  plotters::prelude::RGBColor(s.colour.0, s.colour.1, s.colour.2),
-               )
-            });
-         chart_context.draw_series(std::iter::once(plotters::element::DashedPathElement::new(
-            vec![(min_x, s.min), (max_x, s.min)].into_iter(),
-            15, // Dash size
-            7,  // Gap size
-            plotters::prelude::ShapeStyle {
-               color: plotters::prelude::RGBColor(s.colour.0, s.colour.1, s.colour.2).into(),
-               filled: false,
-               stroke_width: 1,
-            },
-         )))?;
+      // The first panel is twice as high as the others, since it is usually the temperature:
+      let areas: Vec<Area> = if kinds.len() == 1 {
+         vec![drawing_area.clone()]
+      } else {
+         let (first_area, rest_area) = drawing_area.split_vertically(2 * PANEL_HEIGHT);
+         std::iter::once(first_area).chain(rest_area.split_evenly((kinds.len() - 1, 1))).collect()
+      };
+      for (i, (kind, area)) in kinds.iter().zip(areas).enumerate() {
+         let panel: Vec<&Sensor> = sensors.iter().filter(|s| s.kind == *kind).collect();
+         let caption = match kind {
+            common::QuantityKind::Temperature if i == 0 => format!("Temp in Tarasovka on {}", current_time),
+            _ => format!("{}, {}", kind, kind.unit()),
+         };
+         draw_panel(&area, &panel, &caption, (min_x, max_x), tz)?;
       }
 
-      chart_context
-         .configure_series_labels()
-         .background_style(&plotters::style::Color::mix(&plotters::style::colors::WHITE, 0.7)) // Translucent white background
-         .border_style(&plotters::prelude::RGBColor(211, 211, 211)) // No border
-         .label_font(("sans-serif", 30)) // Larger font for labels
-         .position(plotters::prelude::SeriesLabelPosition::LowerLeft)
-         .draw()?;
-
       // Finalize drawing
       drawing_area.present()?;
    };
@@ -169,14 +212,41 @@ mod tests {
             min: 10.0,
             curve: vec![(ts_ymd(2024, 1, 20), 10.0), (ts_ymd(2024, 1, 21), 13.0)],
             colour: (255, 0, 0),
+            kind: common::QuantityKind::Temperature,
          },
          Sensor {
             name: "Sensor2".to_string(),
             min: 9.0,
             curve: vec![(ts_ymd(2024, 1, 20), 12.0), (ts_ymd(2024, 1, 21), 14.0)],
             colour: (0, 0, 255),
+            kind: common::QuantityKind::Temperature,
          },
       ];
-      let _ = create_plot(&mut sensors);
+      let _ = create_plot(&mut sensors, chrono_tz::Europe::Moscow);
+   }
+
+   #[test]
+   fn test_plot_has_panel_per_quantity() -> Result<()> {
+      let sensor = |kind, curve| Sensor {
+         name: "Sensor1".to_string(),
+         min: 10.0,
+         curve,
+         colour: (255, 0, 0),
+         kind,
+      };
+      let temperatures = vec![(ts_ymd(2024, 1, 20), 10.0), (ts_ymd(2024, 1, 21), 13.0)];
+      let humidities = vec![(ts_ymd(2024, 1, 20), 40.0), (ts_ymd(2024, 1, 21), 45.0)];
+      let voltages = vec![(ts_ymd(2024, 1, 20), 5.0), (ts_ymd(2024, 1, 21), 4.9)];
+      let mut sensors = vec![
+         sensor(common::QuantityKind::Voltage, voltages),
+         sensor(common::QuantityKind::Temperature, temperatures),
+         sensor(common::QuantityKind::Humidity, humidities),
+      ];
+      let png = create_plot(&mut sensors, chrono_tz::Europe::Moscow)?;
+
+      let decoder = png::Decoder::new(std::io::Cursor::new(png));
+      let reader = decoder.read_info()?;
+      assert_eq!((reader.info().width, reader.info().height), (700, 1400));
+      Ok(())
    }
 }
@@ -0,0 +1,301 @@
+//
+// ===========================================================================================================
+// Per-sensor statistics of the daily report
+
+/// A reading covers the time until the next one, but at most this many poll periods of the sensor: longer gaps
+/// are missing data
+pub const DEFAULT_MAX_GAP_PERIODS: u32 = 5;
+
+/// The max gap of sensors with too few measurements to estimate their poll period
+const FALLBACK_MAX_READING_GAP: chrono::Duration = chrono::Duration::minutes(5);
+
+/// The most frequent error categories of a sensor shown in the report
+const MAX_ERROR_CATEGORIES: usize = 3;
+
+/// Long categories (e.g. with file paths) are cut to this number of chars
+const MAX_CATEGORY_LEN: usize = 80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Temperatures {
+   pub min: f64,
+   pub max: f64,
+   pub mean: f64,
+   pub last: f64,
+   pub last_ts: chrono::DateTime<chrono::Utc>,
+   /// Total time of readings below the threshold of the sensor
+   pub below_threshold: chrono::Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorStats {
+   pub name: String,
+   pub threshold: f64,
+   /// None if there were no readings with temperature
+   pub temperatures: Option<Temperatures>,
+   /// Percentage of the window covered by readings with temperature
+   pub coverage: f64,
+   /// Categories of errors with their counts, the most frequent first
+   pub errors: Vec<(String, usize)>,
+}
+
+impl SensorStats {
+   /// Measurements are sorted by read_ts and read within [start, end). A reading covers at most max_gap_periods
+   /// poll periods of the sensor (see estimate_poll_period).
+   pub fn compute(
+      sensor: &crate::sensor::Sensor,
+      measurements: &[common::Measurement],
+      start: chrono::DateTime<chrono::Utc>,
+      end: chrono::DateTime<chrono::Utc>,
+      max_gap_periods: u32,
+   ) -> Self {
+      let readings: Vec<(chrono::DateTime<chrono::Utc>, f64)> =
+         measurements.iter().filter_map(|m| m.temperature.map(|t| (m.read_ts.0, t))).collect();
+      let max_reading_gap = estimate_poll_period(measurements)
+         .map_or(FALLBACK_MAX_READING_GAP, |period| period * max_gap_periods as i32);
+
+      let mut covered = chrono::Duration::zero();
+      let mut below_threshold = chrono::Duration::zero();
+      for (i, (ts, temperature)) in readings.iter().enumerate() {
+         let next_ts = readings.get(i + 1).map_or(end, |(next_ts, _)| *next_ts);
+         let span = (next_ts - *ts).clamp(chrono::Duration::zero(), max_reading_gap);
+         covered += span;
+         if *temperature < sensor.min {
+            below_threshold += span;
+         }
+      }
+      let window_ms = (end - start).num_milliseconds();
+      let coverage = match window_ms {
+         ..=0 => 0.0,
+         _ => (100.0 * covered.num_milliseconds() as f64 / window_ms as f64).min(100.0),
+      };
+
+      let temperatures = readings.last().map(|&(last_ts, last)| {
+         let values = readings.iter().map(|(_, t)| *t);
+         Temperatures {
+            min: values.clone().fold(f64::INFINITY, f64::min),
+            max: values.clone().fold(f64::NEG_INFINITY, f64::max),
+            mean: values.sum::<f64>() / readings.len() as f64,
+            last,
+            last_ts,
+            below_threshold,
+         }
+      });
+
+      let mut counts: std::collections::HashMap<String, usize> = Default::default();
+      for measurement in measurements.iter().filter(|m| !m.error.is_empty()) {
+         *counts.entry(error_category(&measurement.error)).or_default() += 1;
+      }
+      let mut errors: Vec<(String, usize)> = counts.into_iter().collect();
+      errors.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+
+      Self {
+         name: sensor.name.clone(),
+         threshold: sensor.min,
+         temperatures,
+         coverage,
+         errors,
+      }
+   }
+
+   pub fn format(&self, tz: chrono_tz::Tz) -> String {
+      let mut lines = Vec::new();
+      match &self.temperatures {
+         None => lines.push(format!("{}: no temperature readings", self.name)),
+         Some(t) => {
+            lines.push(format!(
+               "{}: {:.1}..{:.1}°C, avg {:.1}°C, last {:.1}°C at {}",
+               self.name,
+               t.min,
+               t.max,
+               t.mean,
+               t.last,
+               t.last_ts.with_timezone(&tz).format("%H:%M")
+            ));
+            lines.push(format!(
+               "  below {}°C: {}, coverage: {:.1}%",
+               self.threshold,
+               format_duration(t.below_threshold),
+               self.coverage
+            ));
+         }
+      }
+      lines.extend(self.format_errors());
+      lines.join("\n")
+   }
+
+   /// The most frequent error categories, no lines if there were no errors
+   pub fn format_errors(&self) -> Vec<String> {
+      let mut lines = Vec::new();
+      if self.errors.is_empty() {
+         return lines;
+      }
+      let total: usize = self.errors.iter().map(|(_, count)| count).sum();
+      lines.push(format!("  {total} errors:"));
+      for (category, count) in self.errors.iter().take(MAX_ERROR_CATEGORIES) {
+         lines.push(format!("  {count}× {category}"));
+      }
+      let other = self.errors.len().saturating_sub(MAX_ERROR_CATEGORIES);
+      if other > 0 {
+         lines.push(format!("  and {other} more kinds"));
+      }
+      lines
+   }
+}
+
+/// The median interval between measurements (sorted by read_ts) of a sensor, including failed reads, as they
+/// are made on the same ticks. None if there are fewer than 2 measurements.
+fn estimate_poll_period(measurements: &[common::Measurement]) -> Option<chrono::Duration> {
+   let mut intervals: Vec<chrono::Duration> = measurements
+      .windows(2)
+      .map(|pair| pair[1].read_ts.0 - pair[0].read_ts.0)
+      .filter(|interval| *interval > chrono::Duration::zero())
+      .collect();
+   intervals.sort();
+   intervals.get(intervals.len() / 2).copied()
+}
+
+/// Errors are anyhow chains with the summary in the first line and details (numbers, causes) varying between
+/// occurrences of the same problem: the first line with numbers masked identifies the problem
+pub fn error_category(error: &str) -> String {
+   let first_line = error.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+   let mut category = String::new();
+   let mut chars = first_line.chars().peekable();
+   while let Some(c) = chars.next() {
+      if c.is_ascii_digit() {
+         while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+         category.push('#');
+      } else {
+         category.push(c);
+      }
+   }
+   if category.chars().count() > MAX_CATEGORY_LEN {
+      category = category.chars().take(MAX_CATEGORY_LEN - 1).collect();
+      category.push('…');
+   }
+   category
+}
+
+pub fn format_duration(duration: chrono::Duration) -> String {
+   let minutes = duration.num_minutes();
+   match minutes / 60 {
+      0 => format!("{minutes}m"),
+      hours => format!("{hours}h {}m", minutes % 60),
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn ts_minute(minute: i64) -> chrono::DateTime<chrono::Utc> {
+      use chrono::TimeZone;
+      chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::minutes(minute)
+   }
+
+   fn sensor() -> crate::sensor::Sensor {
+      crate::sensor::Sensor {
+         id: "sen_asdf_1".to_string().try_into().unwrap(),
+         name: "Kitchen".to_string(),
+         location: "location".to_string(),
+         min: 10.0,
+         stale_after_mins: None,
+      }
+   }
+
+   fn id(index: i64) -> common::MeasurementId {
+      common::MeasurementId {
+         sensor_id: sensor().id,
+         index,
+      }
+   }
+
+   #[test]
+   fn test_compute() {
+      let ok = |minute, t| common::Measurement::from_ok(&id(minute), t, ts_minute(minute).into());
+      let err = |minute, e| common::Measurement::from_err(&id(minute), e, ts_minute(minute).into());
+      let measurements = vec![
+         ok(0, 12.0),
+         ok(1, 8.0),
+         ok(3, 9.0),
+         err(4, "Read timed out after 10s"),
+         ok(5, 13.0),
+         // Only 5 minutes of the 25 minutes gap and of the 30 minutes until the end are covered
+         ok(30, 11.0),
+         err(31, "Failed to parse file: \"/sys/28-01/w1_slave\"\n\nCaused by:\n   CRC check failed"),
+         err(32, "Read timed out after 10s"),
+      ];
+      let stats = SensorStats::compute(&sensor(), &measurements, ts_minute(0), ts_minute(60), 5);
+
+      let expected = SensorStats {
+         name: "Kitchen".to_string(),
+         threshold: 10.0,
+         temperatures: Some(Temperatures {
+            min: 8.0,
+            max: 13.0,
+            mean: 10.6,
+            last: 11.0,
+            last_ts: ts_minute(30),
+            below_threshold: chrono::Duration::minutes(4),
+         }),
+         coverage: 25.0,
+         errors: vec![
+            ("Read timed out after #s".to_string(), 2),
+            ("Failed to parse file: \"/sys/#-#/w#_slave\"".to_string(), 1),
+         ],
+      };
+      assert_eq!(stats, expected);
+
+      let expected = [
+         "Kitchen: 8.0..13.0°C, avg 10.6°C, last 11.0°C at 03:30",
+         "  below 10°C: 4m, coverage: 25.0%",
+         "  3 errors:",
+         "  2× Read timed out after #s",
+         "  1× Failed to parse file: \"/sys/#-#/w#_slave\"",
+      ];
+      assert_eq!(stats.format(chrono_tz::Europe::Moscow), expected.join("\n"));
+   }
+
+   #[test]
+   fn test_compute_without_readings() {
+      let stats = SensorStats::compute(&sensor(), &[], ts_minute(0), ts_minute(60), 5);
+      assert_eq!(stats.temperatures, None);
+      assert_eq!(stats.coverage, 0.0);
+      assert_eq!(stats.format(chrono_tz::UTC), "Kitchen: no temperature readings");
+   }
+
+   #[test]
+   fn test_max_gap_follows_poll_period() {
+      // Polled every 10 minutes, a gap of 20 minutes is covered, the last reading covers 50 of 60 minutes:
+      let ok = |minute| common::Measurement::from_ok(&id(minute), 12.0, ts_minute(minute).into());
+      let measurements = vec![ok(0), ok(10), ok(20), ok(40), ok(50)];
+      let stats = SensorStats::compute(&sensor(), &measurements, ts_minute(0), ts_minute(100), 5);
+      assert_eq!(stats.coverage, 100.0);
+      let stats = SensorStats::compute(&sensor(), &measurements, ts_minute(0), ts_minute(100), 1);
+      assert_eq!(stats.coverage, 50.0);
+
+      assert_eq!(estimate_poll_period(&measurements[..1]), None);
+      assert_eq!(estimate_poll_period(&measurements), Some(chrono::Duration::minutes(10)));
+   }
+
+   #[test]
+   fn test_error_category() {
+      let error = "\n  Dropped 12 readings: lagging\nCaused by: 1";
+      assert_eq!(error_category(error), "Dropped # readings: lagging");
+      assert_eq!(error_category("Filtered raw temperature 30.5: x"), "Filtered raw temperature #: x");
+      let long = error_category(&"a".repeat(200));
+      assert_eq!(long.chars().count(), MAX_CATEGORY_LEN);
+      assert!(long.ends_with('…'));
+   }
+
+   #[test]
+   fn test_format_duration() {
+      assert_eq!(format_duration(chrono::Duration::seconds(59)), "0m");
+      assert_eq!(format_duration(chrono::Duration::minutes(135)), "2h 15m");
+   }
+}
@@ -0,0 +1,592 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Config
+
+pub const DEFAULT_RAW_RETENTION_DAYS: i64 = 90;
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct RetentionArgs {
+   /// Keep raw measurements for this number of days. Older ones are rolled up into hourly and daily
+   /// aggregates (min/max/avg/count/error count) and deleted, queries and plots of older ranges read hourly
+   /// aggregates. Measurements resent after their range has been rolled up are deleted without being rolled
+   /// up. Older health reports of sensors are deleted.
+   #[arg(long, default_value_t = DEFAULT_RAW_RETENTION_DAYS)]
+   raw_retention_days: i64,
+
+   /// Local time of day (in --report-tz) to run retention at
+   #[arg(long, default_value = "03:30")]
+   retention_at: chrono::NaiveTime,
+}
+
+impl RetentionArgs {
+   pub fn config(&self, tz: chrono_tz::Tz) -> Config {
+      Config {
+         raw_retention: chrono::Duration::days(self.raw_retention_days),
+         schedule: crate::cron::Schedule::new(vec![self.retention_at], tz),
+      }
+   }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+   pub raw_retention: chrono::Duration,
+   pub schedule: crate::cron::Schedule,
+}
+
+
+//
+// ===========================================================================================================
+// Aggregates
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+   Hourly,
+   Daily,
+}
+
+impl Resolution {
+   pub const ALL: [Resolution; 2] = [Resolution::Hourly, Resolution::Daily];
+
+   fn table(&self) -> &'static str {
+      match self {
+         Resolution::Hourly => "measurements_hourly",
+         Resolution::Daily => "measurements_daily",
+      }
+   }
+
+   /// Buckets are aligned to unix epoch, i.e. daily buckets start at UTC midnight
+   fn bucket_us(&self) -> i64 {
+      match self {
+         Resolution::Hourly => 3600 * 1_000_000,
+         Resolution::Daily => 24 * 3600 * 1_000_000,
+      }
+   }
+}
+
+/// Values of a quantity of a sensor within a bucket. Errors are counted in the temperature aggregate only,
+/// which exists for every bucket with measurements, even if none of them had temperature.
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct Aggregate {
+   pub sensor_id: common::SensorId,
+   /// Start of the bucket
+   pub bucket_ts: common::MicroSecTs,
+   pub kind: common::QuantityKind,
+   /// None if there were no values
+   pub min_value: Option<f64>,
+   pub max_value: Option<f64>,
+   pub avg_value: Option<f64>,
+   pub value_count: i64,
+   pub error_count: i64,
+}
+
+
+//
+// ===========================================================================================================
+// Db
+
+#[derive(Clone)]
+pub struct Sqlite {
+   pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+impl Sqlite {
+   /// Raw measurements are read from and deleted in tables of crate::db::measurement::Sqlite, health reports
+   /// are deleted in the table of crate::health::Sqlite, both have to be created on the same pool
+   pub async fn new(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Sqlite> {
+      crate::db::migrate(pool, "measurement_aggregates", MIGRATIONS)
+         .await
+         .with_context(|| anyhow!("Failed to migrate"))?;
+      Ok(Sqlite { pool: pool.clone() })
+   }
+}
+
+/// Tables of all resolutions have the same layout
+const MIGRATIONS: &[crate::db::Migration] = &[
+   crate::db::Migration {
+      description: "Create hourly and daily aggregates",
+      steps: &[
+         crate::db::Step::Sql(
+            r#"CREATE TABLE IF NOT EXISTS measurements_hourly (
+               sensor_id   TEXT    NOT NULL,
+               bucket_ts   INTEGER NOT NULL,
+               kind        TEXT    NOT NULL,
+               min_value   REAL,
+               max_value   REAL,
+               avg_value   REAL,
+               value_count INTEGER NOT NULL,
+               error_count INTEGER NOT NULL,
+               PRIMARY KEY (sensor_id, bucket_ts, kind)
+            ) STRICT;"#,
+         ),
+         crate::db::Step::Sql(
+            r#"CREATE TABLE IF NOT EXISTS measurements_daily (
+               sensor_id   TEXT    NOT NULL,
+               bucket_ts   INTEGER NOT NULL,
+               kind        TEXT    NOT NULL,
+               min_value   REAL,
+               max_value   REAL,
+               avg_value   REAL,
+               value_count INTEGER NOT NULL,
+               error_count INTEGER NOT NULL,
+               PRIMARY KEY (sensor_id, bucket_ts, kind)
+            ) STRICT;"#,
+         ),
+      ],
+   },
+   crate::db::Migration {
+      description: "Track until when measurements have been rolled up",
+      steps: &[crate::db::Step::Sql(
+         r#"CREATE TABLE IF NOT EXISTS roll_up_watermark (
+            id    INTEGER PRIMARY KEY CHECK (id = 1),
+            up_to INTEGER NOT NULL
+         ) STRICT;"#,
+      )],
+   },
+];
+
+#[async_trait::async_trait]
+pub trait Db {
+   /// Rolls measurements read before up_to into aggregates and deletes them, returns the number of rolled up
+   /// measurements. Measurements read before the watermark (the latest up_to rolled up) are deleted without
+   /// being rolled up: resent ones may have been rolled up already and can not be told apart from late ones.
+   async fn roll_up(&self, up_to: common::MicroSecTs) -> Result<u64>;
+   /// Measurements read before it have been rolled up, None if nothing has been rolled up yet
+   async fn watermark(&self) -> Result<Option<common::MicroSecTs>>;
+   /// Deletes health reports read before up_to, returns their number
+   async fn prune_health(&self, up_to: common::MicroSecTs) -> Result<u64>;
+   /// Aggregates of the sensor with bucket_ts in [start, end), sorted by bucket_ts and kind
+   async fn read(
+      &self,
+      resolution: Resolution,
+      start: common::MicroSecTs,
+      end: common::MicroSecTs,
+      sensor_id: &common::SensorId,
+   ) -> Result<Vec<Aggregate>>;
+}
+
+/// Merges a new aggregate (excluded.*) into an existing one of the same bucket
+const MERGE: &str = r#"
+   min_value = COALESCE(MIN(min_value, excluded.min_value), min_value, excluded.min_value),
+   max_value = COALESCE(MAX(max_value, excluded.max_value), max_value, excluded.max_value),
+   avg_value = CASE WHEN value_count + excluded.value_count = 0 THEN NULL ELSE
+      (COALESCE(avg_value, 0) * value_count + COALESCE(excluded.avg_value, 0) * excluded.value_count)
+      / (value_count + excluded.value_count) END,
+   value_count = value_count + excluded.value_count,
+   error_count = error_count + excluded.error_count
+"#;
+
+#[async_trait::async_trait]
+impl Db for Sqlite {
+   async fn roll_up(&self, up_to: common::MicroSecTs) -> Result<u64> {
+      let mut tx = self.pool.begin().await?;
+      let watermark: Option<common::MicroSecTs> = sqlx::query_scalar("SELECT up_to FROM roll_up_watermark")
+         .fetch_optional(&mut *tx)
+         .await
+         .with_context(|| anyhow!("Failed to read the watermark"))?;
+      for resolution in Resolution::ALL {
+         let table = resolution.table();
+         // Measurements of very old layout without sensor_id can not be attributed to any sensor:
+         let temperature = format!(
+            r#"INSERT INTO {table}
+                  (sensor_id, bucket_ts, kind, min_value, max_value, avg_value, value_count, error_count)
+               SELECT sensor_id, read_ts - read_ts % $2, 'temperature',
+                      MIN(temperature), MAX(temperature), AVG(temperature), COUNT(temperature),
+                      COUNT(NULLIF(error, ''))
+               FROM measurements
+               WHERE read_ts < $1 AND ($3 IS NULL OR read_ts >= $3) AND sensor_id IS NOT NULL
+               GROUP BY 1, 2
+               ON CONFLICT (sensor_id, bucket_ts, kind) DO UPDATE SET {MERGE}
+            "#
+         );
+         let quantities = format!(
+            r#"INSERT INTO {table}
+                  (sensor_id, bucket_ts, kind, min_value, max_value, avg_value, value_count, error_count)
+               SELECT q.sensor_id, m.read_ts - m.read_ts % $2, q.kind,
+                      MIN(q.value), MAX(q.value), AVG(q.value), COUNT(q.value), 0
+               FROM quantities q JOIN measurements m ON m.sensor_id = q.sensor_id AND m.index_n = q.index_n
+               WHERE m.read_ts < $1 AND ($3 IS NULL OR m.read_ts >= $3)
+               GROUP BY 1, 2, 3
+               ON CONFLICT (sensor_id, bucket_ts, kind) DO UPDATE SET {MERGE}
+            "#
+         );
+         for sql in [temperature, quantities] {
+            sqlx::query(&sql)
+               .bind(up_to)
+               .bind(resolution.bucket_us())
+               .bind(watermark)
+               .execute(&mut *tx)
+               .await
+               .with_context(|| anyhow!("Failed to roll up into {table}"))?;
+         }
+      }
+      let discarded = match watermark {
+         Some(watermark) => {
+            let before = common::MicroSecTs(up_to.0.min(watermark.0));
+            let discarded: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM measurements WHERE read_ts < $1")
+               .bind(before)
+               .fetch_one(&mut *tx)
+               .await
+               .with_context(|| anyhow!("Failed to count measurements read before {before}"))?;
+            discarded as u64
+         }
+         None => 0,
+      };
+      if discarded > 0 {
+         log::warn!("Deleting {discarded} measurements read before {watermark:?}, which are rolled up already");
+      }
+      let deleted = crate::db::measurement::delete_in(&mut tx, up_to).await?;
+      sqlx::query(
+         r#"INSERT INTO roll_up_watermark (id, up_to) VALUES (1, $1)
+            ON CONFLICT (id) DO UPDATE SET up_to = MAX(up_to, excluded.up_to)
+         "#,
+      )
+      .bind(up_to)
+      .execute(&mut *tx)
+      .await
+      .with_context(|| anyhow!("Failed to update the watermark"))?;
+      tx.commit().await?;
+      Ok(deleted - discarded)
+   }
+
+   async fn watermark(&self) -> Result<Option<common::MicroSecTs>> {
+      let watermark =
+         sqlx::query_scalar("SELECT up_to FROM roll_up_watermark").fetch_optional(&self.pool).await?;
+      Ok(watermark)
+   }
+
+   async fn prune_health(&self, up_to: common::MicroSecTs) -> Result<u64> {
+      let mut tx = self.pool.begin().await?;
+      let pruned = crate::health::delete_in(&mut tx, up_to).await?;
+      tx.commit().await?;
+      Ok(pruned)
+   }
+
+   async fn read(
+      &self,
+      resolution: Resolution,
+      start: common::MicroSecTs,
+      end: common::MicroSecTs,
+      sensor_id: &common::SensorId,
+   ) -> Result<Vec<Aggregate>> {
+      let sql = format!(
+         r#"
+         SELECT sensor_id, bucket_ts, kind, min_value, max_value, avg_value, value_count, error_count
+         FROM {}
+         WHERE sensor_id = $1 AND bucket_ts >= $2 AND bucket_ts < $3
+         ORDER BY bucket_ts, kind
+         "#,
+         resolution.table()
+      );
+      let aggregates =
+         sqlx::query_as(&sql).bind(sensor_id).bind(start).bind(end).fetch_all(&self.pool).await?;
+      Ok(aggregates)
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Query
+
+/// Measurements of the sensor read within [start, end): raw ones read since the watermark, and before it hourly
+/// aggregates as measurements read at the start of their bucket (see from_aggregates)
+pub async fn read_measurements(
+   measurements_db: &crate::db::measurement::Sqlite,
+   db: &Sqlite,
+   start: common::MicroSecTs,
+   end: common::MicroSecTs,
+   sensor_id: &common::SensorId,
+) -> Result<Vec<common::Measurement>> {
+   use crate::db::measurement::Db as _;
+   let watermark = db.watermark().await.with_context(|| anyhow!("Failed to read the watermark"))?;
+   let raw_start = match watermark {
+      Some(watermark) if watermark.0 > start.0 => watermark,
+      _ => start,
+   };
+   let mut measurements = Vec::new();
+   if raw_start.0 > start.0 {
+      let aggregates_end = common::MicroSecTs(raw_start.0.min(end.0));
+      let aggregates = db.read(Resolution::Hourly, start, aggregates_end, sensor_id).await.with_context(|| {
+         anyhow!("Failed to read aggregates from {start:?} until {aggregates_end:?} of sensor: {sensor_id}")
+      })?;
+      measurements.extend(from_aggregates(&aggregates));
+   }
+   if raw_start.0 < end.0 {
+      measurements.extend(measurements_db.read(raw_start, end, sensor_id).await.with_context(|| {
+         anyhow!("Failed to read measurements from {raw_start:?} until {end:?} of sensor: {sensor_id}")
+      })?);
+   }
+   Ok(measurements)
+}
+
+/// Turns aggregates (sorted by bucket_ts) into a measurement per bucket with average values, read at the start
+/// of the bucket and indexed by it in microseconds. Errors are kept as their count only for buckets without
+/// any values.
+fn from_aggregates(aggregates: &[Aggregate]) -> Vec<common::Measurement> {
+   aggregates
+      .chunk_by(|a, b| a.bucket_ts == b.bucket_ts)
+      .map(|bucket| {
+         let first = &bucket[0];
+         let temperature = bucket.iter().find(|a| a.kind == common::QuantityKind::Temperature);
+         let quantities: Vec<common::Quantity> = bucket
+            .iter()
+            .filter(|a| a.kind != common::QuantityKind::Temperature)
+            .filter_map(|a| a.avg_value.map(|value| common::Quantity::new(a.kind, value)))
+            .collect();
+         let temperature_value = temperature.and_then(|a| a.avg_value);
+         let error_count = temperature.map_or(0, |a| a.error_count);
+         let error = match temperature_value.is_none() && quantities.is_empty() && error_count > 0 {
+            true => format!("failed reads: {error_count}"),
+            false => String::new(),
+         };
+         common::Measurement {
+            id: common::MeasurementId {
+               sensor_id: first.sensor_id.clone(),
+               index: first.bucket_ts.0.timestamp_micros(),
+            },
+            read_ts: first.bucket_ts,
+            temperature: temperature_value,
+            error,
+            quantities,
+            health: None,
+         }
+      })
+      .collect()
+}
+
+
+//
+// ===========================================================================================================
+// Job
+
+/// Rolls up measurements older than raw_retention (relative to now)
+pub async fn run_once(
+   db: &Sqlite,
+   raw_retention: chrono::Duration,
+   now: chrono::DateTime<chrono::Utc>,
+) -> Result<u64> {
+   let up_to = common::MicroSecTs(now - raw_retention);
+   let rolled_up = db.roll_up(up_to).await.with_context(|| anyhow!("Failed to roll up until {up_to}"))?;
+   log::info!("Rolled up {rolled_up} measurements read before {up_to}");
+   let pruned = db.prune_health(up_to).await.with_context(|| anyhow!("Failed to prune health until {up_to}"))?;
+   log::info!("Deleted {pruned} health reports read before {up_to}");
+   Ok(rolled_up)
+}
+
+pub fn start(db: &Sqlite, config: Config) -> Result<()> {
+   if config.raw_retention <= chrono::Duration::zero() {
+      return Err(anyhow!("Raw retention must be positive, got {}", config.raw_retention));
+   }
+   tokio::task::spawn({
+      let db = db.clone();
+      async move {
+         loop {
+            let now = chrono::Utc::now();
+            let next_run = config.schedule.next_run_time(now);
+            let to_sleep = (next_run - now).to_std().unwrap_or(std::time::Duration::ZERO);
+            log::info!("Next retention run at {next_run}");
+            tokio::time::sleep(to_sleep).await;
+            if let Err(why) = run_once(&db, config.raw_retention, chrono::Utc::now()).await {
+               log::warn!("Retention failed: {why:?}");
+            }
+         }
+      }
+   });
+   Ok(())
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn ts(day: u32, hour: u32, min: u32) -> common::MicroSecTs {
+      use chrono::TimeZone;
+      common::MicroSecTs(chrono::Utc.with_ymd_and_hms(2024, 1, day, hour, min, 0).unwrap())
+   }
+
+   fn get_sen_id() -> common::SensorId { "sen_asdf_1".to_string().try_into().unwrap() }
+
+   fn id(index: i64) -> common::MeasurementId {
+      common::MeasurementId {
+         sensor_id: get_sen_id(),
+         index,
+      }
+   }
+
+   fn aggregate(
+      bucket_ts: common::MicroSecTs,
+      kind: common::QuantityKind,
+      values: (f64, f64, f64),
+      value_count: i64,
+      error_count: i64,
+   ) -> Aggregate {
+      Aggregate {
+         sensor_id: get_sen_id(),
+         bucket_ts,
+         kind,
+         min_value: Some(values.0),
+         max_value: Some(values.1),
+         avg_value: Some(values.2),
+         value_count,
+         error_count,
+      }
+   }
+
+   async fn create_dbs() -> Result<(crate::db::measurement::Sqlite, Sqlite)> {
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      crate::health::Sqlite::new(&pool).await?;
+      Ok((crate::db::measurement::Sqlite::new(&pool).await?, Sqlite::new(&pool).await?))
+   }
+
+   #[tokio::test]
+   async fn test_roll_up_aggregates_and_deletes_old_measurements_only() -> Result<()> {
+      use crate::db::measurement::Db as _;
+      let (measurements, retention) = create_dbs().await?;
+      let humidity = |value| vec![common::Quantity::new(common::QuantityKind::Humidity, value)];
+      for m in [
+         common::Measurement::from_ok(&id(1), 20.0, ts(1, 10, 0)),
+         common::Measurement::from_ok(&id(2), 22.0, ts(1, 10, 30)),
+         common::Measurement::from_err(&id(3), "error1", ts(1, 10, 40)),
+         common::Measurement::from_quantities(&id(4), humidity(40.0), ts(1, 11, 0))?,
+         common::Measurement::from_ok(&id(5), 30.0, ts(2, 0, 0)),
+      ] {
+         measurements.write(&m).await?;
+      }
+
+      assert_eq!(retention.roll_up(ts(2, 0, 0)).await?, 4);
+
+      let remaining = measurements.read(ts(1, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      assert_eq!(remaining, vec![common::Measurement::from_ok(&id(5), 30.0, ts(2, 0, 0))]);
+
+      use common::QuantityKind::{Humidity, Temperature};
+      let hourly = retention.read(Resolution::Hourly, ts(1, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      let no_temperature = Aggregate {
+         min_value: None,
+         max_value: None,
+         avg_value: None,
+         ..aggregate(ts(1, 11, 0), Temperature, (0.0, 0.0, 0.0), 0, 0)
+      };
+      let expected = vec![
+         aggregate(ts(1, 10, 0), Temperature, (20.0, 22.0, 21.0), 2, 1),
+         aggregate(ts(1, 11, 0), Humidity, (40.0, 40.0, 40.0), 1, 0),
+         no_temperature,
+      ];
+      assert_eq!(hourly, expected);
+
+      let daily = retention.read(Resolution::Daily, ts(1, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      let expected = vec![
+         aggregate(ts(1, 0, 0), Humidity, (40.0, 40.0, 40.0), 1, 0),
+         aggregate(ts(1, 0, 0), Temperature, (20.0, 22.0, 21.0), 2, 1),
+      ];
+      assert_eq!(daily, expected);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_prune_health_deletes_old_reports_only() -> Result<()> {
+      use crate::health::Db as _;
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let health_db = crate::health::Sqlite::new(&pool).await?;
+      let retention = Sqlite::new(&pool).await?;
+      let report = |index, read_ts| crate::health::Report {
+         id: id(index),
+         read_ts,
+         health: common::Health {
+            since: read_ts,
+            reads: 10,
+            failures: 1,
+            consecutive_failures: 0,
+            mean_latency: std::time::Duration::from_millis(750),
+            last_error: "error1".to_string(),
+         },
+      };
+      health_db.write(&report(1, ts(1, 10, 0))).await?;
+      health_db.write(&report(2, ts(2, 10, 0))).await?;
+
+      assert_eq!(retention.prune_health(ts(2, 0, 0)).await?, 1);
+      let remaining = health_db.read(ts(1, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      assert_eq!(remaining, vec![report(2, ts(2, 10, 0))]);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_roll_up_skips_measurements_resent_after_being_rolled_up() -> Result<()> {
+      use crate::db::measurement::Db as _;
+      let (measurements, retention) = create_dbs().await?;
+      measurements.write(&common::Measurement::from_ok(&id(1), 20.0, ts(1, 10, 0))).await?;
+      measurements.write(&common::Measurement::from_err(&id(2), "error1", ts(1, 10, 40))).await?;
+      assert_eq!(retention.roll_up(ts(2, 0, 0)).await?, 2);
+      assert_eq!(retention.watermark().await?, Some(ts(2, 0, 0)));
+
+      // Resent by a sensor, that has not got the confirmation, and read after the watermark:
+      measurements.write(&common::Measurement::from_ok(&id(1), 20.0, ts(1, 10, 0))).await?;
+      measurements.write(&common::Measurement::from_ok(&id(3), 26.0, ts(2, 10, 0))).await?;
+      assert_eq!(retention.roll_up(ts(2, 0, 0)).await?, 0);
+      assert_eq!(retention.roll_up(ts(3, 0, 0)).await?, 1);
+      assert_eq!(retention.watermark().await?, Some(ts(3, 0, 0)));
+
+      use common::QuantityKind::Temperature;
+      let hourly = retention.read(Resolution::Hourly, ts(1, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      let expected = vec![
+         aggregate(ts(1, 10, 0), Temperature, (20.0, 20.0, 20.0), 1, 1),
+         aggregate(ts(2, 10, 0), Temperature, (26.0, 26.0, 26.0), 1, 0),
+      ];
+      assert_eq!(hourly, expected);
+      Ok(())
+   }
+
+   #[tokio::test]
+   async fn test_read_measurements_serves_rolled_up_ranges_from_aggregates() -> Result<()> {
+      use crate::db::measurement::Db as _;
+      let (measurements, retention) = create_dbs().await?;
+      let humidity = |value| vec![common::Quantity::new(common::QuantityKind::Humidity, value)];
+      for m in [
+         common::Measurement::from_ok(&id(1), 20.0, ts(1, 10, 0)),
+         common::Measurement::from_ok(&id(2), 22.0, ts(1, 10, 30)),
+         common::Measurement::from_err(&id(3), "error1", ts(1, 11, 0)),
+         common::Measurement::from_quantities(&id(4), humidity(40.0), ts(1, 12, 0))?,
+         common::Measurement::from_ok(&id(5), 30.0, ts(2, 0, 0)),
+      ] {
+         measurements.write(&m).await?;
+      }
+      let before = read_measurements(&measurements, &retention, ts(1, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      retention.roll_up(ts(2, 0, 0)).await?;
+
+      let bucket = |hour, temperature, error: &str, quantities| common::Measurement {
+         id: common::MeasurementId {
+            sensor_id: get_sen_id(),
+            index: ts(1, hour, 0).0.timestamp_micros(),
+         },
+         read_ts: ts(1, hour, 0),
+         temperature,
+         error: error.to_string(),
+         quantities,
+         health: None,
+      };
+      let expected = vec![
+         bucket(10, Some(21.0), "", vec![]),
+         bucket(11, None, "failed reads: 1", vec![]),
+         bucket(12, None, "", humidity(40.0)),
+         before[4].clone(),
+      ];
+      let after = read_measurements(&measurements, &retention, ts(1, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      assert_eq!(after, expected);
+
+      let raw_only =
+         read_measurements(&measurements, &retention, ts(2, 0, 0), ts(3, 0, 0), &get_sen_id()).await?;
+      assert_eq!(raw_only, vec![before[4].clone()]);
+      let aggregates_only =
+         read_measurements(&measurements, &retention, ts(1, 11, 0), ts(1, 12, 0), &get_sen_id()).await?;
+      assert_eq!(aggregates_only, vec![expected[1].clone()]);
+      Ok(())
+   }
+}
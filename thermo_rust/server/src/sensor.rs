@@ -11,6 +11,20 @@ pub struct Sensor {
    pub name: String,
    pub location: String,
    pub min: f64,
+   /// If there is no data from the sensor for this number of minutes, it is considered stale.
+   /// None => the default from the command line is used.
+   pub stale_after_mins: Option<i64>,
+}
+
+impl From<Sensor> for common::pb::Sensor {
+   fn from(sensor: Sensor) -> Self {
+      Self {
+         sensor_id: sensor.id.into(),
+         name: sensor.name,
+         location: sensor.location,
+         min: sensor.min,
+      }
+   }
 }
 
 
@@ -26,22 +40,41 @@ pub struct Sqlite {
 
 impl Sqlite {
    pub async fn new(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Sqlite> {
-      crate::db::init_ddl(&pool, Self::ddl())
+      crate::db::migrate(pool, "sensors", MIGRATIONS)
          .await
-         .with_context(|| anyhow!("Failed to init ddl"))?;
+         .with_context(|| anyhow!("Failed to migrate"))?;
       Ok(Sqlite { pool: pool.clone() })
    }
-
-   fn ddl() -> &'static [&'static str] {
-      &[
-         "CREATE TABLE IF NOT EXISTS sensors (id TEXT PRIMARY KEY) STRICT;",
-         "ALTER TABLE sensors ADD name        TEXT;",
-         "ALTER TABLE sensors ADD location    TEXT;",
-         "ALTER TABLE sensors ADD min         REAL;",
-      ]
-   }
 }
 
+/// Idempotent, because databases created before schema versioning may have any of the columns already
+const MIGRATIONS: &[crate::db::Migration] = &[crate::db::Migration {
+   description: "Create sensors",
+   steps: &[
+      crate::db::Step::Sql("CREATE TABLE IF NOT EXISTS sensors (id TEXT PRIMARY KEY) STRICT;"),
+      crate::db::Step::AddColumnIfMissing {
+         table: "sensors",
+         column: "name",
+         definition: "TEXT",
+      },
+      crate::db::Step::AddColumnIfMissing {
+         table: "sensors",
+         column: "location",
+         definition: "TEXT",
+      },
+      crate::db::Step::AddColumnIfMissing {
+         table: "sensors",
+         column: "min",
+         definition: "REAL",
+      },
+      crate::db::Step::AddColumnIfMissing {
+         table: "sensors",
+         column: "stale_after_mins",
+         definition: "INTEGER",
+      },
+   ],
+}];
+
 #[async_trait::async_trait]
 pub trait Db {
    async fn add(&self, sensor: &Sensor) -> Result<()>;
@@ -49,6 +82,7 @@ pub trait Db {
    async fn delete(&self, id: &common::SensorId) -> Result<()>;
    async fn update_min(&self, id: &common::SensorId, min: f64) -> Result<()>;
    async fn update_name(&self, id: &common::SensorId, name: &str) -> Result<()>;
+   async fn update_stale_after_mins(&self, id: &common::SensorId, stale_after_mins: Option<i64>) -> Result<()>;
    async fn get_all(&self) -> Result<Vec<Sensor>>;
 }
 
@@ -57,14 +91,15 @@ pub trait Db {
 impl Db for Sqlite {
    async fn add(&self, row: &Sensor) -> Result<()> {
       sqlx::query(
-         r#"INSERT INTO sensors (id, name, location, min)
-           VALUES ($1, $2, $3, $4)
+         r#"INSERT INTO sensors (id, name, location, min, stale_after_mins)
+           VALUES ($1, $2, $3, $4, $5)
         "#,
       )
       .bind(&row.id)
       .bind(&row.name)
       .bind(&row.location)
       .bind(&row.min)
+      .bind(row.stale_after_mins)
       .execute(&self.pool)
       .await?;
       Ok(())
@@ -72,7 +107,7 @@ impl Db for Sqlite {
 
    async fn get_by_id(&self, id: &common::SensorId) -> Result<Option<Sensor>> {
       let sensor = sqlx::query_as(
-         r#"SELECT id, name, location, min
+         r#"SELECT id, name, location, min, stale_after_mins
         FROM sensors
         WHERE id = $1
         "#,
@@ -119,10 +154,26 @@ impl Db for Sqlite {
       Ok(())
    }
 
+   async fn update_stale_after_mins(
+      &self,
+      id: &common::SensorId,
+      stale_after_mins: Option<i64>,
+   ) -> Result<()> {
+      sqlx::query(
+         r#"UPDATE sensors SET stale_after_mins = $1 WHERE id = $2
+        "#,
+      )
+      .bind(stale_after_mins)
+      .bind(id.clone())
+      .execute(&self.pool)
+      .await?;
+      Ok(())
+   }
+
    async fn get_all(&self) -> Result<Vec<Sensor>> {
       let sensors = sqlx::query_as(
          r#"
-         SELECT id, name, location, min
+         SELECT id, name, location, min, stale_after_mins
          FROM sensors
          "#,
       )
@@ -150,6 +201,7 @@ mod tests {
          name,
          location: "tar".to_string(),
          min: 5.0,
+         stale_after_mins: None,
       };
       sensor
    }
@@ -160,6 +212,7 @@ mod tests {
          name: "sensor2".to_string(),
          location: "asdf".to_string(),
          min,
+         stale_after_mins: None,
       };
       sensor
    }
@@ -170,10 +223,37 @@ mod tests {
          name: "sensor2".to_string(),
          location: "asdf".to_string(),
          min: 5.0,
+         stale_after_mins: None,
       };
       sensor
    }
 
+   #[tokio::test]
+   async fn test_new_migrates_old_layout() -> Result<()> {
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      // The layout before schema versioning and stale_after_mins:
+      let old_ddl = [
+         "CREATE TABLE sensors (id TEXT PRIMARY KEY) STRICT;",
+         "ALTER TABLE sensors ADD name        TEXT;",
+         "ALTER TABLE sensors ADD location    TEXT;",
+         "ALTER TABLE sensors ADD min         REAL;",
+      ];
+      for sql in old_ddl {
+         sqlx::query(sql).execute(&pool).await?;
+      }
+      let id = common::SensorId::new();
+      sqlx::query("INSERT INTO sensors (id, name, location, min) VALUES ($1, 'sensor2', 'asdf', 5.0)")
+         .bind(&id)
+         .execute(&pool)
+         .await?;
+
+      let sqlite = Sqlite::new(&pool).await?;
+      assert_eq!(crate::db::schema_version(&pool, "sensors").await?, 1);
+      assert_eq!(sqlite.get_by_id(&id).await?, Some(s_id(&id)));
+      sqlite.update_stale_after_mins(&id, Some(10)).await?;
+      Ok(())
+   }
+
    #[tokio::test]
    async fn test_set_update_name_with_same_id() -> Result<()> {
       let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
@@ -200,6 +280,22 @@ mod tests {
       Ok(())
    }
 
+   #[tokio::test]
+   async fn test_update_stale_after_mins() -> Result<()> {
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      let id = common::SensorId::new();
+      sqlite.add(&s_id(&id)).await?;
+      sqlite.update_stale_after_mins(&id, Some(15)).await?;
+      let res = sqlite.get_by_id(&id).await?;
+      let expected = Some(Sensor {
+         stale_after_mins: Some(15),
+         ..s_id(&id)
+      });
+      assert_eq!(res, expected);
+      Ok(())
+   }
+
    #[tokio::test]
    async fn test_get_by_id_present_id() -> Result<()> {
       let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
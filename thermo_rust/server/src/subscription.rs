@@ -0,0 +1,347 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Subscription of a Telegram chat to events of a kind about a sensor (or all sensors)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+   /// The scheduled report with the plot and per-sensor stats
+   Report,
+   /// Temperature dropped below the min of a sensor or recovered
+   Threshold,
+   /// A sensor went silent or resumed
+   Stale,
+   /// Error categories and read health of sensors in the report
+   Errors,
+}
+
+impl Kind {
+   pub const ALL: [Kind; 4] = [Kind::Report, Kind::Threshold, Kind::Stale, Kind::Errors];
+
+   pub fn name(&self) -> &'static str {
+      match self {
+         Kind::Report => "report",
+         Kind::Threshold => "threshold",
+         Kind::Stale => "stale",
+         Kind::Errors => "errors",
+      }
+   }
+}
+
+impl std::fmt::Display for Kind {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(self.name()) }
+}
+
+impl std::str::FromStr for Kind {
+   type Err = anyhow::Error;
+
+   fn from_str(value: &str) -> Result<Self, Self::Err> {
+      Kind::ALL.into_iter().find(|kind| kind.name() == value).ok_or_else(|| {
+         let names: Vec<_> = Kind::ALL.iter().map(|kind| kind.name()).collect();
+         anyhow!("Unknown event kind: {value}, expected one of: {}", names.join(", "))
+      })
+   }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+   pub chat_id: i64,
+   pub kind: Kind,
+   /// None => all sensors, including ones added later
+   pub sensor_id: Option<common::SensorId>,
+}
+
+impl Subscription {
+   pub fn matches(&self, kind: Kind, sensor_id: &common::SensorId) -> bool {
+      self.kind == kind && self.sensor_id.as_ref().is_none_or(|id| id == sensor_id)
+   }
+}
+
+impl std::fmt::Display for Subscription {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      let sensor = self.sensor_id.as_ref().map_or("*".to_string(), |id| id.to_string());
+      write!(f, "{} {} {sensor}", self.chat_id, self.kind)
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Routing of events to notifiers
+
+/// What an audience wants to receive
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+   Everything,
+   Subscriptions(Vec<Subscription>),
+}
+
+impl Filter {
+   pub fn wants(&self, kind: Kind, sensor_id: &common::SensorId) -> bool {
+      match self {
+         Filter::Everything => true,
+         Filter::Subscriptions(subscriptions) => subscriptions.iter().any(|s| s.matches(kind, sensor_id)),
+      }
+   }
+}
+
+/// Sends events to the notifiers of --notify and the chat of --tg-chat-id (they get everything) and to the
+/// subscribed chats. The chat of --tg-chat-id gets only its subscriptions once it has any.
+/// Subscriptions are read on every event, so changes via the config command apply without restart.
+#[derive(Clone)]
+pub struct Router {
+   telegram: Option<crate::message::Telegram>,
+   notifiers: crate::notifier::Notifiers,
+   db: Sqlite,
+}
+
+impl Router {
+   pub fn new(
+      telegram: Option<crate::message::Telegram>,
+      notifiers: crate::notifier::Notifiers,
+      db: &Sqlite,
+   ) -> Self {
+      Self {
+         telegram,
+         notifiers,
+         db: db.clone(),
+      }
+   }
+
+   /// Who gets what: the audience of everything (if any) and each subscribed chat
+   pub async fn audiences(&self) -> Result<Vec<(Filter, crate::notifier::Notifiers)>> {
+      let subscriptions = self.db.get_all().await.with_context(|| anyhow!("Failed to get subscriptions"))?;
+      let mut by_chat: std::collections::BTreeMap<i64, Vec<Subscription>> = Default::default();
+      for subscription in subscriptions {
+         by_chat.entry(subscription.chat_id).or_default().push(subscription);
+      }
+
+      let mut everything = self.notifiers.clone();
+      if let Some(telegram) = &self.telegram
+         && telegram.chat_id.parse::<i64>().map_or(true, |chat_id| !by_chat.contains_key(&chat_id))
+      {
+         everything.push(std::sync::Arc::new(telegram.clone()));
+      }
+      let mut audiences = Vec::new();
+      if !everything.is_empty() {
+         audiences.push((Filter::Everything, everything));
+      }
+      for (chat_id, subscriptions) in by_chat {
+         let Some(telegram) = &self.telegram else {
+            log::warn!("Chat {chat_id} is subscribed, but Telegram is not configured => skipping it");
+            continue;
+         };
+         let telegram = std::sync::Arc::new(telegram.with_chat_id(chat_id));
+         let notifiers = crate::notifier::Notifiers::new(vec![telegram]);
+         audiences.push((Filter::Subscriptions(subscriptions), notifiers));
+      }
+      Ok(audiences)
+   }
+
+   /// Everyone who wants the event of the kind about the sensor
+   pub async fn notifiers(
+      &self,
+      kind: Kind,
+      sensor_id: &common::SensorId,
+   ) -> Result<crate::notifier::Notifiers> {
+      let mut notifiers = crate::notifier::Notifiers::default();
+      for (filter, audience) in self.audiences().await? {
+         if filter.wants(kind, sensor_id) {
+            notifiers.extend(audience);
+         }
+      }
+      Ok(notifiers)
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Db
+
+#[derive(Clone)]
+pub struct Sqlite {
+   pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+impl Sqlite {
+   pub async fn new(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<Sqlite> {
+      crate::db::migrate(pool, "subscriptions", MIGRATIONS)
+         .await
+         .with_context(|| anyhow!("Failed to migrate"))?;
+      Ok(Sqlite { pool: pool.clone() })
+   }
+}
+
+/// sensor_id is '' for subscriptions to all sensors: NULLs are never equal in the primary key
+const MIGRATIONS: &[crate::db::Migration] = &[crate::db::Migration {
+   description: "Create subscriptions",
+   steps: &[crate::db::Step::Sql(
+      r#"CREATE TABLE IF NOT EXISTS subscriptions (
+         chat_id   INTEGER NOT NULL,
+         kind      TEXT NOT NULL,
+         sensor_id TEXT NOT NULL,
+         PRIMARY KEY (chat_id, kind, sensor_id)
+      ) STRICT;"#,
+   )],
+}];
+
+fn sensor_id_column(subscription: &Subscription) -> String {
+   subscription.sensor_id.as_ref().map(|id| id.to_string()).unwrap_or_default()
+}
+
+#[async_trait::async_trait]
+pub trait Db {
+   async fn subscribe(&self, subscription: &Subscription) -> Result<()>;
+   /// Returns false if there was no such subscription
+   async fn unsubscribe(&self, subscription: &Subscription) -> Result<bool>;
+   async fn get_all(&self) -> Result<Vec<Subscription>>;
+}
+
+#[async_trait::async_trait]
+impl Db for Sqlite {
+   async fn subscribe(&self, row: &Subscription) -> Result<()> {
+      sqlx::query(
+         r#"INSERT OR IGNORE INTO subscriptions (chat_id, kind, sensor_id)
+            VALUES ($1, $2, $3)
+         "#,
+      )
+      .bind(row.chat_id)
+      .bind(row.kind.name())
+      .bind(sensor_id_column(row))
+      .execute(&self.pool)
+      .await?;
+      Ok(())
+   }
+
+   async fn unsubscribe(&self, row: &Subscription) -> Result<bool> {
+      let result = sqlx::query(
+         r#"DELETE FROM subscriptions WHERE chat_id = $1 AND kind = $2 AND sensor_id = $3
+         "#,
+      )
+      .bind(row.chat_id)
+      .bind(row.kind.name())
+      .bind(sensor_id_column(row))
+      .execute(&self.pool)
+      .await?;
+      Ok(result.rows_affected() > 0)
+   }
+
+   async fn get_all(&self) -> Result<Vec<Subscription>> {
+      let rows: Vec<(i64, String, String)> = sqlx::query_as(
+         r#"
+         SELECT chat_id, kind, sensor_id
+         FROM subscriptions
+         ORDER BY chat_id, kind, sensor_id
+         "#,
+      )
+      .fetch_all(&self.pool)
+      .await?;
+      rows.into_iter()
+         .map(|(chat_id, kind, sensor_id)| {
+            Ok(Subscription {
+               chat_id,
+               kind: kind.parse()?,
+               sensor_id: match sensor_id.as_str() {
+                  "" => None,
+                  _ => Some(sensor_id.try_into()?),
+               },
+            })
+         })
+         .collect()
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn subscription(chat_id: i64, kind: Kind, sensor_id: Option<&common::SensorId>) -> Subscription {
+      Subscription {
+         chat_id,
+         kind,
+         sensor_id: sensor_id.cloned(),
+      }
+   }
+
+   #[tokio::test]
+   async fn test_subscribe_unsubscribe() -> Result<()> {
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      let sen1 = common::SensorId::new();
+
+      sqlite.subscribe(&subscription(1, Kind::Threshold, Some(&sen1))).await?;
+      sqlite.subscribe(&subscription(1, Kind::Threshold, Some(&sen1))).await?;
+      sqlite.subscribe(&subscription(1, Kind::Report, None)).await?;
+      sqlite.subscribe(&subscription(-2, Kind::Errors, None)).await?;
+      let expected = vec![
+         subscription(-2, Kind::Errors, None),
+         subscription(1, Kind::Report, None),
+         subscription(1, Kind::Threshold, Some(&sen1)),
+      ];
+      assert_eq!(sqlite.get_all().await?, expected);
+
+      assert!(sqlite.unsubscribe(&subscription(1, Kind::Report, None)).await?);
+      assert!(!sqlite.unsubscribe(&subscription(1, Kind::Report, None)).await?);
+      assert!(!sqlite.unsubscribe(&subscription(1, Kind::Threshold, None)).await?);
+      assert_eq!(sqlite.get_all().await?.len(), 2);
+      Ok(())
+   }
+
+   #[test]
+   fn test_filter() {
+      let (sen1, sen2) = (common::SensorId::new(), common::SensorId::new());
+      let filter = Filter::Subscriptions(vec![
+         subscription(1, Kind::Threshold, Some(&sen1)),
+         subscription(1, Kind::Report, None),
+      ]);
+      assert!(filter.wants(Kind::Threshold, &sen1));
+      assert!(!filter.wants(Kind::Threshold, &sen2));
+      assert!(filter.wants(Kind::Report, &sen2));
+      assert!(!filter.wants(Kind::Stale, &sen1));
+      assert!(Filter::Everything.wants(Kind::Errors, &sen2));
+      assert_eq!(subscription(1, Kind::Report, None).to_string(), "1 report *");
+   }
+
+   #[tokio::test]
+   async fn test_router() -> Result<()> {
+      use crate::notifier::Notifier as _;
+      let pool = crate::db::Location::create_pool(&crate::db::Location::Memory).await?;
+      let sqlite = Sqlite::new(&pool).await?;
+      let (sen1, sen2) = (common::SensorId::new(), common::SensorId::new());
+      let telegram = crate::message::Telegram {
+         chat_id: "1".to_string(),
+         bot_id: "123:token".to_string(),
+         api_url: crate::message::DEFAULT_API_URL.to_string(),
+         retry: Default::default(),
+         queue: None,
+      };
+      let stdout = crate::notifier::Notifiers::new(vec![std::sync::Arc::new(crate::notifier::Stdout)]);
+      let router = Router::new(Some(telegram), stdout, &sqlite);
+      let names = |notifiers: crate::notifier::Notifiers| notifiers.name();
+
+      assert_eq!(names(router.notifiers(Kind::Threshold, &sen1).await?), "stdout, telegram chat 1");
+
+      sqlite.subscribe(&subscription(2, Kind::Threshold, Some(&sen1))).await?;
+      assert_eq!(
+         names(router.notifiers(Kind::Threshold, &sen1).await?),
+         "stdout, telegram chat 1, telegram chat 2"
+      );
+      assert_eq!(names(router.notifiers(Kind::Threshold, &sen2).await?), "stdout, telegram chat 1");
+
+      // The report chat narrowed down to its subscriptions:
+      sqlite.subscribe(&subscription(1, Kind::Stale, None)).await?;
+      assert_eq!(names(router.notifiers(Kind::Threshold, &sen2).await?), "stdout");
+      assert_eq!(names(router.notifiers(Kind::Stale, &sen2).await?), "stdout, telegram chat 1");
+      assert_eq!(router.audiences().await?.len(), 3);
+      Ok(())
+   }
+}
@@ -0,0 +1,322 @@
+use anyhow::{Context, Result, anyhow};
+
+
+//
+// ===========================================================================================================
+// Config
+
+#[derive(clap::Parser, Debug, Clone)]
+pub struct WatchdogArgs {
+   /// Consider a sensor stale if there is no data from it for this number of minutes.
+   /// Can be overridden per sensor with `config sensor-update --stale-after-mins`.
+   #[arg(long, default_value_t = 30)]
+   stale_after_mins: i64,
+}
+
+impl WatchdogArgs {
+   pub fn config(&self) -> Config {
+      Config {
+         stale_after: chrono::Duration::minutes(self.stale_after_mins),
+         check_period: std::time::Duration::from_secs(60),
+      }
+   }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+   /// Default silence window for sensors that do not have their own
+   pub stale_after: chrono::Duration,
+   pub check_period: std::time::Duration,
+}
+
+
+//
+// ===========================================================================================================
+// State machine
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+   Stale { last_seen: chrono::DateTime<chrono::Utc> },
+   /// Silence lasts from the last reading seen until the arrival of the next data, which may have been read
+   /// long before (e.g. readings replayed from the outbox of a sensor)
+   Resumed { silent_for: chrono::Duration },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SensorState {
+   last_seen: chrono::DateTime<chrono::Utc>,
+   is_stale: bool,
+}
+
+pub struct Watchdog {
+   started: chrono::DateTime<chrono::Utc>,
+   by_sensor: std::collections::HashMap<common::SensorId, SensorState>,
+}
+
+impl Watchdog {
+   /// Sensors, that we have not heard from since `started`, are treated as if they were seen at `started`,
+   /// so that a restart of the server does not immediately raise alerts for all of them.
+   pub fn new(started: chrono::DateTime<chrono::Utc>) -> Self {
+      Self {
+         started,
+         by_sensor: Default::default(),
+      }
+   }
+
+   /// Now is the arrival time of the measurement
+   pub fn on_measurement(
+      &mut self,
+      measurement: &common::Measurement,
+      now: chrono::DateTime<chrono::Utc>,
+   ) -> Option<Event> {
+      let read_ts = measurement.read_ts.0;
+      let state = self.by_sensor.entry(measurement.id.sensor_id.clone()).or_insert(SensorState {
+         last_seen: self.started,
+         is_stale: false,
+      });
+      let previous = state.last_seen;
+      state.last_seen = std::cmp::max(state.last_seen, read_ts);
+      if !state.is_stale {
+         return None;
+      }
+      state.is_stale = false;
+      Some(Event::Resumed {
+         silent_for: (now - previous).max(chrono::Duration::zero()),
+      })
+   }
+
+   /// Checks every (sensor id, silence window) pair and returns events for sensors that just became stale.
+   pub fn check(
+      &mut self,
+      sensors: &[(common::SensorId, chrono::Duration)],
+      now: chrono::DateTime<chrono::Utc>,
+   ) -> Vec<(common::SensorId, Event)> {
+      let mut events = Vec::new();
+      for (id, stale_after) in sensors {
+         let state = self.by_sensor.entry(id.clone()).or_insert(SensorState {
+            last_seen: self.started,
+            is_stale: false,
+         });
+         if state.is_stale || now - state.last_seen <= *stale_after {
+            continue;
+         }
+         state.is_stale = true;
+         events.push((id.clone(), Event::Stale {
+            last_seen: state.last_seen,
+         }));
+      }
+      events
+   }
+}
+
+
+fn format_event(sensor: &crate::sensor::Sensor, event: &Event, now: chrono::DateTime<chrono::Utc>) -> String {
+   let name = format!("{} ({})", sensor.name, sensor.location);
+   let human = |d: chrono::Duration| human_duration::human_duration(&d.to_std().unwrap_or_default());
+   match event {
+      Event::Stale { last_seen } => {
+         format!("STALE: {name}: no data for {} (last seen at {last_seen})", human(now - *last_seen))
+      }
+      Event::Resumed { silent_for } => {
+         format!("RESUMED: {name}: data is coming again after {} of silence", human(*silent_for))
+      }
+   }
+}
+
+
+//
+// ===========================================================================================================
+// Actor
+
+async fn on_measurement(
+   watchdog: &mut Watchdog,
+   measurement: &common::Measurement,
+   sensor_db: &crate::sensor::Sqlite,
+   router: &crate::subscription::Router,
+) -> Result<()> {
+   let Some(event) = watchdog.on_measurement(measurement, chrono::Utc::now()) else {
+      return Ok(());
+   };
+   use crate::notifier::Notifier as _;
+   use crate::sensor::Db as _;
+   let sensor_id = &measurement.id.sensor_id;
+   let Some(sensor) = sensor_db
+      .get_by_id(sensor_id)
+      .await
+      .with_context(|| anyhow!("Failed to get sensor {sensor_id}"))?
+   else {
+      return Ok(());
+   };
+   let text = format_event(&sensor, &event, chrono::Utc::now());
+   log::info!("Sending: {text}");
+   let notifiers = router.notifiers(crate::subscription::Kind::Stale, sensor_id).await?;
+   notifiers.send_text(&text).await.with_context(|| anyhow!("Failed to send {event:?}"))
+}
+
+async fn on_check(
+   watchdog: &mut Watchdog,
+   config: &Config,
+   sensor_db: &crate::sensor::Sqlite,
+   router: &crate::subscription::Router,
+) -> Result<()> {
+   use crate::notifier::Notifier as _;
+   use crate::sensor::Db as _;
+   let now = chrono::Utc::now();
+   let sensors = sensor_db.get_all().await.with_context(|| anyhow!("Failed to sensor_db.get_all()"))?;
+   let windows: Vec<_> = sensors
+      .iter()
+      .map(|s| {
+         let stale_after = s.stale_after_mins.map(chrono::Duration::minutes).unwrap_or(config.stale_after);
+         (s.id.clone(), stale_after)
+      })
+      .collect();
+
+   // Every event is sent on its own, so that a failure to send one does not lose the others:
+   let mut errors = Vec::new();
+   for (id, event) in watchdog.check(&windows, now) {
+      let Some(sensor) = sensors.iter().find(|s| s.id == id) else { continue };
+      let text = format_event(sensor, &event, now);
+      log::info!("Sending: {text}");
+      let res = match router.notifiers(crate::subscription::Kind::Stale, &id).await {
+         Ok(notifiers) => notifiers.send_text(&text).await,
+         Err(why) => Err(why),
+      };
+      if let Err(why) = res.with_context(|| anyhow!("Failed to send {event:?}")) {
+         errors.push(format!("{why:?}"));
+      }
+   }
+   match errors.is_empty() {
+      true => Ok(()),
+      false => Err(anyhow!("Failed to send {} watchdog events:\n{}", errors.len(), errors.join("\n"))),
+   }
+}
+
+pub fn start(
+   mut rx: tokio::sync::broadcast::Receiver<common::Measurement>,
+   sensor_db: &crate::sensor::Sqlite,
+   router: crate::subscription::Router,
+   config: Config,
+) -> Result<()> {
+   tokio::task::spawn({
+      let sensor_db = sensor_db.clone();
+      async move {
+         let mut watchdog = Watchdog::new(chrono::Utc::now());
+         let mut interval = tokio::time::interval(config.check_period);
+         loop {
+            tokio::select! {
+               received = rx.recv() => {
+                  let measurement = match received {
+                     Ok(measurement) => measurement,
+                     Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("Watchdog is lagging behind, skipped {n} measurements");
+                        continue;
+                     }
+                     Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        log::info!("Measurements channel is closed => stopping watchdog");
+                        return;
+                     }
+                  };
+                  let res = on_measurement(&mut watchdog, &measurement, &sensor_db, &router).await;
+                  if let Err(why) = res {
+                     log::warn!("Watchdog on_measurement() failed: {why:?}");
+                  }
+               },
+               _ = interval.tick() => {
+                  let res = on_check(&mut watchdog, &config, &sensor_db, &router).await;
+                  if let Err(why) = res {
+                     log::warn!("Watchdog on_check() failed: {why:?}");
+                  }
+               }
+            }
+         }
+      }
+   });
+   Ok(())
+}
+
+
+//
+// ===========================================================================================================
+// Tests
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use pretty_assertions::assert_eq;
+
+   fn ts_minute(minute: i64) -> chrono::DateTime<chrono::Utc> {
+      use chrono::TimeZone;
+      chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::minutes(minute)
+   }
+
+   fn get_sen_id() -> common::SensorId { "sen_asdf_1".to_string().try_into().unwrap() }
+
+   fn measurement(minute: i64) -> common::Measurement {
+      let id = common::MeasurementId {
+         sensor_id: get_sen_id(),
+         index: minute,
+      };
+      common::Measurement::from_ok(&id, 20.0, common::MicroSecTs(ts_minute(minute)))
+   }
+
+   fn windows() -> Vec<(common::SensorId, chrono::Duration)> {
+      vec![(get_sen_id(), chrono::Duration::minutes(30))]
+   }
+
+   #[test]
+   fn test_never_seen_sensor_becomes_stale_after_window_since_start() {
+      let mut watchdog = Watchdog::new(ts_minute(0));
+      assert_eq!(watchdog.check(&windows(), ts_minute(30)), Vec::new());
+      assert_eq!(watchdog.check(&windows(), ts_minute(31)), vec![(get_sen_id(), Event::Stale {
+         last_seen: ts_minute(0)
+      })]);
+   }
+
+   #[test]
+   fn test_stale_is_reported_once_and_resumed_after_new_data() {
+      let mut watchdog = Watchdog::new(ts_minute(0));
+      assert_eq!(watchdog.on_measurement(&measurement(10), ts_minute(10)), None);
+      assert_eq!(watchdog.check(&windows(), ts_minute(41)), vec![(get_sen_id(), Event::Stale {
+         last_seen: ts_minute(10)
+      })]);
+      assert_eq!(watchdog.check(&windows(), ts_minute(42)), Vec::new());
+      assert_eq!(
+         watchdog.on_measurement(&measurement(50), ts_minute(50)),
+         Some(Event::Resumed {
+            silent_for: chrono::Duration::minutes(40)
+         })
+      );
+      assert_eq!(watchdog.on_measurement(&measurement(51), ts_minute(51)), None);
+      assert_eq!(watchdog.check(&windows(), ts_minute(52)), Vec::new());
+   }
+
+   #[test]
+   fn test_regular_data_keeps_sensor_alive() {
+      let mut watchdog = Watchdog::new(ts_minute(0));
+      for minute in (0..120).step_by(10) {
+         assert_eq!(watchdog.on_measurement(&measurement(minute), ts_minute(minute)), None);
+         assert_eq!(watchdog.check(&windows(), ts_minute(minute + 5)), Vec::new());
+      }
+   }
+
+   #[test]
+   fn test_old_resent_data_does_not_move_last_seen_back() {
+      let mut watchdog = Watchdog::new(ts_minute(0));
+      watchdog.on_measurement(&measurement(20), ts_minute(20));
+      watchdog.on_measurement(&measurement(5), ts_minute(21));
+      assert_eq!(watchdog.check(&windows(), ts_minute(45)), Vec::new());
+   }
+
+   #[test]
+   fn test_resumed_by_replayed_data_counts_silence_until_arrival() {
+      let mut watchdog = Watchdog::new(ts_minute(0));
+      watchdog.on_measurement(&measurement(20), ts_minute(20));
+      assert_eq!(watchdog.check(&windows(), ts_minute(51)).len(), 1);
+      assert_eq!(
+         watchdog.on_measurement(&measurement(5), ts_minute(60)),
+         Some(Event::Resumed {
+            silent_for: chrono::Duration::minutes(40)
+         })
+      );
+   }
+}